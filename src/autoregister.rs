@@ -0,0 +1,196 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use metrics::{counter, gauge};
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{info, warn};
+
+use crate::{
+    config::resolve_topic_template,
+    hem::{setup_device, Device, HemrsUrls, HttpBackend, Registry, ReqwestBackend},
+    mqtt::DeviceContext,
+};
+
+pub const DEFAULT_AUTO_REGISTER_MAX: usize = 100;
+
+/// A device being set up or set up already
+type Slot = Arc<OnceCell<Arc<DeviceContext>>>;
+
+#[derive(Default)]
+struct Registered {
+    /// Device of every topic seen so far, by topic, counting towards the maximum from its first
+    /// message on
+    devices: HashMap<String, Slot>,
+    /// Devices of every hemrs instance by its devices URL, fetched on the first registration
+    registries: HashMap<String, Arc<Mutex<Registry<Device>>>>,
+}
+
+/// Sets up a device of its own for every topic matching a wildcard topic, on its first message
+pub struct AutoRegister<B = ReqwestBackend> {
+    backend: B,
+    urls: HemrsUrls,
+    /// Devices are only set up in hemrs when ids are numeric and this is not a dry run
+    setup: bool,
+    max: usize,
+    /// Only held to look up or add a topic's slot, setting up its device happens in the slot so
+    /// messages from known topics never wait on hemrs
+    registered: Mutex<Registered>,
+}
+
+impl<B: HttpBackend> AutoRegister<B> {
+    pub fn new(backend: B, urls: HemrsUrls, setup: bool, max: usize) -> Self {
+        Self {
+            backend,
+            urls,
+            setup,
+            max,
+            registered: Mutex::new(Registered::default()),
+        }
+    }
+
+    /// Device of `topic`, named by resolving the name and location of the wildcard topic's
+    /// device `pattern` against it. `None` once `max` devices are registered.
+    pub async fn device(
+        &self,
+        topic: &str,
+        pattern: &DeviceContext,
+    ) -> Result<Option<Arc<DeviceContext>>> {
+        let name = resolve_topic_template(&pattern.name, topic)?;
+        let location = resolve_topic_template(&pattern.location, topic)?;
+        let Some(slot) = self.slot(topic).await else {
+            return Ok(None);
+        };
+        // Two messages from a new topic wait on the same slot, so its device is created once. A
+        // failed setup leaves the slot empty and the next message tries again.
+        let device = slot
+            .get_or_try_init(|| self.register(topic, pattern, name, location))
+            .await?;
+        Ok(Some(Arc::clone(device)))
+    }
+
+    /// Slot of `topic`, added if it is new and fewer than `max` topics have one
+    async fn slot(&self, topic: &str) -> Option<Slot> {
+        let mut registered = self.registered.lock().await;
+        if let Some(slot) = registered.devices.get(topic) {
+            return Some(Arc::clone(slot));
+        }
+        if registered.devices.len() >= self.max {
+            warn!(
+                "Not registering a device for {}, {} devices are registered already",
+                topic, self.max
+            );
+            counter!("sensor_auto_register_rejected_total").increment(1);
+            return None;
+        }
+        let slot = Slot::default();
+        registered
+            .devices
+            .insert(topic.to_string(), Arc::clone(&slot));
+        gauge!("sensor_auto_registered_devices").set(registered.devices.len() as f64);
+        Some(slot)
+    }
+
+    async fn register(
+        &self,
+        topic: &str,
+        pattern: &DeviceContext,
+        name: String,
+        location: String,
+    ) -> Result<Arc<DeviceContext>> {
+        let id = if self.setup {
+            let devices_url = &pattern.hemrs.as_ref().unwrap_or(&self.urls).devices;
+            let registry = Arc::clone(
+                self.registered
+                    .lock()
+                    .await
+                    .registries
+                    .entry(devices_url.clone())
+                    .or_insert_with(|| Arc::new(Mutex::new(Registry::new(devices_url.clone())))),
+            );
+            let mut registry = registry.lock().await;
+            setup_device(&self.backend, &mut registry, &name, &location).await?
+        } else {
+            0
+        };
+        info!("Registered {} in {} for {} {:?}", name, location, topic, id);
+        Ok(Arc::new(DeviceContext {
+            id,
+            name,
+            location,
+            ..pattern.clone()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::testing::fixtures::{topic_to_device, FakeBackend};
+
+    #[tokio::test]
+    async fn registers_a_device_once_and_caches_it() {
+        let urls = HemrsUrls::new(
+            "http://hemrs",
+            "api/measurements",
+            "api/sensors",
+            "api/devices",
+        );
+        let auto_register = AutoRegister::new(FakeBackend::new(Vec::new(), true), urls, true, 10);
+        let mut pattern = topic_to_device("tele/+/SENSOR", 0, "esp_{1}")
+            .remove("tele/+/SENSOR")
+            .unwrap();
+        pattern.location = "{1}".to_string();
+
+        // Two messages of a new topic arriving together
+        let (first, second) = tokio::join!(
+            auto_register.device("tele/stue/SENSOR", &pattern),
+            auto_register.device("tele/stue/SENSOR", &pattern),
+        );
+        let (first, second) = (first.unwrap().unwrap(), second.unwrap().unwrap());
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            (first.id, first.name.as_str(), first.location.as_str()),
+            (1, "esp_stue", "stue")
+        );
+
+        let cached = auto_register
+            .device("tele/stue/SENSOR", &pattern)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &cached));
+        let kitchen = auto_register
+            .device("tele/kitchen/SENSOR", &pattern)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kitchen.id, 2);
+
+        let backend = &auto_register.backend;
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_topics_beyond_the_maximum() {
+        let urls = HemrsUrls::new(
+            "http://hemrs",
+            "api/measurements",
+            "api/sensors",
+            "api/devices",
+        );
+        let auto_register = AutoRegister::new(FakeBackend::default(), urls, false, 1);
+        let pattern = topic_to_device("tele/+/SENSOR", 0, "esp_{1}")
+            .remove("tele/+/SENSOR")
+            .unwrap();
+
+        let stue = auto_register.device("tele/stue/SENSOR", &pattern).await;
+        assert_eq!(stue.unwrap().unwrap().name, "esp_stue");
+        let kitchen = auto_register.device("tele/kitchen/SENSOR", &pattern).await;
+        assert!(kitchen.unwrap().is_none());
+        let stue = auto_register.device("tele/stue/SENSOR", &pattern).await;
+        assert!(stue.unwrap().is_some());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::future::join_all;
+use tracing::{info, warn};
+
+use crate::{
+    config::PayloadEncoding,
+    hem::{join_url, setup_device, setup_sensor, HemrsUrls, Registry, ReqwestBackend},
+    mqtt::{DeviceContext, Measurement},
+    retry::RetryPolicy,
+    sink::{HemrsSink, IdMode},
+};
+
+const BENCH_DEVICE_NAME: &str = "sensor_monitor_bench";
+const BENCH_DEVICE_LOCATION: &str = "bench";
+const BENCH_SENSOR_NAME: &str = "sensor_monitor bench";
+
+/// Outcome of posting synthetic measurements to hemrs
+#[derive(Debug)]
+pub struct BenchReport {
+    pub count: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    /// Latency of every successful post, sorted
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn throughput(&self) -> f64 {
+        (self.count - self.errors) as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.count as f64
+    }
+
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((self.latencies.len() - 1) as f64 * percentile / 100.0).round() as usize;
+        self.latencies[index]
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} measurements in {:.2?}, {:.1} measurements/s",
+            self.count,
+            self.elapsed,
+            self.throughput()
+        )?;
+        writeln!(
+            f,
+            "latency p50 {:.2?} p90 {:.2?} p99 {:.2?} max {:.2?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )?;
+        write!(
+            f,
+            "{} errors, {:.2}% error rate",
+            self.errors,
+            self.error_rate() * 100.0
+        )
+    }
+}
+
+/// Posts `count` synthetic measurements for a bench device to hemrs, `concurrency` at a time.
+/// With `cleanup` the bench device is deleted again afterwards. Nothing is retried, failed
+/// requests count as errors.
+pub async fn run_bench(
+    client: &reqwest::Client,
+    urls: &HemrsUrls,
+    sink: &HemrsSink,
+    id_mode: IdMode,
+    count: usize,
+    concurrency: usize,
+    cleanup: bool,
+) -> Result<BenchReport> {
+    let backend = ReqwestBackend::new(client.clone(), RetryPolicy::default());
+    let (device_id, sensor_id) = match id_mode {
+        IdMode::Numeric => (
+            setup_device(
+                &backend,
+                &mut Registry::new(urls.devices.clone()),
+                BENCH_DEVICE_NAME,
+                BENCH_DEVICE_LOCATION,
+            )
+            .await?,
+            setup_sensor(
+                &backend,
+                &mut Registry::new(urls.sensors.clone()),
+                BENCH_SENSOR_NAME,
+                "",
+            )
+            .await?,
+        ),
+        IdMode::Name => (0, 0),
+    };
+    let device = DeviceContext {
+        id: device_id,
+        name: BENCH_DEVICE_NAME.to_string(),
+        location: BENCH_DEVICE_LOCATION.to_string(),
+        expected_sensors: Vec::new(),
+        info_topics: Vec::new(),
+        offsets: HashMap::new(),
+        payload_root: String::new(),
+        hemrs: None,
+        payload_encoding: PayloadEncoding::None,
+    };
+
+    let concurrency = concurrency.clamp(1, count.max(1));
+    info!("Posting {} measurements, {} at a time", count, concurrency);
+    let start = Instant::now();
+    let workers = (0..concurrency).map(|worker| {
+        let device = &device;
+        async move {
+            let mut outcomes = Vec::new();
+            for i in (worker..count).step_by(concurrency) {
+                let measurement = Measurement::new(device, sensor_id, BENCH_SENSOR_NAME, i as f64);
+                let start = Instant::now();
+                outcomes.push(
+                    sink.post_checked(&measurement)
+                        .await
+                        .map(|_| start.elapsed()),
+                );
+            }
+            outcomes
+        }
+    });
+    let outcomes: Vec<Result<Duration>> = join_all(workers).await.into_iter().flatten().collect();
+    let elapsed = start.elapsed();
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for outcome in outcomes {
+        match outcome {
+            Ok(latency) => latencies.push(latency),
+            Err(e) => {
+                warn!("Posting bench measurement failed: {:#}", e);
+                errors += 1;
+            }
+        }
+    }
+    latencies.sort();
+
+    if cleanup && id_mode == IdMode::Numeric {
+        let url = join_url(&urls.devices, &device_id.to_string());
+        match client
+            .delete(&url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(_) => info!("Deleted bench device {}", device_id),
+            Err(e) => warn!("Deleting bench device {} failed: {}", device_id, e),
+        }
+    }
+
+    Ok(BenchReport {
+        count,
+        errors,
+        elapsed,
+        latencies,
+    })
+}
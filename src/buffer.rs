@@ -0,0 +1,161 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use metrics::{counter, gauge};
+use tracing::{debug, warn};
+
+use crate::{mqtt::Measurement, sink::Sink};
+
+/// Measurements held in memory until they are stored together in one batch
+#[derive(Debug)]
+pub struct MeasurementBuffer {
+    measurements: Mutex<Vec<Measurement>>,
+    max: usize,
+}
+
+impl MeasurementBuffer {
+    pub fn new(max: usize) -> Self {
+        Self {
+            measurements: Mutex::new(Vec::new()),
+            max,
+        }
+    }
+
+    /// Adds a measurement, returning whether the buffer is full and should be flushed
+    pub fn push(&self, measurement: Measurement) -> bool {
+        let mut measurements = self.measurements.lock().unwrap();
+        measurements.push(measurement);
+        gauge!("buffered_measurements").set(measurements.len() as f64);
+        measurements.len() >= self.max
+    }
+
+    /// Takes every buffered measurement, leaving the buffer empty
+    pub fn drain(&self) -> Vec<Measurement> {
+        let mut measurements = self.measurements.lock().unwrap();
+        gauge!("buffered_measurements").set(0.0);
+        std::mem::take(&mut *measurements)
+    }
+
+    /// Stores the buffered measurements in `sink` as one batch, returning how many there were.
+    /// They are dropped if the batch fails, a spool in `sink` keeps them instead.
+    pub async fn flush(&self, sink: &dyn Sink) -> Result<usize> {
+        let measurements = self.drain();
+        if measurements.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = sink.write_measurements(&measurements).await {
+            counter!("buffer_dropped_measurements_total").increment(measurements.len() as u64);
+            return Err(e);
+        }
+        Ok(measurements.len())
+    }
+}
+
+/// Sink buffering measurements and storing them in `inner` in batches, once `max` are buffered
+/// and from [`spawn_flusher`] every interval
+pub struct BufferedSink {
+    inner: Arc<dyn Sink>,
+    buffer: Arc<MeasurementBuffer>,
+}
+
+impl BufferedSink {
+    pub fn new(inner: Arc<dyn Sink>, buffer: Arc<MeasurementBuffer>) -> Self {
+        Self { inner, buffer }
+    }
+}
+
+#[async_trait]
+impl Sink for BufferedSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        if self.buffer.push(measurement.clone()) {
+            let stored = self.buffer.flush(self.inner.as_ref()).await?;
+            debug!(
+                "Stored {} buffered measurements, the buffer was full",
+                stored
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Flushes the buffer to `sink` every `interval` from a background task, so buffered
+/// measurements are stored even when no new ones arrive
+pub fn spawn_flusher(buffer: Arc<MeasurementBuffer>, sink: Arc<dyn Sink>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match buffer.flush(sink.as_ref()).await {
+                Ok(0) => {}
+                Ok(stored) => debug!("Stored {} buffered measurements", stored),
+                Err(e) => warn!("Flushing the buffer failed: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::InMemorySink;
+
+    fn measurement(sensor: i32) -> Measurement {
+        Measurement {
+            device: 1,
+            sensor,
+            measurement: 21.5,
+            unit: None,
+            instance: None,
+            timestamp: None,
+            device_name: "stue".to_string(),
+            sensor_name: "ds18b20".to_string(),
+            hemrs_url: None,
+        }
+    }
+
+    fn sensors(sink: &InMemorySink) -> Vec<i32> {
+        sink.measurements().iter().map(|m| m.sensor).collect()
+    }
+
+    #[tokio::test]
+    async fn flushes_once_full() {
+        let inner = Arc::new(InMemorySink::new());
+        let buffer = Arc::new(MeasurementBuffer::new(3));
+        let sink = BufferedSink::new(inner.clone(), Arc::clone(&buffer));
+        for sensor in 1..=2 {
+            sink.write_measurement(&measurement(sensor)).await.unwrap();
+        }
+        assert!(inner.measurements().is_empty());
+
+        sink.write_measurement(&measurement(3)).await.unwrap();
+        assert_eq!(sensors(&inner), [1, 2, 3]);
+        assert!(buffer.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn flushes_every_interval() {
+        let inner = Arc::new(InMemorySink::new());
+        let buffer = Arc::new(MeasurementBuffer::new(100));
+        let sink = BufferedSink::new(inner.clone(), Arc::clone(&buffer));
+        spawn_flusher(
+            Arc::clone(&buffer),
+            inner.clone(),
+            Duration::from_millis(50),
+        );
+        sink.write_measurement(&measurement(1)).await.unwrap();
+        sink.write_measurement(&measurement(2)).await.unwrap();
+        assert!(inner.measurements().is_empty());
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while inner.measurements().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(sensors(&inner), [1, 2]);
+    }
+}
@@ -0,0 +1,727 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::warn;
+
+use crate::{hem::SENSORS, mqtt::match_topic};
+
+/// Serializes a secret as `<redacted>`, so printing the config does not leak it
+pub fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Bounds {
+    pub fn contains(&self, value: f32) -> bool {
+        value >= self.min && value <= self.max
+    }
+}
+
+/// A sensor to register in hemrs, readings are mapped to it by `key`, e.g. `ds18b20`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SensorConfig {
+    pub key: String,
+    pub name: String,
+    pub unit: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TopicConfig {
+    pub topic: String,
+    pub device_name: String,
+    /// `default_location` of the config when left out or empty
+    #[serde(default)]
+    pub device_location: String,
+    /// MQTT QoS level to subscribe with, 0, 1 or 2
+    #[serde(default)]
+    pub qos: u8,
+    /// Sensor blocks the device is expected to report, e.g. `["DHT11"]`, missing blocks of other
+    /// sensors are not warned about
+    #[serde(default, alias = "sensors")]
+    pub expected_sensors: Vec<String>,
+    /// Tasmota INFO topics to record the firmware version and IP address from, Tasmota sends the
+    /// version on `INFO1` and the address on `INFO2`
+    #[serde(default)]
+    pub info_topics: Vec<String>,
+    /// Calibration offset added to the readings of each sensor key, e.g. `dht11_humidity = -6.0`
+    #[serde(default)]
+    pub offsets: HashMap<String, f32>,
+    /// JSON pointer to the sensor object in the payload, e.g. `/StatusSNS`, the payload itself
+    /// when empty
+    #[serde(default)]
+    pub payload_root: String,
+    /// hemrs instance the device is set up in and its readings are stored in, instead of the
+    /// global one
+    pub hemrs_base_url: Option<String>,
+    /// How the payload is encoded on the wire, decoded to JSON before parsing
+    #[serde(default)]
+    pub payload_encoding: PayloadEncoding,
+}
+
+/// Encoding of MQTT payloads, for firmware that compresses its JSON to save bandwidth
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// Plain UTF-8 JSON
+    #[default]
+    None,
+    Base64,
+    Gzip,
+    /// Base64 of gzipped JSON, for bridges that only pass text
+    #[serde(rename = "base64+gzip")]
+    Base64Gzip,
+}
+
+/// How the connection to an MQTT broker is made
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    #[default]
+    Tcp,
+    Tls,
+    /// MQTT over WebSocket, needs the websocket feature
+    Ws,
+    /// MQTT over WebSocket with TLS, needs the websocket feature
+    Wss,
+}
+
+impl std::str::FromStr for MqttTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(MqttTransport::Tcp),
+            "tls" => Ok(MqttTransport::Tls),
+            "ws" => Ok(MqttTransport::Ws),
+            "wss" => Ok(MqttTransport::Wss),
+            _ => Err("unknown mqtt transport".to_string()),
+        }
+    }
+}
+
+impl MqttTransport {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            MqttTransport::Tcp => 1883,
+            MqttTransport::Tls => 8883,
+            MqttTransport::Ws => 80,
+            MqttTransport::Wss => 443,
+        }
+    }
+
+    /// The TLS variant of the transport, tcp becomes tls and ws becomes wss
+    pub fn secure(self) -> Self {
+        match self {
+            MqttTransport::Tcp => MqttTransport::Tls,
+            MqttTransport::Ws => MqttTransport::Wss,
+            secure => secure,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BrokerConfig {
+    pub host: String,
+    /// Hosts tried in order when `host` cannot be reached, on the same port
+    #[serde(default)]
+    pub failover_hosts: Vec<String>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub transport: MqttTransport,
+    /// Path of the MQTT endpoint for the ws and wss transports, `/mqtt` by default
+    pub ws_path: Option<String>,
+    /// PEM encoded CA certificate to verify the broker with over tls and wss, the system root
+    /// certificates by default
+    pub ca_cert: Option<PathBuf>,
+    /// Credentials for brokers requiring authentication, both or neither must be set
+    pub username: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub password: Option<String>,
+    pub topics: Vec<TopicConfig>,
+}
+
+/// Serial port emitting the same newline delimited JSON as the Tasmota SENSOR topic
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SerialConfig {
+    pub port: String,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(flatten)]
+    pub device: TopicConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub broker: Vec<BrokerConfig>,
+    #[serde(default)]
+    pub serial: Vec<SerialConfig>,
+    /// Location of the devices of topics without a `device_location`
+    pub default_location: Option<String>,
+    /// Per sensor values treated as errors and dropped, e.g. `ds18b20 = [85.0]`
+    #[serde(default)]
+    pub sentinels: HashMap<String, Vec<f32>>,
+    /// Plausible range per registered unit, overriding the defaults for `%` and `hPa` enabled by
+    /// `validate_ranges`
+    #[serde(default)]
+    pub unit_bounds: HashMap<String, Bounds>,
+    /// Plausible range per sensor key, e.g. `dht11_humidity = { min = 0.0, max = 100.0 }`
+    #[serde(default)]
+    pub sensor_bounds: HashMap<String, Bounds>,
+    /// Also check temperatures, humidities, `%` and `hPa` against built in plausible ranges
+    #[serde(default)]
+    pub validate_ranges: bool,
+    /// Warn when a payload does not contain exactly the expected sensors of its topic
+    #[serde(default)]
+    pub validate_expected_sensors: bool,
+    /// Store all measurements from one message in a single request
+    #[serde(default)]
+    pub batch_posts: bool,
+    /// Include the unit of the sensor with every measurement
+    #[serde(default)]
+    pub send_units: bool,
+    /// Include the `Time` of the message with every measurement
+    #[serde(default)]
+    pub send_timestamps: bool,
+    /// Accept payloads without a `Time`, they are stored at the time they are received
+    #[serde(default)]
+    pub allow_missing_time: bool,
+    /// Drop readings with a timestamp older than the last stored reading of the same sensor
+    #[serde(default)]
+    pub enforce_monotonic_time: bool,
+    /// Rejected readings in a row after which a sensor is suppressed for `deadtime_secs`, 0 turns
+    /// it off
+    pub deadtime_after: Option<u32>,
+    /// How long a sensor stays suppressed, 300 seconds by default
+    pub deadtime_secs: Option<u64>,
+    /// Messages from a device arriving sooner than this after the last stored one are dropped
+    pub min_interval_secs: Option<u64>,
+    /// Moving average window per sensor, e.g. `dht11_humidity = 5`
+    #[serde(default)]
+    pub smoothing: HashMap<String, usize>,
+    /// Compute the dew point from temperature and humidity when the firmware omits it
+    #[serde(default)]
+    pub compute_dew_point: bool,
+    /// Compute the DHT11 heat index and absolute humidity from temperature and humidity
+    #[serde(default)]
+    pub compute_comfort_metrics: bool,
+    /// How many topics to put in each SUBSCRIBE packet
+    pub subscribe_batch_size: Option<usize>,
+    /// Skip devices failing to set up in hemrs instead of aborting, as long as one succeeds
+    #[serde(default)]
+    pub continue_on_setup_error: bool,
+    /// Identifies this monitor in tagged measurements, defaults to the MQTT client id
+    pub instance_id: Option<String>,
+    /// Attach the instance id to every measurement
+    #[serde(default)]
+    pub tag_instance: bool,
+    /// Topic on which `pause` and `resume` messages stop and restart storing readings
+    pub control_topic: Option<String>,
+    /// Retained topic set to `online` while connected and `offline` otherwise, through the last
+    /// will when the connection is lost. `sensor_monitor/<hostname>/status` by default.
+    pub status_topic: Option<String>,
+    /// Keep the last raw payload of every topic for the `/last` HTTP endpoint
+    #[serde(default)]
+    pub expose_last_payloads: bool,
+    /// Replace characters other than letters, digits and `_-.:/` in metric label values
+    #[serde(default)]
+    pub sanitize_labels: bool,
+    /// Give up on a broker after this many failed reconnects in a row, unlimited by default
+    pub max_reconnect_attempts: Option<u32>,
+    /// Most keys each per device, sensor or topic state map holds before evicting the least
+    /// recently used, 10000 by default
+    pub state_map_max: Option<usize>,
+    /// Set up a device for every topic a wildcard topic matches, named by its templates
+    #[serde(default)]
+    pub auto_register: bool,
+    /// Most devices set up by `auto_register`, 100 by default
+    pub auto_register_max: Option<usize>,
+    /// Messages on topics a wildcard topic matches are only processed when the topic ends in
+    /// this, `/SENSOR` by default
+    pub sensor_topic_suffix: Option<String>,
+    /// Only subscribe to the configured topics matching one of these patterns, all when empty
+    #[serde(default)]
+    pub only_topics: Vec<String>,
+    /// Publish every stored measurement retained to the first broker
+    #[serde(default)]
+    pub republish: bool,
+    /// Topic measurements are republished on, `sensor_monitor/{device}/{sensor}` by default
+    pub republish_topic: Option<String>,
+    /// Delay before the first reconnect attempt, doubling with every failed attempt, 500 by default
+    pub reconnect_delay_ms: Option<u64>,
+    /// Longest delay between reconnect attempts, 60000 by default
+    pub reconnect_max_delay_ms: Option<u64>,
+    /// Payloads taking longer than this to parse are logged
+    pub slow_parse_threshold_ms: Option<u64>,
+    /// Payloads failing to parse are appended here as JSON lines instead of failing the source
+    pub dead_letter_file: Option<PathBuf>,
+    /// JSON Schema sensor payloads are validated against before they are parsed, needs the
+    /// schema feature
+    pub payload_schema: Option<PathBuf>,
+    /// Log the measurements instead of storing them and leave devices and sensors unregistered
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Round stored values to this many decimal places, ties to even, unrounded by default
+    pub round_decimals: Option<u32>,
+    /// Stop after the first sensor message is stored or fails
+    #[serde(default)]
+    pub once: bool,
+    /// Skip retained sensor messages, which the broker replays on subscribe with stale readings
+    #[serde(default)]
+    pub ignore_retained: bool,
+    /// Sensors to register in hemrs, the built in ones when empty
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+}
+
+impl Config {
+    /// The configured sensors, or the built in ones if none are configured
+    pub fn sensor_configs(&self) -> Vec<SensorConfig> {
+        if !self.sensors.is_empty() {
+            return self.sensors.clone();
+        }
+        SENSORS
+            .iter()
+            .map(|sensor| SensorConfig {
+                key: sensor.key.to_string(),
+                name: sensor.name.to_string(),
+                unit: sensor.unit.to_string(),
+            })
+            .collect()
+    }
+
+    /// Broker topics and serial ports together
+    pub fn topic_count(&self) -> usize {
+        self.broker
+            .iter()
+            .map(|broker| broker.topics.len())
+            .sum::<usize>()
+            + self.serial.len()
+    }
+
+    /// Drops the broker topics and serial ports not matching one of `only_topics`, keeping all
+    /// of them when it is empty
+    pub fn retain_topics(&mut self) {
+        let patterns = &self.only_topics;
+        let matches = |topic: &TopicConfig| {
+            patterns
+                .iter()
+                .any(|pattern| match_topic(pattern, &topic.topic))
+        };
+        if !patterns.is_empty() {
+            for broker in &mut self.broker {
+                broker.topics.retain(matches);
+            }
+            self.serial.retain(|serial| matches(&serial.device));
+        }
+    }
+}
+
+/// Replaces `{n}` in `template` with the n-th `/` separated segment of `topic`, counting from 0,
+/// so `{1}` in the location of `tele/kitchen/SENSOR` becomes `kitchen`
+pub fn resolve_topic_template(template: &str, topic: &str) -> Result<String> {
+    let segments: Vec<&str> = topic.split('/').collect();
+    let mut resolved = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        resolved.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let index = after
+            .find('}')
+            .and_then(|end| after[..end].parse::<usize>().ok().map(|index| (index, end)));
+        match index {
+            Some((index, end)) => {
+                let segment = segments.get(index).ok_or_else(|| {
+                    anyhow!(
+                        "{:?} refers to segment {} but {} only has {} segments",
+                        template,
+                        index,
+                        topic,
+                        segments.len()
+                    )
+                })?;
+                resolved.push_str(segment);
+                rest = &after[end + 1..];
+            }
+            None => {
+                resolved.push('{');
+                rest = after;
+            }
+        }
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+impl TopicConfig {
+    fn fill_location(&mut self, default_location: Option<&str>) -> Result<()> {
+        if self.device_location.trim().is_empty() {
+            self.device_location = default_location
+                .ok_or_else(|| {
+                    anyhow!(
+                        "topic {:?} has no device_location and there is no default_location",
+                        self.topic
+                    )
+                })?
+                .to_string();
+        }
+        Ok(())
+    }
+
+    fn resolve_templates(&mut self) -> Result<()> {
+        // Resolved against every topic they match instead, when auto registering
+        if self.topic.contains(['+', '#']) {
+            return Ok(());
+        }
+        self.device_name = resolve_topic_template(&self.device_name, &self.topic)?;
+        self.device_location = resolve_topic_template(&self.device_location, &self.topic)?;
+        Ok(())
+    }
+}
+
+/// Fails on topics configured more than once, since only one of their devices would get the
+/// readings
+pub fn validate_configs(topics: &[TopicConfig]) -> Result<()> {
+    let mut seen = HashSet::new();
+    let duplicates: BTreeSet<&str> = topics
+        .iter()
+        .map(|topic| topic.topic.as_str())
+        .filter(|topic| !seen.insert(*topic))
+        .collect();
+    if !duplicates.is_empty() {
+        return Err(anyhow!(
+            "topics configured more than once: {}",
+            duplicates.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Syntax of the config file
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err("unknown config format".to_string()),
+        }
+    }
+}
+
+impl ConfigFormat {
+    /// `format` if given, otherwise the format matching the extension of `path`
+    pub fn resolve(path: &Path, format: Option<ConfigFormat>) -> Result<Self> {
+        if let Some(format) = format {
+            return Ok(format);
+        }
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.parse().ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot tell the format of config file {} from its extension, use .toml, .yaml or .yml or pass --config-format",
+                    path.display()
+                )
+            })
+    }
+}
+
+/// Parses the config file, as `format` if given and by its extension otherwise
+pub fn parse_config(path: &Path, format: Option<ConfigFormat>) -> Result<Config> {
+    let format = ConfigFormat::resolve(path, format)?;
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let parsed = match format {
+        ConfigFormat::Toml => toml::from_str(&content).map_err(anyhow::Error::new),
+        ConfigFormat::Yaml => serde_yaml::from_str(&content).map_err(anyhow::Error::new),
+    };
+    let mut config: Config =
+        parsed.with_context(|| format!("failed to parse config file {}", path.display()))?;
+    let default_location = config.default_location.clone();
+    config
+        .broker
+        .iter_mut()
+        .flat_map(|broker| broker.topics.iter_mut())
+        .chain(config.serial.iter_mut().map(|serial| &mut serial.device))
+        .try_for_each(|topic| {
+            topic.fill_location(default_location.as_deref())?;
+            topic.resolve_templates()
+        })
+        .with_context(|| format!("invalid topic in {}", path.display()))?;
+    for broker in &config.broker {
+        validate_configs(&broker.topics)
+            .with_context(|| format!("invalid topics for {} in {}", broker.host, path.display()))?;
+    }
+    Ok(config)
+}
+
+const RELOAD_ATTEMPTS: u32 = 3;
+const RELOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Re-reads the config for a reload, retrying a few times since the file may briefly be missing
+/// or truncated while it is being replaced. Returns None if the current config should be kept.
+pub async fn reload_config(path: &Path, format: Option<ConfigFormat>) -> Option<Config> {
+    for attempt in 1..=RELOAD_ATTEMPTS {
+        match parse_config(path, format) {
+            Ok(config) => return Some(config),
+            Err(e) => {
+                warn!(
+                    "Reloading config failed on attempt {} of {}: {:#}",
+                    attempt, RELOAD_ATTEMPTS, e
+                );
+                if attempt < RELOAD_ATTEMPTS {
+                    tokio::time::sleep(RELOAD_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+    warn!("Keeping the current config");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Level;
+
+    use super::*;
+    use crate::testing::fixtures::{config_file, logged};
+
+    const CONFIG: &str = r#"
+default_location = "Stue"
+
+[[broker]]
+host = "mqtt.local"
+
+[[broker.topics]]
+topic = "tele/stue/SENSOR"
+device_name = "esp32_stue"
+expected_sensors = ["DHT11"]
+
+[[broker.topics]]
+topic = "tele/kjeller/SENSOR"
+device_name = "esp32_kjeller"
+device_location = "Kjeller"
+offsets = { ds18b20 = 0.5 }
+"#;
+
+    /// The same config as `CONFIG`
+    const CONFIG_YAML: &str = r#"
+default_location: Stue
+broker:
+  - host: mqtt.local
+    topics:
+      - topic: tele/stue/SENSOR
+        device_name: esp32_stue
+        expected_sensors: [DHT11]
+      - topic: tele/kjeller/SENSOR
+        device_name: esp32_kjeller
+        device_location: Kjeller
+        offsets:
+          ds18b20: 0.5
+"#;
+
+    const DUPLICATE_TOPICS: &str = r#"
+[[broker]]
+host = "mqtt.local"
+
+[[broker.topics]]
+topic = "tele/stue/SENSOR"
+device_name = "esp32_stue"
+device_location = "Stue"
+
+[[broker.topics]]
+topic = "tele/stue/SENSOR"
+device_name = "esp32_kjeller"
+device_location = "Kjeller"
+"#;
+
+    #[test]
+    fn rejects_duplicate_topics() {
+        let config: Config = toml::from_str(DUPLICATE_TOPICS).unwrap();
+        let error = validate_configs(&config.broker[0].topics).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "topics configured more than once: tele/stue/SENSOR"
+        );
+
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert!(validate_configs(&config.broker[0].topics).is_ok());
+
+        let error =
+            parse_config(&config_file("duplicates.toml", DUPLICATE_TOPICS), None).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("tele/stue/SENSOR"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn parses_yaml_like_toml() {
+        let toml = parse_config(&config_file("config.toml", CONFIG), None).unwrap();
+        for name in ["config.yaml", "config.yml"] {
+            let yaml = parse_config(&config_file(name, CONFIG_YAML), None).unwrap();
+            assert_eq!(yaml.broker[0].topics, toml.broker[0].topics);
+            assert_eq!(
+                serde_json::to_value(&yaml).unwrap(),
+                serde_json::to_value(&toml).unwrap()
+            );
+        }
+
+        // An explicit format wins over the extension
+        let path = config_file("config.conf", CONFIG_YAML);
+        let yaml = parse_config(&path, Some(ConfigFormat::Yaml)).unwrap();
+        assert_eq!(yaml.broker[0].topics, toml.broker[0].topics);
+    }
+
+    #[test]
+    fn rejects_unknown_extensions() {
+        let error = parse_config(&config_file("config.ini", CONFIG), None).unwrap_err();
+        assert!(error.to_string().contains("--config-format"), "{error:#}");
+    }
+
+    #[test]
+    fn resolves_topic_segments() {
+        let resolve = |template| resolve_topic_template(template, "tele/kitchen/SENSOR");
+        assert_eq!(resolve("{1}").unwrap(), "kitchen");
+        assert_eq!(resolve("esp_{1}_{0}").unwrap(), "esp_kitchen_tele");
+        assert_eq!(resolve("Stue").unwrap(), "Stue");
+        // Braces around anything but an index are kept
+        assert_eq!(resolve("{name}").unwrap(), "{name}");
+
+        let error = resolve("{3}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "\"{3}\" refers to segment 3 but tele/kitchen/SENSOR only has 3 segments"
+        );
+    }
+
+    #[test]
+    fn resolves_templates_when_parsing() {
+        const TEMPLATED: &str = r#"
+[[broker]]
+host = "mqtt.local"
+
+[[broker.topics]]
+topic = "tele/kitchen/SENSOR"
+device_name = "esp_{1}"
+device_location = "{1}"
+
+[[broker.topics]]
+topic = "tele/+/SENSOR"
+device_name = "esp_{1}"
+device_location = "{1}"
+"#;
+        let config = parse_config(&config_file("templated.toml", TEMPLATED), None).unwrap();
+        let topics = &config.broker[0].topics;
+        assert_eq!(
+            (
+                topics[0].device_name.as_str(),
+                topics[0].device_location.as_str()
+            ),
+            ("esp_kitchen", "kitchen")
+        );
+        // Wildcard topics are resolved against each topic they match
+        assert_eq!(
+            (
+                topics[1].device_name.as_str(),
+                topics[1].device_location.as_str()
+            ),
+            ("esp_{1}", "{1}")
+        );
+
+        let invalid = TEMPLATED.replace("esp_{1}", "esp_{5}");
+        let error =
+            parse_config(&config_file("invalid_template.toml", &invalid), None).unwrap_err();
+        assert!(format!("{error:#}").contains("segment 5"), "{error:#}");
+    }
+
+    #[test]
+    fn fills_in_the_default_location() {
+        let config = parse_config(&config_file("default_location.toml", CONFIG), None).unwrap();
+        let locations: Vec<&str> = config.broker[0]
+            .topics
+            .iter()
+            .map(|topic| topic.device_location.as_str())
+            .collect();
+        assert_eq!(locations, ["Stue", "Kjeller"]);
+
+        let mut topic = config.broker[0].topics[0].clone();
+        topic.device_location = " ".to_string();
+        topic.fill_location(Some("Gang")).unwrap();
+        assert_eq!(topic.device_location, "Gang");
+    }
+
+    #[test]
+    fn fails_without_any_location() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let mut topic = config.broker[0].topics[0].clone();
+        let error = topic.fill_location(None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "topic \"tele/stue/SENSOR\" has no device_location and there is no default_location"
+        );
+
+        let without_default = CONFIG.replace("default_location = \"Stue\"", "");
+        let path = config_file("no_default_location.toml", &without_default);
+        let error = parse_config(&path, None).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("no default_location"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn retries_a_reload_while_the_file_is_replaced() {
+        // The file is truncated on the first attempt and complete before the second
+        let path = config_file("reload.toml", &CONFIG[..CONFIG.len() / 2]);
+        let replaced = path.clone();
+        let (config, warnings) = logged(Level::WARN, async move {
+            tokio::spawn(async move {
+                tokio::time::sleep(RELOAD_RETRY_DELAY / 2).await;
+                std::fs::write(replaced, CONFIG).unwrap();
+            });
+            reload_config(&path, None).await
+        });
+        assert_eq!(config.unwrap().broker[0].topics.len(), 2);
+        let warnings: Vec<&String> = warnings
+            .iter()
+            .filter(|line| line.contains("WARN"))
+            .collect();
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("attempt 1 of 3"), "{warnings:?}");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_config_when_the_file_stays_unreadable() {
+        let path = config_file("missing", "").with_extension("toml");
+        assert!(reload_config(&path, None).await.is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use metrics::counter;
+use serde::Serialize;
+
+/// A payload that could not be parsed, kept for later inspection
+#[derive(Serialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub payload: String,
+    pub error: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(topic: &str, payload: &str, error: &impl std::fmt::Display) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            error: error.to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Appends `letter` to `path` as one JSON line, creating the file if needed
+pub fn append(path: &Path, letter: &DeadLetter) -> Result<()> {
+    let mut line = serde_json::to_string(letter)?;
+    line.push('\n');
+    // One write per line so concurrent appends do not interleave
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write dead letter to {}", path.display()))?;
+    counter!("sensor_dead_letters_total").increment(1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn appends_one_json_line_per_unparsable_payload() {
+        let path = std::env::temp_dir().join(format!(
+            "sensor_monitor_dead_letters_{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let payloads = ["{\"DS18B20\": ", "not json"];
+        for payload in payloads {
+            let error = serde_json::from_str::<serde_json::Value>(payload).unwrap_err();
+            append(&path, &DeadLetter::new("tele/stue/SENSOR", payload, &error)).unwrap();
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), payloads.len());
+        for (line, payload) in lines.iter().zip(payloads) {
+            assert_eq!(line["topic"], "tele/stue/SENSOR");
+            assert_eq!(line["payload"], payload);
+            let error = serde_json::from_str::<serde_json::Value>(payload).unwrap_err();
+            assert_eq!(line["error"], error.to_string());
+            assert!(line["timestamp"]
+                .as_str()
+                .unwrap()
+                .parse::<DateTime<Utc>>()
+                .is_ok());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
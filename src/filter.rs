@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+
+use metrics::{counter, gauge};
+use tracing::{info, warn};
+
+use crate::{config::Bounds, lru::BoundedMap};
+
+/// Plausible ranges of the units sensors are registered with, used when range validation is
+/// enabled
+fn default_unit_bounds() -> HashMap<String, Bounds> {
+    HashMap::from([
+        (
+            "%".to_string(),
+            Bounds {
+                min: 0.0,
+                max: 100.0,
+            },
+        ),
+        (
+            "hPa".to_string(),
+            Bounds {
+                min: 300.0,
+                max: 1100.0,
+            },
+        ),
+    ])
+}
+
+/// Plausible ranges of the temperature and humidity sensors, used when range validation is enabled
+fn default_sensor_bounds() -> HashMap<String, Bounds> {
+    let temperature = Bounds {
+        min: -40.0,
+        max: 85.0,
+    };
+    let humidity = Bounds {
+        min: 0.0,
+        max: 100.0,
+    };
+    [
+        ("ds18b20", temperature),
+        ("dht11_temperature", temperature),
+        ("bme280_temperature", temperature),
+        ("sht3x_temperature", temperature),
+        ("dht11_humidity", humidity),
+        ("bme280_humidity", humidity),
+        ("sht3x_humidity", humidity),
+    ]
+    .into_iter()
+    .map(|(sensor_key, bounds)| (sensor_key.to_string(), bounds))
+    .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Filters {
+    sentinels: HashMap<String, Vec<f32>>,
+    unit_bounds: HashMap<String, Bounds>,
+    sensor_bounds: HashMap<String, Bounds>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self::new(HashMap::new(), HashMap::new(), HashMap::new(), false)
+    }
+}
+
+impl Filters {
+    /// `unit_bounds` and `sensor_bounds` always apply, `validate_ranges` adds the defaults for
+    /// the units and sensors not listed
+    pub fn new(
+        sentinels: HashMap<String, Vec<f32>>,
+        unit_bounds: HashMap<String, Bounds>,
+        sensor_bounds: HashMap<String, Bounds>,
+        validate_ranges: bool,
+    ) -> Self {
+        let (mut bounds, mut ranges) = if validate_ranges {
+            (default_unit_bounds(), default_sensor_bounds())
+        } else {
+            (HashMap::new(), HashMap::new())
+        };
+        bounds.extend(unit_bounds);
+        ranges.extend(sensor_bounds);
+        Self {
+            sentinels,
+            unit_bounds: bounds,
+            sensor_bounds: ranges,
+        }
+    }
+
+    /// Whether the reading equals a configured sentinel value for the sensor, like the 85.0 a
+    /// DS18B20 reports on power-on before it has done a conversion.
+    fn is_sentinel(&self, sensor_key: &'static str, value: f32) -> bool {
+        self.sentinels
+            .get(sensor_key)
+            .is_some_and(|sentinels| sentinels.contains(&value))
+    }
+
+    /// Returns the bounds for a unit sensors are registered with in hemrs, if any
+    pub fn bounds(&self, unit: &str) -> Option<&Bounds> {
+        self.unit_bounds.get(unit)
+    }
+
+    /// Whether the reading is within the plausible range of the sensor, sensors without one
+    /// accept anything
+    pub fn validate_reading(&self, sensor_key: &str, value: f32) -> bool {
+        self.sensor_bounds
+            .get(sensor_key)
+            .is_none_or(|bounds| bounds.contains(value))
+    }
+
+    pub fn accept(&self, topic: &str, sensor_key: &'static str, unit: &str, value: f32) -> bool {
+        if self.is_sentinel(sensor_key, value) {
+            warn!(
+                "Dropping sentinel value {} from {} on {}",
+                value, sensor_key, topic
+            );
+            counter!("sensor_sentinel_readings_total", "sensor" => sensor_key).increment(1);
+            return false;
+        }
+        let bounds = match self.sensor_bounds.get(sensor_key) {
+            Some(bounds) if !self.validate_reading(sensor_key, value) => Some(bounds),
+            _ => self.bounds(unit).filter(|bounds| !bounds.contains(value)),
+        };
+        if let Some(bounds) = bounds {
+            warn!(
+                "Dropping {} from {} on {}, outside {}..{}",
+                value, sensor_key, topic, bounds.min, bounds.max
+            );
+            counter!("sensor_out_of_bounds_readings_total", "sensor" => sensor_key).increment(1);
+            return false;
+        }
+        true
+    }
+}
+
+/// Drops readings older than the last accepted reading of the same device and sensor, so a device
+/// clock jumping backwards does not produce out of order writes
+#[derive(Debug)]
+pub struct MonotonicTime {
+    enabled: bool,
+    last: Mutex<BoundedMap<(String, &'static str), DateTime<Utc>>>,
+}
+
+impl MonotonicTime {
+    pub fn new(enabled: bool, capacity: usize) -> Self {
+        Self {
+            enabled,
+            last: Mutex::new(BoundedMap::new("monotonic_time", capacity)),
+        }
+    }
+
+    pub fn accept(&self, device: &str, sensor_key: &'static str, time: DateTime<Utc>) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let mut last = self.last.lock().unwrap();
+        match last.get(&(device.to_string(), sensor_key)) {
+            Some(previous) if time < *previous => {
+                warn!(
+                    "Dropping {} reading from {} at {}, older than {}",
+                    sensor_key, device, time, previous
+                );
+                counter!("sensor_out_of_order_readings_total", "sensor" => sensor_key).increment(1);
+                false
+            }
+            _ => {
+                last.insert((device.to_string(), sensor_key), time);
+                true
+            }
+        }
+    }
+}
+
+/// Drops messages from a device arriving less than `min_interval` after the last accepted one, so a
+/// device publishing far too often does not overwhelm hemrs
+#[derive(Debug)]
+pub struct RateLimit {
+    min_interval: Option<Duration>,
+    last: Mutex<BoundedMap<String, Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(min_interval: Option<Duration>, capacity: usize) -> Self {
+        Self {
+            min_interval,
+            last: Mutex::new(BoundedMap::new("rate_limit", capacity)),
+        }
+    }
+
+    pub fn accept(&self, device: &str) -> bool {
+        let Some(min_interval) = self.min_interval else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        match last.get(&device.to_string()) {
+            Some(previous) if now.duration_since(*previous) < min_interval => false,
+            _ => {
+                last.insert(device.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RejectionStreak {
+    rejected: u32,
+    suppressed_until: Option<Instant>,
+}
+
+/// Stops processing a sensor for a cooldown period once `after` readings in a row were rejected,
+/// so a broken sensor does not flood the logs and metrics
+#[derive(Debug)]
+pub struct Deadtime {
+    after: Option<u32>,
+    cooldown: Duration,
+    streaks: Mutex<BoundedMap<(String, &'static str), RejectionStreak>>,
+}
+
+impl Deadtime {
+    /// `after` of 0 turns the deadtime off like `None`, rather than suppressing every sensor on
+    /// its first rejected reading
+    pub fn new(after: Option<u32>, cooldown: Duration, capacity: usize) -> Self {
+        Self {
+            after: after.filter(|after| *after > 0),
+            cooldown,
+            streaks: Mutex::new(BoundedMap::new("deadtime", capacity)),
+        }
+    }
+
+    fn update_gauge(streaks: &BoundedMap<(String, &'static str), RejectionStreak>) {
+        let suppressed = streaks
+            .values()
+            .filter(|streak| streak.suppressed_until.is_some())
+            .count();
+        gauge!("suppressed_sensors").set(suppressed as f64);
+    }
+
+    /// Whether readings from the sensor are currently suppressed
+    pub fn suppressed(&self, device: &str, sensor_key: &'static str) -> bool {
+        if self.after.is_none() {
+            return false;
+        }
+        let mut streaks = self.streaks.lock().unwrap();
+        let Some(streak) = streaks.get_mut(&(device.to_string(), sensor_key)) else {
+            return false;
+        };
+        match streak.suppressed_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                info!("Re-enabling {} on {} after deadtime", sensor_key, device);
+                *streak = RejectionStreak::default();
+                Self::update_gauge(&streaks);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records whether a reading was accepted, starting the deadtime once too many were rejected
+    pub fn record(&self, device: &str, sensor_key: &'static str, accepted: bool) {
+        let Some(after) = self.after else {
+            return;
+        };
+        let mut streaks = self.streaks.lock().unwrap();
+        if accepted {
+            streaks.remove(&(device.to_string(), sensor_key));
+            return;
+        }
+        let streak =
+            streaks.get_or_insert_with((device.to_string(), sensor_key), RejectionStreak::default);
+        streak.rejected += 1;
+        if streak.rejected >= after {
+            warn!(
+                "Suppressing {} on {} for {:?} after {} rejected readings in a row",
+                sensor_key, device, self.cooldown, streak.rejected
+            );
+            streak.suppressed_until = Some(Instant::now() + self.cooldown);
+            Self::update_gauge(&streaks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        mqtt::handle_payload,
+        testing::{
+            fixtures::{topic_to_device, ProcessorState},
+            InMemorySink,
+        },
+    };
+
+    fn filters(unit_bounds: &[(&str, f32, f32)], validate_ranges: bool) -> Filters {
+        let unit_bounds = unit_bounds
+            .iter()
+            .map(|(unit, min, max)| {
+                (
+                    unit.to_string(),
+                    Bounds {
+                        min: *min,
+                        max: *max,
+                    },
+                )
+            })
+            .collect();
+        Filters::new(HashMap::new(), unit_bounds, HashMap::new(), validate_ranges)
+    }
+
+    #[test]
+    fn checks_units_only_when_enabled_or_configured() {
+        let unchecked = filters(&[], false);
+        assert!(unchecked.accept("tele/stue/SENSOR", "dht11_humidity", "%", 150.0));
+        assert!(unchecked.accept("tele/stue/SENSOR", "bme280_pressure", "hPa", 50.0));
+
+        let configured = filters(&[("°C", -55.0, 125.0)], false);
+        assert!(configured.accept("tele/stue/SENSOR", "ds18b20", "°C", 125.0));
+        assert!(!configured.accept("tele/stue/SENSOR", "ds18b20", "°C", 126.0));
+        assert!(configured.accept("tele/stue/SENSOR", "dht11_humidity", "%", 150.0));
+    }
+
+    #[test]
+    fn limits_percentages_by_default() {
+        let filters = filters(&[], true);
+        // A sensor without a default range of its own, so only its unit limits it
+        for value in [0.0, 45.5, 100.0] {
+            assert!(filters.accept("tele/stue/SENSOR", "soil_moisture", "%", value));
+        }
+        for value in [-0.1, 100.1, 150.0] {
+            assert!(!filters.accept("tele/stue/SENSOR", "soil_moisture", "%", value));
+        }
+    }
+
+    #[test]
+    fn limits_pressures_by_default() {
+        let filters = filters(&[], true);
+        for value in [300.0, 1013.25, 1100.0] {
+            assert!(filters.accept("tele/stue/SENSOR", "bme280_pressure", "hPa", value));
+        }
+        for value in [0.0, 299.9, 1100.1] {
+            assert!(!filters.accept("tele/stue/SENSOR", "bme280_pressure", "hPa", value));
+        }
+    }
+
+    #[test]
+    fn configured_unit_bounds_override_the_defaults() {
+        let filters = filters(&[("hPa", 800.0, 1200.0)], true);
+        assert!(filters.accept("tele/stue/SENSOR", "bme280_pressure", "hPa", 1150.0));
+        assert!(!filters.accept("tele/stue/SENSOR", "bme280_pressure", "hPa", 500.0));
+        // Units without a range are not checked
+        assert!(filters.accept("tele/stue/SENSOR", "energy_power", "W", 1e6));
+    }
+
+    #[test]
+    fn suppresses_a_sensor_after_rejections_in_a_row() {
+        let deadtime = Deadtime::new(Some(2), Duration::from_millis(50), 10);
+        deadtime.record("stue", "ds18b20", false);
+        deadtime.record("stue", "ds18b20", true);
+        deadtime.record("stue", "ds18b20", false);
+        assert!(!deadtime.suppressed("stue", "ds18b20"));
+
+        deadtime.record("stue", "ds18b20", false);
+        assert!(deadtime.suppressed("stue", "ds18b20"));
+        assert!(!deadtime.suppressed("stue", "dht11_temperature"));
+        assert!(!deadtime.suppressed("kjeller", "ds18b20"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!deadtime.suppressed("stue", "ds18b20"));
+        deadtime.record("stue", "ds18b20", false);
+        assert!(!deadtime.suppressed("stue", "ds18b20"));
+    }
+
+    #[test]
+    fn deadtime_after_zero_is_off() {
+        for after in [None, Some(0)] {
+            let deadtime = Deadtime::new(after, Duration::from_secs(60), 10);
+            for _ in 0..3 {
+                deadtime.record("stue", "ds18b20", false);
+                assert!(!deadtime.suppressed("stue", "ds18b20"), "{:?}", after);
+            }
+        }
+    }
+
+    #[test]
+    fn validates_readings_against_the_sensor_ranges() {
+        let filters = Filters::new(HashMap::new(), HashMap::new(), HashMap::new(), true);
+        for sensor_key in [
+            "ds18b20",
+            "dht11_temperature",
+            "bme280_temperature",
+            "sht3x_temperature",
+        ] {
+            for value in [-40.0, 21.5, 85.0] {
+                assert!(
+                    filters.validate_reading(sensor_key, value),
+                    "{sensor_key} {value}"
+                );
+            }
+            for value in [-40.1, 85.1, 127.0] {
+                assert!(
+                    !filters.validate_reading(sensor_key, value),
+                    "{sensor_key} {value}"
+                );
+            }
+        }
+        for sensor_key in ["dht11_humidity", "bme280_humidity", "sht3x_humidity"] {
+            for value in [0.0, 45.0, 100.0] {
+                assert!(
+                    filters.validate_reading(sensor_key, value),
+                    "{sensor_key} {value}"
+                );
+            }
+            for value in [-1.0, 100.1, 255.0] {
+                assert!(
+                    !filters.validate_reading(sensor_key, value),
+                    "{sensor_key} {value}"
+                );
+            }
+        }
+        // Sensors without a range accept anything
+        assert!(filters.validate_reading("energy_power", -1e6));
+
+        let unchecked = Filters::default();
+        assert!(unchecked.validate_reading("ds18b20", 127.0));
+        assert!(unchecked.accept("tele/stue/SENSOR", "dht11_humidity", "%", 255.0));
+        assert!(!filters.accept("tele/stue/SENSOR", "dht11_humidity", "%", 255.0));
+    }
+
+    #[test]
+    fn drops_readings_from_before_a_backward_time_jump() {
+        let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+        let monotonic = MonotonicTime::new(true, 10);
+        assert!(monotonic.accept("stue", "ds18b20", at("2026-10-15T08:00:00Z")));
+        // The device clock jumps back an hour
+        assert!(!monotonic.accept("stue", "ds18b20", at("2026-10-15T07:00:00Z")));
+        assert!(!monotonic.accept("stue", "ds18b20", at("2026-10-15T07:59:59Z")));
+        // Other sensors and devices keep their own last time
+        assert!(monotonic.accept("stue", "dht11_temperature", at("2026-10-15T07:00:00Z")));
+        assert!(monotonic.accept("kjeller", "ds18b20", at("2026-10-15T07:00:00Z")));
+        // Equal and later times are accepted once the clock catches up
+        assert!(monotonic.accept("stue", "ds18b20", at("2026-10-15T08:00:00Z")));
+        assert!(monotonic.accept("stue", "ds18b20", at("2026-10-15T08:01:00Z")));
+
+        let disabled = MonotonicTime::new(false, 10);
+        assert!(disabled.accept("stue", "ds18b20", at("2026-10-15T08:00:00Z")));
+        assert!(disabled.accept("stue", "ds18b20", at("2026-10-15T07:00:00Z")));
+    }
+
+    #[test]
+    fn limits_messages_per_device() {
+        let rate_limit = RateLimit::new(Some(Duration::from_millis(50)), 10);
+        assert!(rate_limit.accept("stue"));
+        assert!(!rate_limit.accept("stue"));
+        assert!(rate_limit.accept("kjeller"));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(rate_limit.accept("stue"));
+
+        let unlimited = RateLimit::new(None, 10);
+        assert!(unlimited.accept("stue") && unlimited.accept("stue"));
+    }
+
+    #[tokio::test]
+    async fn stores_only_the_first_of_two_rapid_messages() {
+        const TOPIC: &str = "tele/stue/SENSOR";
+        let state = ProcessorState::new(Config {
+            min_interval_secs: Some(60),
+            ..Config::default()
+        });
+        let sink = InMemorySink::new();
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        for temperature in [21.5, 22.5] {
+            let payload = format!(
+                r#"{{"Time":"2026-10-15T08:00:00","DHT11":{{"Temperature":{temperature},"Humidity":40.0,"DewPoint":7.5}},"TempUnit":"C"}}"#
+            );
+            handle_payload(TOPIC, &payload, &state.processor(&sink), &devices)
+                .await
+                .unwrap();
+        }
+        let stored: Vec<f64> = sink.measurements().iter().map(|m| m.measurement).collect();
+        assert_eq!(stored, [21.5, 40.0, 7.5]);
+    }
+}
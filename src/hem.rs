@@ -1,15 +1,215 @@
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::{collections::HashMap, fmt::Debug};
 
-#[derive(Debug)]
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use metrics::counter;
+use reqwest::header::CONTENT_TYPE;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{config::SensorConfig, retry::RetryPolicy};
+
+/// Joins `path` to `base` with exactly one slash between them, however either is written
+pub fn join_url(base: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Where hemrs serves measurements, sensors and devices
+#[derive(Debug, Clone, PartialEq)]
+pub struct HemrsUrls {
+    pub measurements: String,
+    pub sensors: String,
+    pub devices: String,
+    /// Where the firmware and IP address of devices are stored, when enabled
+    pub device_metadata: Option<String>,
+    paths: [String; 3],
+    device_metadata_path: Option<String>,
+}
+
+impl HemrsUrls {
+    pub fn new(
+        base_url: &str,
+        measurements_path: &str,
+        sensors_path: &str,
+        devices_path: &str,
+    ) -> Self {
+        Self {
+            measurements: join_url(base_url, measurements_path),
+            sensors: join_url(base_url, sensors_path),
+            devices: join_url(base_url, devices_path),
+            device_metadata: None,
+            paths: [
+                measurements_path.to_string(),
+                sensors_path.to_string(),
+                devices_path.to_string(),
+            ],
+            device_metadata_path: None,
+        }
+    }
+
+    /// Stores device metadata at `path`, joined to the base url
+    pub fn with_device_metadata(mut self, base_url: &str, path: &str) -> Self {
+        self.device_metadata = Some(join_url(base_url, path));
+        self.device_metadata_path = Some(path.to_string());
+        self
+    }
+
+    /// The same endpoints on another hemrs instance
+    pub fn with_base(&self, base_url: &str) -> Self {
+        let [measurements, sensors, devices] = &self.paths;
+        let urls = Self::new(base_url, measurements, sensors, devices);
+        match &self.device_metadata_path {
+            Some(path) => urls.with_device_metadata(base_url, path),
+            None => urls,
+        }
+    }
+}
+
+/// A sensor as registered in hemrs
+#[derive(Debug, Clone)]
+pub struct RegisteredSensor {
+    pub id: i32,
+    pub name: String,
+    pub unit: String,
+}
+
+/// Registered sensors by key
+#[derive(Debug, Default)]
 pub struct SensorIds {
-    pub ds18b20: i32,
-    pub dht11_temperature: i32,
-    pub dht11_humidity: i32,
-    pub dht11_dew_point: i32,
+    sensors: HashMap<String, RegisteredSensor>,
 }
 
+impl SensorIds {
+    /// Every sensor with id 0, for when sensors are identified by name or not registered at all
+    pub fn unregistered(sensors: &[SensorConfig]) -> Self {
+        Self {
+            sensors: sensors
+                .iter()
+                .map(|sensor| {
+                    let registered = RegisteredSensor {
+                        id: 0,
+                        name: sensor.name.clone(),
+                        unit: sensor.unit.clone(),
+                    };
+                    (sensor.key.clone(), registered)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&RegisteredSensor> {
+        self.sensors.get(key)
+    }
+
+    /// Key of the sensor registered as `name`, or the name itself for unknown sensors
+    pub fn key<'a>(&'a self, name: &'a str) -> &'a str {
+        self.sensors
+            .iter()
+            .find(|(_, sensor)| sensor.name == name)
+            .map_or(name, |(key, _)| key.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct SensorDefinition {
+    pub key: &'static str,
+    pub name: &'static str,
+    pub unit: &'static str,
+}
+
+/// Sensors registered when the config does not list its own
+pub const SENSORS: &[SensorDefinition] = &[
+    SensorDefinition {
+        key: "ds18b20",
+        name: "DS18B20",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "dht11_temperature",
+        name: "DHT11 Temperature",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "dht11_humidity",
+        name: "DHT11 Humidity",
+        unit: "%",
+    },
+    SensorDefinition {
+        key: "dht11_dew_point",
+        name: "DHT11 Dew Point",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "dht11_heat_index",
+        name: "DHT11 Heat Index",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "dht11_absolute_humidity",
+        name: "DHT11 Absolute Humidity",
+        unit: "g/m³",
+    },
+    SensorDefinition {
+        key: "bme280_temperature",
+        name: "BME280 Temperature",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "bme280_humidity",
+        name: "BME280 Humidity",
+        unit: "%",
+    },
+    SensorDefinition {
+        key: "bme280_pressure",
+        name: "BME280 Pressure",
+        unit: "hPa",
+    },
+    SensorDefinition {
+        key: "bme280_dew_point",
+        name: "BME280 Dew Point",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "sht3x_temperature",
+        name: "SHT3X Temperature",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "sht3x_humidity",
+        name: "SHT3X Humidity",
+        unit: "%",
+    },
+    SensorDefinition {
+        key: "sht3x_dew_point",
+        name: "SHT3X Dew Point",
+        unit: "°C",
+    },
+    SensorDefinition {
+        key: "energy_voltage",
+        name: "ENERGY Voltage",
+        unit: "V",
+    },
+    SensorDefinition {
+        key: "energy_current",
+        name: "ENERGY Current",
+        unit: "A",
+    },
+    SensorDefinition {
+        key: "energy_power",
+        name: "ENERGY Power",
+        unit: "W",
+    },
+    SensorDefinition {
+        key: "energy_total",
+        name: "ENERGY Total",
+        unit: "kWh",
+    },
+];
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Sensor {
     #[serde(skip_serializing)]
@@ -28,80 +228,423 @@ pub struct Device {
     location: String,
 }
 
-pub fn fetch_devices(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<Device>> {
-    let devices = client.get(url).send()?.json::<Vec<Device>>()?;
-    Ok(devices)
+const MAX_LOGGED_BODY_LENGTH: usize = 256;
+
+/// The HTTP requests setting up devices and sensors needs, so it can run against a fake hemrs
+#[async_trait]
+pub trait HttpBackend: Sync {
+    /// GETs `url` and parses the JSON list of `kind` entries it answers with, `kind` is used in
+    /// errors and metrics
+    async fn get_json<T: DeserializeOwned + Send>(
+        &self,
+        url: &str,
+        kind: &'static str,
+    ) -> Result<T>;
+
+    /// POSTs `body` as JSON to `url`, returning the answer if it parses as `T`
+    async fn post_json<B: Serialize + Sync, T: DeserializeOwned + Send>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<Option<T>>;
 }
 
-pub fn fetch_sensors(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<Sensor>> {
-    let devices = client.get(url).send()?.json::<Vec<Sensor>>()?;
-    Ok(devices)
+/// Talks to hemrs through reqwest, retrying failed requests
+#[derive(Debug, Clone)]
+pub struct ReqwestBackend {
+    client: reqwest::Client,
+    retry: RetryPolicy,
 }
 
-fn setup_sensor(
-    client: &reqwest::blocking::Client,
-    url: &str,
-    sensor_name: &str,
-    sensor_unit: &str,
-) -> Result<i32> {
-    let sensors = fetch_sensors(client, url)?;
-    let device = sensors.iter().find(|d| d.name == sensor_name);
-    match device {
-        Some(d) => {
-            info!("{:?}", d);
-            Ok(d.id)
-        }
-        None => {
-            let new_device = Sensor {
-                id: 0,
-                name: sensor_name.to_string(),
-                unit: sensor_unit.to_string(),
-            };
-            let response = client.post(url).json(&new_device).send()?;
-            info!("{:?}", response);
-            setup_sensor(client, url, sensor_name, sensor_unit)
+impl ReqwestBackend {
+    pub fn new(client: reqwest::Client, retry: RetryPolicy) -> Self {
+        Self { client, retry }
+    }
+}
+
+#[async_trait]
+impl HttpBackend for ReqwestBackend {
+    async fn get_json<T: DeserializeOwned + Send>(
+        &self,
+        url: &str,
+        kind: &'static str,
+    ) -> Result<T> {
+        let response = self.retry.send(self.client.get(url)).await?;
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if !status.is_success() || !content_type.contains("json") {
+            let body: String = response
+                .text()
+                .await
+                .unwrap_or_default()
+                .chars()
+                .take(MAX_LOGGED_BODY_LENGTH)
+                .collect();
+            error!(
+                "expected JSON {} list from {}, got status {} with content type {:?}: {}",
+                kind, url, status, content_type, body
+            );
+            counter!("hemrs_unexpected_responses_total", "kind" => kind).increment(1);
+            return Err(anyhow!(
+                "expected JSON {} list from {}, got status {}",
+                kind,
+                url,
+                status
+            ));
         }
+        Ok(response.json::<T>().await?)
     }
+
+    async fn post_json<B: Serialize + Sync, T: DeserializeOwned + Send>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<Option<T>> {
+        let response = self.retry.send(self.client.post(url).json(body)).await?;
+        info!("{:?}", response);
+        Ok(response.json::<T>().await.ok())
+    }
+}
+
+/// Something hemrs lists and creates, a device or a sensor
+pub trait Entry: Serialize + DeserializeOwned + Debug + Send + Sync {
+    const KIND: &'static str;
+
+    fn id(&self) -> i32;
 }
 
-pub fn setup_sensors(client: &reqwest::blocking::Client, url: &str) -> Result<SensorIds> {
-    let ds18b20 = setup_sensor(client, url, "DS18B20", "°C")?;
-    let dht11_temperature = setup_sensor(client, url, "DHT11 Temperature", "°C")?;
-    let dht11_humidity = setup_sensor(client, url, "DHT11 Humidity", "%")?;
-    let dht11_dew_point = setup_sensor(client, url, "DHT11 Dew Point", "°C")?;
+impl Entry for Sensor {
+    const KIND: &'static str = "sensor";
 
-    Ok(SensorIds {
-        ds18b20,
-        dht11_temperature,
-        dht11_humidity,
-        dht11_dew_point,
-    })
+    fn id(&self) -> i32 {
+        self.id
+    }
 }
 
-pub fn setup_device(
-    client: &reqwest::blocking::Client,
+impl Entry for Device {
+    const KIND: &'static str = "device";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+/// The entries listed at `url`, fetched on the first lookup and kept up to date as entries are
+/// created, so setting up many devices or sensors does not list them all every time
+#[derive(Debug)]
+pub struct Registry<T> {
+    url: String,
+    entries: Option<Vec<T>>,
+}
+
+impl<T: Entry> Registry<T> {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            entries: None,
+        }
+    }
+
+    async fn entries(&mut self, backend: &impl HttpBackend) -> Result<&[T]> {
+        let entries = match self.entries.take() {
+            Some(entries) => entries,
+            None => backend.get_json(&self.url, T::KIND).await?,
+        };
+        Ok(self.entries.insert(entries))
+    }
+
+    /// Id of the first entry matching `matches`, creating `new` when there is none
+    async fn find_or_create(
+        &mut self,
+        backend: &impl HttpBackend,
+        matches: impl Fn(&T) -> bool,
+        new: T,
+    ) -> Result<i32> {
+        let found = self.entries(backend).await?.iter().find(|e| matches(e));
+        if let Some(entry) = found {
+            info!("{:?}", entry);
+            return Ok(entry.id());
+        }
+        // When hemrs answers with the created entry its id is known, otherwise the entries are
+        // listed again to learn it
+        match backend.post_json::<T, T>(&self.url, &new).await? {
+            Some(created) => {
+                let id = created.id();
+                self.entries.get_or_insert_with(Vec::new).push(created);
+                Ok(id)
+            }
+            None => {
+                self.entries = None;
+                self.entries(backend)
+                    .await?
+                    .iter()
+                    .find(|e| matches(e))
+                    .map(Entry::id)
+                    .ok_or_else(|| anyhow!("created {:?} but {} does not list it", new, self.url))
+            }
+        }
+    }
+}
+
+pub async fn setup_sensor(
+    backend: &impl HttpBackend,
+    sensors: &mut Registry<Sensor>,
+    sensor_name: &str,
+    sensor_unit: &str,
+) -> Result<i32> {
+    let new_sensor = Sensor {
+        id: 0,
+        name: sensor_name.to_string(),
+        unit: sensor_unit.to_string(),
+    };
+    sensors
+        .find_or_create(backend, |s| s.name == sensor_name, new_sensor)
+        .await
+}
+
+pub async fn setup_sensors(
+    backend: &impl HttpBackend,
     url: &str,
+    sensors: &[SensorConfig],
+) -> Result<SensorIds> {
+    let mut registry = Registry::new(url);
+    let mut ids = SensorIds::default();
+    for sensor in sensors {
+        let id = setup_sensor(backend, &mut registry, &sensor.name, &sensor.unit).await?;
+        ids.sensors.insert(
+            sensor.key.clone(),
+            RegisteredSensor {
+                id,
+                name: sensor.name.clone(),
+                unit: sensor.unit.clone(),
+            },
+        );
+    }
+    Ok(ids)
+}
+
+pub async fn setup_device(
+    backend: &impl HttpBackend,
+    devices: &mut Registry<Device>,
     device_name: &str,
     device_location: &str,
 ) -> Result<DeviceId> {
-    let devices = fetch_devices(client, url)?;
-    let device = devices
-        .iter()
-        .find(|d| d.name == device_name && d.location == device_location);
-    match device {
-        Some(d) => {
-            info!("{:?}", d);
-            Ok(d.id)
+    let new_device = Device {
+        id: 0,
+        name: device_name.to_string(),
+        location: device_location.to_string(),
+    };
+    devices
+        .find_or_create(
+            backend,
+            |d| d.name == device_name && d.location == device_location,
+            new_device,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::fixtures::{FakeBackend, MockServer};
+
+    const DEVICES: &str = "http://hemrs/api/devices";
+
+    #[tokio::test]
+    async fn creates_a_missing_device_and_fetches_its_id() {
+        // hemrs lists no devices at first and does not answer a POST with the created one
+        let backend = FakeBackend::new(Vec::new(), false);
+        let mut devices = Registry::new(DEVICES);
+        let id = setup_device(&backend, &mut devices, "esp32_stue", "Stue")
+            .await
+            .unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 1);
+
+        // Found in the list fetched after creating it
+        let id = setup_device(&backend, &mut devices, "esp32_stue", "Stue")
+            .await
+            .unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn finds_an_existing_device_without_creating_it() {
+        let backend = FakeBackend::new(
+            vec![json!({"id": 7, "name": "esp32_stue", "location": "Stue"})],
+            false,
+        );
+        let mut devices = Registry::new(DEVICES);
+        let id = setup_device(&backend, &mut devices, "esp32_stue", "Stue")
+            .await
+            .unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 0);
+
+        // The same name in another location is another device
+        let id = setup_device(&backend, &mut devices, "esp32_stue", "Kjeller")
+            .await
+            .unwrap();
+        assert_eq!(id, 2);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fetches_each_list_once() {
+        let sensors: Vec<SensorConfig> = SENSORS
+            .iter()
+            .map(|sensor| SensorConfig {
+                key: sensor.key.to_string(),
+                name: sensor.name.to_string(),
+                unit: sensor.unit.to_string(),
+            })
+            .collect();
+        let backend = FakeBackend::new(
+            vec![json!({"id": 1, "name": "DS18B20", "unit": "°C"})],
+            true,
+        );
+        let ids = setup_sensors(&backend, "http://hemrs/api/sensors", &sensors)
+            .await
+            .unwrap();
+        assert_eq!(ids.get("ds18b20").unwrap().id, 1);
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), SENSORS.len() - 1);
+
+        let backend = FakeBackend::new(Vec::new(), true);
+        let mut devices = Registry::new(DEVICES);
+        for name in ["esp32_stue", "esp32_kjeller", "esp32_stue"] {
+            setup_device(&backend, &mut devices, name, "Stue")
+                .await
+                .unwrap();
+        }
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_html_instead_of_a_json_list() {
+        let backend = ReqwestBackend::new(
+            reqwest::Client::new(),
+            RetryPolicy::new(0, Default::default()),
+        );
+        let html = "<html><body>502 Bad Gateway</body></html>";
+        for status in [200, 502] {
+            let server = MockServer::start_with_body(&[status], "text/html", html);
+            let url = join_url(&server.url, "api/devices");
+            let error = backend
+                .get_json::<Vec<Device>>(&url, "device")
+                .await
+                .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "expected JSON device list from {url}, got status {}",
+                    reqwest::StatusCode::from_u16(status).unwrap()
+                )
+            );
         }
-        None => {
-            let new_device = Device {
-                id: 0,
-                name: device_name.to_string(),
-                location: device_location.to_string(),
-            };
-            let response = client.post(url).json(&new_device).send()?;
-            info!("{:?}", response);
-            setup_device(client, url, device_name, device_location)
+
+        let server = MockServer::start_with_body(
+            &[200],
+            "application/json",
+            r#"[{"id":3,"name":"esp32_stue","location":"Stue"}]"#,
+        );
+        let devices: Vec<Device> = backend
+            .get_json(&join_url(&server.url, "api/devices"), "device")
+            .await
+            .unwrap();
+        assert_eq!(devices[0].id, 3);
+    }
+
+    #[test]
+    fn joins_urls_with_one_slash() {
+        for (base, path) in [
+            ("http://hemrs", "api/devices"),
+            ("http://hemrs/", "api/devices"),
+            ("http://hemrs", "/api/devices"),
+            ("http://hemrs//", "//api/devices"),
+        ] {
+            assert_eq!(join_url(base, path), "http://hemrs/api/devices");
         }
+        assert_eq!(
+            join_url("http://hemrs/prefix/", "/api/measurements"),
+            "http://hemrs/prefix/api/measurements"
+        );
+
+        let urls = HemrsUrls::new("http://hemrs/", "/m", "s/", "d");
+        assert_eq!(
+            (
+                urls.measurements.as_str(),
+                urls.sensors.as_str(),
+                urls.devices.as_str()
+            ),
+            ("http://hemrs/m", "http://hemrs/s/", "http://hemrs/d")
+        );
+        assert_eq!(urls.with_base("http://other").devices, "http://other/d");
+    }
+
+    #[test]
+    fn registers_the_built_in_sensors_by_default() {
+        let config = crate::config::Config::default();
+        let keys: Vec<String> = config.sensor_configs().into_iter().map(|s| s.key).collect();
+        let built_in: Vec<&str> = SENSORS.iter().map(|sensor| sensor.key).collect();
+        assert_eq!(keys, built_in);
+        let unique: std::collections::HashSet<&str> = built_in.iter().copied().collect();
+        assert_eq!(unique.len(), SENSORS.len());
+    }
+
+    #[tokio::test]
+    async fn registers_a_custom_sensor_list() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+[[sensors]]
+key = "ds18b20"
+name = "Temperatur"
+unit = "C"
+
+[[sensors]]
+key = "dht11_humidity"
+name = "Fuktighet"
+unit = "%"
+"#,
+        )
+        .unwrap();
+        let backend = FakeBackend::new(
+            vec![json!({"id": 4, "name": "Fuktighet", "unit": "%"})],
+            true,
+        );
+        let ids = setup_sensors(
+            &backend,
+            "http://hemrs/api/sensors",
+            &config.sensor_configs(),
+        )
+        .await
+        .unwrap();
+
+        let temperature = ids.get("ds18b20").unwrap();
+        assert_eq!(
+            (
+                temperature.id,
+                temperature.name.as_str(),
+                temperature.unit.as_str()
+            ),
+            (2, "Temperatur", "C")
+        );
+        assert_eq!(ids.get("dht11_humidity").unwrap().id, 4);
+        assert!(ids.get("dht11_temperature").is_none());
+        assert_eq!(ids.key("Fuktighet"), "dht11_humidity");
+        assert_eq!(backend.posts.load(Ordering::SeqCst), 1);
     }
 }
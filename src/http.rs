@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::lru::BoundedMap;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LastPayload {
+    pub payload: String,
+    pub received: DateTime<Utc>,
+}
+
+/// The most recent raw payload received on each topic, for live inspection over HTTP
+#[derive(Debug)]
+pub struct LastPayloads {
+    enabled: bool,
+    payloads: Mutex<BoundedMap<String, LastPayload>>,
+}
+
+impl LastPayloads {
+    pub fn new(enabled: bool, capacity: usize) -> Self {
+        Self {
+            enabled,
+            payloads: Mutex::new(BoundedMap::new("last_payloads", capacity)),
+        }
+    }
+
+    pub fn record(&self, topic: &str, payload: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+        self.payloads.lock().unwrap().insert(
+            topic.to_string(),
+            LastPayload {
+                payload: String::from_utf8_lossy(payload).into_owned(),
+                received: Utc::now(),
+            },
+        );
+    }
+
+    pub fn get(&self, topic: &str) -> Option<LastPayload> {
+        self.payloads.lock().unwrap().get(topic).cloned()
+    }
+
+    pub fn all(&self) -> HashMap<String, LastPayload> {
+        self.payloads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(topic, last)| (topic.clone(), last.clone()))
+            .collect()
+    }
+}
+
+/// Liveness and readiness of the monitor. It is live once every broker has connected at least
+/// once, and ready while it is live, all devices are set up and every broker is connected.
+#[derive(Debug, Default)]
+pub struct Health {
+    brokers: AtomicUsize,
+    setup_done: AtomicBool,
+    /// Whether each broker that has connected at least once is connected right now
+    connected: Mutex<HashMap<String, bool>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn expect_brokers(&self, count: usize) {
+        self.brokers.store(count, Ordering::SeqCst);
+    }
+
+    pub fn setup_done(&self) {
+        self.setup_done.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_connected(&self, broker: &str, connected: bool) {
+        let mut brokers = self.connected.lock().unwrap();
+        match brokers.get_mut(broker) {
+            Some(state) => *state = connected,
+            None if connected => {
+                brokers.insert(broker.to_string(), true);
+            }
+            None => {}
+        }
+    }
+
+    pub fn live(&self) -> bool {
+        self.connected.lock().unwrap().len() >= self.brokers.load(Ordering::SeqCst)
+    }
+
+    pub fn ready(&self) -> bool {
+        self.setup_done.load(Ordering::SeqCst)
+            && self.live()
+            && self
+                .connected
+                .lock()
+                .unwrap()
+                .values()
+                .all(|connected| *connected)
+    }
+}
+
+/// Binds `addr` and serves the status endpoints from background threads, one per connection so
+/// a slow client never holds up a probe: `/healthz` and `/readyz` for probes, `/last` with the
+/// last payload of every topic and `/last/{topic}` with that of a single topic. Returns the
+/// address it listens on.
+pub fn serve(
+    addr: SocketAddr,
+    last_payloads: Arc<LastPayloads>,
+    health: Arc<Health>,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("failed to bind HTTP server to {}", addr))?;
+    let addr = listener.local_addr()?;
+    info!("Serving status endpoints on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("HTTP request failed: {:?}", e);
+                    continue;
+                }
+            };
+            let (last_payloads, health) = (Arc::clone(&last_payloads), Arc::clone(&health));
+            thread::spawn(move || {
+                if let Err(e) = handle_request(stream, &last_payloads, &health) {
+                    warn!("HTTP request failed: {:?}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+fn probe(ok: bool) -> (&'static str, String) {
+    match ok {
+        true => ("200 OK", r#"{"status":"ok"}"#.to_string()),
+        false => (
+            "503 Service Unavailable",
+            r#"{"status":"unavailable"}"#.to_string(),
+        ),
+    }
+}
+
+/// How long a client may take to send its request or read the response before it is dropped, so
+/// stalled clients do not pile up threads
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn handle_request(
+    mut stream: TcpStream,
+    last_payloads: &LastPayloads,
+    health: &Health,
+) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, body) = match (method, path) {
+        ("GET", "/healthz") => probe(health.live()),
+        ("GET", "/readyz") => probe(health.ready()),
+        ("GET", "/last") => ("200 OK", serde_json::to_string(&last_payloads.all())?),
+        ("GET", path) if path.starts_with("/last/") => {
+            match last_payloads.get(&path["/last/".len()..]) {
+                Some(last) => ("200 OK", serde_json::to_string(&last)?),
+                None => (
+                    "404 Not Found",
+                    r#"{"error":"no payload received"}"#.to_string(),
+                ),
+            }
+        }
+        ("GET", _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn stalled_client_does_not_block_other_requests() {
+        let addr = serve(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(LastPayloads::new(false, 1)),
+            Arc::new(Health::new()),
+        )
+        .unwrap();
+
+        // Connects without ever sending a request line
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        client.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+}
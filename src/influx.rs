@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+
+use crate::{mqtt::Measurement, retry::RetryPolicy, sink::Sink};
+
+/// Measurement name every reading is written under, device and sensor are tags
+const INFLUX_MEASUREMENT: &str = "sensor_monitor";
+
+/// Escapes commas, equals signs and spaces in a tag value as line protocol requires
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Formats the measurement as one line of InfluxDB line protocol, stamped with its timestamp if it
+/// has one and by the server otherwise
+pub fn line_protocol(measurement: &Measurement) -> String {
+    let mut line = format!(
+        "{},device={},sensor={}",
+        INFLUX_MEASUREMENT,
+        escape_tag(&measurement.device_name),
+        escape_tag(&measurement.sensor_name)
+    );
+    if let Some(unit) = &measurement.unit {
+        line.push_str(&format!(",unit={}", escape_tag(unit)));
+    }
+    if let Some(instance) = &measurement.instance {
+        line.push_str(&format!(",instance={}", escape_tag(instance)));
+    }
+    // As f32 it prints at the precision it was read with, 21.7 rather than 21.700000762939453
+    line.push_str(&format!(" value={}", measurement.measurement as f32));
+    if let Some(nanos) = measurement
+        .timestamp
+        .and_then(|timestamp| timestamp.timestamp_nanos_opt())
+    {
+        line.push_str(&format!(" {}", nanos));
+    }
+    line
+}
+
+/// Writes measurements to an InfluxDB bucket through the v2 write API
+pub struct InfluxSink {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl InfluxSink {
+    pub fn new(
+        client: reqwest::Client,
+        influx_url: &str,
+        bucket: &str,
+        org: Option<&str>,
+        token: Option<String>,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let mut url = reqwest::Url::parse(&format!("{}/api/v2/write", influx_url))?;
+        url.query_pairs_mut().append_pair("bucket", bucket);
+        if let Some(org) = org {
+            url.query_pairs_mut().append_pair("org", org);
+        }
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            token,
+            retry,
+        })
+    }
+
+    async fn write_lines(&self, body: String) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Token {}", token));
+        }
+        let response = self.retry.send(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("InfluxDB answered {} {}", status, body));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        self.write_lines(line_protocol(measurement)).await
+    }
+
+    /// Writes all measurements in a single request, one line each
+    async fn write_measurements(&self, measurements: &[Measurement]) -> Result<()> {
+        if measurements.is_empty() {
+            return Ok(());
+        }
+        let lines: Vec<String> = measurements.iter().map(line_protocol).collect();
+        self.write_lines(lines.join("\n")).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::MockServer;
+
+    fn dht11_temperature() -> Measurement {
+        Measurement {
+            device: 1,
+            sensor: 2,
+            measurement: 21.7,
+            unit: Some("C".to_string()),
+            instance: None,
+            timestamp: Some("2026-10-15T10:00:00Z".parse().unwrap()),
+            device_name: "stue".to_string(),
+            sensor_name: "dht11_temperature".to_string(),
+            hemrs_url: None,
+        }
+    }
+
+    #[test]
+    fn formats_a_dht11_reading_as_line_protocol() {
+        let mut measurement = dht11_temperature();
+        assert_eq!(
+            line_protocol(&measurement),
+            "sensor_monitor,device=stue,sensor=dht11_temperature,unit=C value=21.7 1792058400000000000"
+        );
+        measurement.timestamp = None;
+        measurement.instance = Some("1".to_string());
+        assert_eq!(
+            line_protocol(&measurement),
+            "sensor_monitor,device=stue,sensor=dht11_temperature,unit=C,instance=1 value=21.7"
+        );
+    }
+
+    #[test]
+    fn escapes_tag_values() {
+        assert_eq!(
+            escape_tag(r"living room,east=1\2"),
+            r"living\ room\,east\=1\\2"
+        );
+        let mut measurement = dht11_temperature();
+        measurement.device_name = "living room".to_string();
+        measurement.timestamp = None;
+        assert!(line_protocol(&measurement)
+            .starts_with(r"sensor_monitor,device=living\ room,sensor=dht11_temperature,"));
+    }
+
+    #[tokio::test]
+    async fn writes_a_batch_as_one_line_each() {
+        let server = MockServer::start(&[204]);
+        let sink = InfluxSink::new(
+            reqwest::Client::new(),
+            &server.url,
+            "sensors",
+            Some("home"),
+            None,
+            RetryPolicy::default(),
+        )
+        .unwrap();
+        let mut humidity = dht11_temperature();
+        humidity.sensor_name = "dht11_humidity".to_string();
+        humidity.measurement = 45.0;
+        humidity.unit = None;
+        sink.write_measurements(&[dht11_temperature(), humidity.clone()])
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(server.bodies()[0].clone()).unwrap(),
+            format!(
+                "{}\n{}",
+                line_protocol(&dht11_temperature()),
+                line_protocol(&humidity)
+            )
+        );
+    }
+}
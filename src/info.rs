@@ -0,0 +1,254 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use metrics::gauge;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    hem::HemrsUrls, labels::Labels, lru::BoundedMap, mqtt::DeviceContext, retry::RetryPolicy,
+    sink::IdMode,
+};
+
+/// Firmware and network details a Tasmota device reports on its INFO topics
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub firmware: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+struct InfoFields {
+    version: Option<String>,
+    #[serde(rename = "IPAddress")]
+    ip_address: Option<String>,
+}
+
+/// Newer firmware nests the fields under `Info1` and `Info2`, older firmware sends them flat
+#[derive(Deserialize, Debug)]
+struct InfoPayload {
+    #[serde(rename = "Info1")]
+    info1: Option<InfoFields>,
+    #[serde(rename = "Info2")]
+    info2: Option<InfoFields>,
+    #[serde(flatten)]
+    fields: InfoFields,
+}
+
+pub fn parse_device_info(payload: &[u8]) -> Result<DeviceInfo> {
+    let payload: InfoPayload = serde_json::from_slice(payload)?;
+    let mut info = DeviceInfo::default();
+    for fields in [payload.info1, payload.info2, Some(payload.fields)]
+        .into_iter()
+        .flatten()
+    {
+        info.firmware = info.firmware.or(fields.version);
+        info.ip_address = info.ip_address.or(fields.ip_address);
+    }
+    Ok(info)
+}
+
+/// Latest known info per device, exported as the `sensor_device_info` gauge with the details as
+/// labels. INFO1 and INFO2 arrive as separate messages, so new details are merged into the known.
+#[derive(Debug)]
+pub struct DeviceInfos {
+    infos: Mutex<BoundedMap<String, DeviceInfo>>,
+}
+
+impl DeviceInfos {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            infos: Mutex::new(BoundedMap::new("device_info", capacity)),
+        }
+    }
+
+    fn set_gauge(labels: &Labels, device: &str, info: &DeviceInfo, value: f64) {
+        gauge!(
+            "sensor_device_info",
+            "device" => labels.value(device),
+            "firmware" => info.firmware.clone().unwrap_or_default(),
+            "ip_address" => info.ip_address.clone().unwrap_or_default()
+        )
+        .set(value);
+    }
+
+    /// Merges `update` into the known info of the device, returning the merged info if it changed
+    pub fn update(
+        &self,
+        labels: &Labels,
+        device: &DeviceContext,
+        update: DeviceInfo,
+    ) -> Option<DeviceInfo> {
+        let mut infos = self.infos.lock().unwrap();
+        let known = infos.get_or_insert_with(device.name.clone(), DeviceInfo::default);
+        let merged = DeviceInfo {
+            firmware: update.firmware.or(known.firmware.clone()),
+            ip_address: update.ip_address.or(known.ip_address.clone()),
+        };
+        if merged == *known {
+            return None;
+        }
+        info!(
+            "{} runs firmware {} at {}",
+            device.name,
+            merged.firmware.as_deref().unwrap_or("unknown"),
+            merged.ip_address.as_deref().unwrap_or("unknown")
+        );
+        if *known != DeviceInfo::default() {
+            Self::set_gauge(labels, &device.name, known, 0.0);
+        }
+        Self::set_gauge(labels, &device.name, &merged, 1.0);
+        *known = merged.clone();
+        Some(merged)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceMetadataBody<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_name: Option<&'a str>,
+    firmware: Option<&'a str>,
+    ip_address: Option<&'a str>,
+}
+
+/// Stores device info in hemrs, posting `{"device": 1, "firmware": .., "ip_address": ..}` to the
+/// device metadata endpoint, with `device_name` instead of `device` when identifying by name
+#[derive(Debug)]
+pub struct DeviceMetadata {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    urls: HemrsUrls,
+    id_mode: IdMode,
+}
+
+impl DeviceMetadata {
+    pub fn new(
+        client: reqwest::Client,
+        retry: RetryPolicy,
+        urls: HemrsUrls,
+        id_mode: IdMode,
+    ) -> Self {
+        Self {
+            client,
+            retry,
+            urls,
+            id_mode,
+        }
+    }
+
+    pub async fn store(&self, device: &DeviceContext, info: &DeviceInfo) -> Result<()> {
+        let urls = device.hemrs.as_ref().unwrap_or(&self.urls);
+        let Some(url) = &urls.device_metadata else {
+            return Ok(());
+        };
+        let body = DeviceMetadataBody {
+            device: (self.id_mode == IdMode::Numeric).then_some(device.id),
+            device_name: (self.id_mode == IdMode::Name).then_some(device.name.as_str()),
+            firmware: info.firmware.as_deref(),
+            ip_address: info.ip_address.as_deref(),
+        };
+        let storing = || format!("storing the info of {} at {}", device.name, url);
+        let response = self
+            .retry
+            .send(self.client.post(url).json(&body))
+            .await
+            .with_context(storing)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("hemrs answered {}", status)).with_context(storing);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::testing::fixtures::{topic_to_device, MockServer};
+
+    fn device() -> DeviceContext {
+        topic_to_device("tele/stue/SENSOR", 1, "stue")
+            .into_values()
+            .next()
+            .unwrap()
+    }
+
+    fn metadata(server: &MockServer, id_mode: IdMode) -> DeviceMetadata {
+        let urls = HemrsUrls::new(
+            &server.url,
+            "/api/measurements",
+            "/api/sensors",
+            "/api/devices",
+        )
+        .with_device_metadata(&server.url, "/api/devices/metadata");
+        DeviceMetadata::new(
+            reqwest::Client::new(),
+            RetryPolicy::new(0, Duration::from_millis(1)),
+            urls,
+            id_mode,
+        )
+    }
+
+    #[test]
+    fn merges_info_sent_on_separate_topics() {
+        let (infos, labels, device) = (DeviceInfos::new(10), Labels::new(false), device());
+        let firmware = parse_device_info(br#"{"Info1":{"Version":"14.2.0"}}"#).unwrap();
+        let address = parse_device_info(br#"{"Info2":{"IPAddress":"192.168.1.23"}}"#).unwrap();
+
+        assert!(infos.update(&labels, &device, firmware.clone()).is_some());
+        assert_eq!(
+            infos.update(&labels, &device, address),
+            Some(DeviceInfo {
+                firmware: Some("14.2.0".to_string()),
+                ip_address: Some("192.168.1.23".to_string()),
+            })
+        );
+        assert_eq!(infos.update(&labels, &device, firmware), None);
+    }
+
+    #[tokio::test]
+    async fn stores_device_info_in_hemrs() {
+        let info = DeviceInfo {
+            firmware: Some("14.2.0".to_string()),
+            ip_address: Some("192.168.1.23".to_string()),
+        };
+        let server = MockServer::start(&[200]);
+        metadata(&server, IdMode::Numeric)
+            .store(&device(), &info)
+            .await
+            .unwrap();
+        metadata(&server, IdMode::Name)
+            .store(&device(), &info)
+            .await
+            .unwrap();
+
+        let bodies: Vec<Value> = server
+            .bodies()
+            .iter()
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                json!({"device": 1, "firmware": "14.2.0", "ip_address": "192.168.1.23"}),
+                json!({"device_name": "stue", "firmware": "14.2.0", "ip_address": "192.168.1.23"}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_when_hemrs_rejects_device_info() {
+        let server = MockServer::start(&[404]);
+        let result = metadata(&server, IdMode::Numeric)
+            .store(&device(), &DeviceInfo::default())
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use kafka::producer::{Producer, Record, RequiredAcks};
+use metrics::counter;
+use tracing::warn;
+
+use crate::{
+    mqtt::Measurement,
+    retry::RetryPolicy,
+    sink::{to_json, IdMode, Sink},
+};
+
+/// Produces every measurement as a JSON message keyed by device name
+pub struct KafkaSink {
+    producer: Arc<Mutex<Producer>>,
+    topic: String,
+    id_mode: IdMode,
+    retry: RetryPolicy,
+}
+
+impl KafkaSink {
+    pub fn new(
+        brokers: Vec<String>,
+        topic: &str,
+        id_mode: IdMode,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let producer = Producer::from_hosts(brokers)
+            .with_ack_timeout(Duration::from_secs(5))
+            .with_required_acks(RequiredAcks::One)
+            .create()?;
+        Ok(Self {
+            producer: Arc::new(Mutex::new(producer)),
+            topic: topic.to_string(),
+            id_mode,
+            retry,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        let value = to_json(measurement, self.id_mode)?;
+        let mut attempt = 0;
+        loop {
+            let (producer, topic, key, value) = (
+                Arc::clone(&self.producer),
+                self.topic.clone(),
+                measurement.device_name.clone(),
+                value.clone(),
+            );
+            // The producer blocks until the broker acknowledges
+            let result = tokio::task::spawn_blocking(move || {
+                let record = Record::from_key_value(&topic, key.as_str(), value);
+                producer.lock().unwrap().send(&record)
+            })
+            .await?;
+            let Err(e) = result else {
+                return Ok(());
+            };
+            warn!("Producing to {} failed: {:?}", self.topic, e);
+            counter!("kafka_produce_errors_total").increment(1);
+            if attempt >= self.retry.retries {
+                return Err(e.into());
+            }
+            attempt += 1;
+            warn!(
+                "Retrying produce to {}, retry {} of {} in {:?}",
+                self.topic, attempt, self.retry.retries, self.retry.delay
+            );
+            counter!("kafka_retries_total").increment(1);
+            tokio::time::sleep(self.retry.delay).await;
+        }
+    }
+}
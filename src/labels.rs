@@ -0,0 +1,38 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use tracing::info;
+
+/// Turns device names and topics into metric label values. When enabled, every character other
+/// than ASCII letters, digits and `_-.:/` is replaced by `_`, so `Stue & kjøkken` becomes
+/// `Stue___kj_kken`.
+#[derive(Debug, Default)]
+pub struct Labels {
+    sanitize: bool,
+    logged: Mutex<HashSet<String>>,
+}
+
+impl Labels {
+    pub fn new(sanitize: bool) -> Self {
+        Self {
+            sanitize,
+            logged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn value(&self, raw: &str) -> String {
+        if !self.sanitize {
+            return raw.to_string();
+        }
+        let sanitized: String = raw
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | ':' | '/' => c,
+                _ => '_',
+            })
+            .collect();
+        if sanitized != raw && self.logged.lock().unwrap().insert(raw.to_string()) {
+            info!("Using metric label {:?} for {:?}", sanitized, raw);
+        }
+        sanitized
+    }
+}
@@ -0,0 +1,31 @@
+pub mod autoregister;
+pub mod bench;
+pub mod buffer;
+pub mod config;
+pub mod deadletter;
+pub mod filter;
+pub mod hem;
+pub mod http;
+pub mod influx;
+pub mod info;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod labels;
+pub mod lru;
+pub mod monitor;
+pub mod mqtt;
+pub mod republish;
+pub mod retry;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "serial")]
+pub mod serial;
+pub mod sink;
+pub mod smoothing;
+pub mod spool;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod validate;
@@ -0,0 +1,152 @@
+use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+
+use metrics::{counter, gauge};
+
+/// Default for how many keys each per device, sensor or topic state map holds
+pub const DEFAULT_STATE_MAP_MAX: usize = 10_000;
+
+/// Map holding at most `capacity` keys. Inserting a new key into a full map evicts the least
+/// recently used key, where both reads and writes count as use. The size is exported as
+/// `state_map_entries` and evictions as `state_map_evictions_total`, labeled with the map name.
+#[derive(Debug)]
+pub struct BoundedMap<K, V> {
+    name: &'static str,
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> BoundedMap<K, V> {
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn touch(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn update_gauge(&self) {
+        gauge!("state_map_entries", "map" => self.name).set(self.entries.len() as f64);
+    }
+
+    fn evict_if_full(&mut self) {
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            self.entries.remove(&oldest);
+            counter!("state_map_evictions_total", "map" => self.name).increment(1);
+        }
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let tick = self.touch();
+        self.entries.get_mut(key).map(|(value, used)| {
+            *used = tick;
+            value
+        })
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let tick = self.touch();
+        if !self.entries.contains_key(&key) {
+            self.evict_if_full();
+        }
+        self.entries.insert(key, (value, tick));
+        self.update_gauge();
+    }
+
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            self.insert(key.clone(), default());
+        }
+        self.get_mut(&key).expect("key was just inserted")
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let removed = self.entries.remove(key).map(|(value, _)| value);
+        self.update_gauge();
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(value, _)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_key_when_full() {
+        let mut map = BoundedMap::new("test", 2);
+        map.insert("a", 1);
+        map.insert("b", 2);
+        // Reading a makes b the least recently used
+        assert_eq!(map.get("a"), Some(&1));
+        map.insert("c", 3);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.get("a"), Some(&1));
+
+        // Overwriting a key in a full map evicts nothing
+        map.insert("c", 4);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("c"), Some(&4));
+
+        *map.get_or_insert_with("d", || 5) += 1;
+        assert_eq!(map.get("a"), None);
+        assert_eq!(map.get("d"), Some(&6));
+    }
+
+    #[test]
+    fn holds_at_least_one_key() {
+        let mut map = BoundedMap::new("test", 0);
+        map.insert("a", 1);
+        assert_eq!(map.get("a"), Some(&1));
+        map.insert("b", 2);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.remove("b"), Some(2));
+        assert!(map.is_empty());
+    }
+}
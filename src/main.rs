@@ -1,22 +1,46 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
 use metrics_exporter_prometheus::PrometheusBuilder;
-use rumqttc::{Client, MqttOptions, QoS};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT};
+use serde::Serialize;
 use structopt::StructOpt;
-use tracing::{info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use crate::{
-    hem::{setup_device, setup_sensors},
-    mqtt::handle_connection,
+#[cfg(feature = "schema")]
+use sensor_monitor::schema::PayloadSchema;
+use sensor_monitor::{
+    bench::run_bench,
+    buffer::{self, BufferedSink, MeasurementBuffer},
+    config::{
+        parse_config, redact, BrokerConfig, Config, ConfigFormat, MqttTransport, PayloadEncoding,
+        TopicConfig,
+    },
+    hem::HemrsUrls,
+    http,
+    influx::InfluxSink,
+    monitor::Monitor,
+    mqtt::ReconnectAttemptsExceeded,
+    retry::RetryPolicy,
+    sink::{Backend, BatchFormat, DryRunSink, HemrsSink, IdMode, Sink},
+    spool::{spawn_flusher, Spool, SpoolSink},
+    validate::check_config,
 };
 
-mod hem;
-mod mqtt;
+/// Exit code when a broker could not be reconnected to within --max-reconnect-attempts
+const EXIT_RECONNECT_ATTEMPTS_EXCEEDED: i32 = 3;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
 enum LogLevel {
     Trace,
     Debug,
@@ -52,87 +76,915 @@ impl From<LogLevel> for Level {
     }
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Serialize, Debug)]
+pub enum Command {
+    /// Process readings until stopped, what runs without a subcommand
+    Run,
+    /// Post synthetic measurements to hemrs and report its throughput
+    Bench {
+        /// Number of measurements to post
+        #[structopt(long, default_value = "1000")]
+        count: usize,
+
+        /// Number of measurements posted at the same time
+        #[structopt(long, default_value = "4")]
+        concurrency: usize,
+
+        /// Delete the bench device from hemrs afterwards
+        #[structopt(long)]
+        cleanup: bool,
+    },
+    /// Check the config file and report what it would set up, without connecting to anything
+    Validate {
+        /// Config file to check, the top level --config by default
+        #[structopt(short, long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Serialize, Debug)]
 pub struct Opts {
     #[structopt(short, long, env, default_value = "thor.lan")]
     pub mqtt_host: String,
 
+    /// Comma separated MQTT brokers tried in order, failing over to the next when one cannot be
+    /// reached, replaces --mqtt-host
+    #[structopt(long, env, use_delimiter = true)]
+    pub mqtt_hosts: Vec<String>,
+
+    /// MQTT broker port, defaults to the standard port of the transport
+    #[structopt(long, env)]
+    pub mqtt_port: Option<u16>,
+
+    /// How to connect to the MQTT broker, tcp, tls, ws or wss
+    #[structopt(long, env, default_value = "tcp")]
+    pub mqtt_transport: MqttTransport,
+
+    /// Connect to the MQTT broker over TLS, the same as --mqtt-transport tls
+    #[structopt(long, env, parse(try_from_str))]
+    pub mqtt_tls: Option<bool>,
+
+    /// PEM encoded CA certificate to verify the MQTT broker with instead of the system root certificates
+    #[structopt(long, env)]
+    pub mqtt_ca_cert: Option<PathBuf>,
+
+    /// Username to authenticate to the MQTT broker with, requires --mqtt-password
+    #[structopt(long, env)]
+    pub mqtt_username: Option<String>,
+
+    /// Password to authenticate to the MQTT broker with, requires --mqtt-username
+    #[structopt(long, env, hide_env_values = true)]
+    #[serde(serialize_with = "redact")]
+    pub mqtt_password: Option<String>,
+
+    /// Path of the MQTT endpoint for the ws and wss transports, defaults to /mqtt
+    #[structopt(long, env)]
+    pub mqtt_ws_path: Option<String>,
+
     #[structopt(short, long, env, default_value = "tele/vinterhage/SENSOR")]
     pub topic: String,
 
     #[structopt(short, long, env, default_value = "http://desktop:65534")]
     pub hemrs_base_url: String,
 
+    /// Path of the hemrs measurements endpoint, joined to the base url
+    #[structopt(long, env, default_value = "/api/measurements")]
+    pub measurements_path: String,
+
+    /// Path of the hemrs sensors endpoint, joined to the base url
+    #[structopt(long, env, default_value = "/api/sensors")]
+    pub sensors_path: String,
+
+    /// Path of the hemrs devices endpoint, joined to the base url
+    #[structopt(long, env, default_value = "/api/devices")]
+    pub devices_path: String,
+
+    /// Path of a hemrs endpoint the firmware version and IP address read from INFO topics are
+    /// posted to, e.g. /api/devices/metadata, they are only exported as metrics when left out
+    #[structopt(long, env)]
+    pub device_metadata_path: Option<String>,
+
     #[structopt(short, long, env, default_value = "esp32_stue")]
     pub device_name: String,
 
     #[structopt(short = "l", long, env, default_value = "Stue")]
     pub device_location: String,
 
-    #[structopt(short, long, default_value = "info")]
+    /// hemrs API version to request through the Accept header, e.g. v2
+    #[structopt(long, env)]
+    pub hemrs_api_version: Option<String>,
+
+    /// Post measurements to hemrs nested under this key instead of as a bare object
+    #[structopt(long, env)]
+    pub body_wrapper_key: Option<String>,
+
+    /// Where to store measurements, hemrs, kafka, sqlite or influx
+    #[structopt(long, env, default_value = "hemrs")]
+    pub backend: Backend,
+
+    /// Comma separated Kafka bootstrap brokers for the kafka backend
+    #[structopt(long, env, use_delimiter = true)]
+    pub kafka_brokers: Vec<String>,
+
+    /// Kafka topic to produce measurements to
+    #[structopt(long, env, default_value = "sensor_measurements")]
+    pub kafka_topic: String,
+
+    /// InfluxDB the influx backend writes to
+    #[structopt(long, env, default_value = "http://localhost:8086")]
+    pub influx_url: String,
+
+    /// InfluxDB bucket to write measurements to, required for the influx backend
+    #[structopt(long, env)]
+    pub influx_bucket: Option<String>,
+
+    /// InfluxDB organization owning the bucket
+    #[structopt(long, env)]
+    pub influx_org: Option<String>,
+
+    /// InfluxDB API token
+    #[structopt(long, env, hide_env_values = true)]
+    #[serde(serialize_with = "redact")]
+    pub influx_token: Option<String>,
+
+    /// SQLite database the sqlite backend stores measurements in until they are uploaded
+    #[structopt(long, env, default_value = "sensor_monitor.db")]
+    pub sqlite_path: PathBuf,
+
+    /// Seconds between uploads of measurements stored by the sqlite backend to hemrs
+    #[structopt(long, env, default_value = "30")]
+    pub sqlite_sync_interval: u64,
+
+    /// Directory to spool measurements to when they cannot be stored, they are stored later
+    #[structopt(long, env)]
+    pub spool_dir: Option<PathBuf>,
+
+    /// Seconds between attempts to store the spooled measurements
+    #[structopt(long, env, default_value = "30")]
+    pub spool_flush_interval: u64,
+
+    /// Buffer measurements and store them in one batch every this many seconds
+    #[structopt(long, env)]
+    pub flush_interval_secs: Option<u64>,
+
+    /// Store the buffered measurements early once this many are buffered
+    #[structopt(long, env, default_value = "100")]
+    pub flush_max: usize,
+
+    /// Log the status and body of every hemrs response at debug level
+    #[structopt(long, env, parse(try_from_str))]
+    pub log_post_responses: Option<bool>,
+
+    /// Gzip the body of batch posts to hemrs and send it with Content-Encoding: gzip
+    #[structopt(long, env, parse(try_from_str))]
+    pub compress_requests: Option<bool>,
+
+    /// Times a hemrs request failing with a connection error or a 5xx response is retried
+    #[structopt(long, env, default_value = "3")]
+    pub hemrs_retries: u32,
+
+    /// Milliseconds to wait before retrying a failed hemrs request
+    #[structopt(long, env, default_value = "500")]
+    pub hemrs_retry_delay_ms: u64,
+
+    /// Max idle connections kept per host in the hemrs connection pool
+    #[structopt(long, env)]
+    pub http_pool_max_idle: Option<usize>,
+
+    /// Seconds an idle hemrs connection is kept in the pool before being closed
+    #[structopt(long, env)]
+    pub http_pool_idle_timeout: Option<u64>,
+
+    /// Seconds to wait for connecting to hemrs and for each hemrs request to complete
+    #[structopt(long, env, default_value = "10")]
+    pub hemrs_timeout_secs: u64,
+
+    /// Identify devices and sensors towards hemrs by numeric id or by name, name skips the id lookup
+    #[structopt(long, env, default_value = "numeric")]
+    pub id_mode: IdMode,
+
+    /// Compute the dew point when the firmware does not report it
+    #[structopt(long, env, parse(try_from_str))]
+    pub compute_dewpoint: Option<bool>,
+
+    /// Warn when a payload's sensors differ from the expected_sensors of its topic
+    #[structopt(long, env, parse(try_from_str))]
+    pub validate_expected_sensors: Option<bool>,
+
+    /// Drop temperatures outside -40..85 °C, humidities and other `%` readings outside 0..100 and
+    /// pressures outside 300..1100 hPa
+    #[structopt(long, env, parse(try_from_str))]
+    pub validate_ranges: Option<bool>,
+
+    /// Compute and store the DHT11 heat index and absolute humidity
+    #[structopt(long, env, parse(try_from_str))]
+    pub compute_comfort_metrics: Option<bool>,
+
+    /// Post all measurements from one message to hemrs as a single JSON array
+    #[structopt(long, env, parse(try_from_str))]
+    pub batch_posts: Option<bool>,
+
+    /// Log the measurements that would be posted without registering devices or posting anything
+    #[structopt(long, env, parse(try_from_str))]
+    pub dry_run: Option<bool>,
+
+    /// Round stored values to this many decimal places, ties to even
+    #[structopt(long, env)]
+    pub round_decimals: Option<u32>,
+
+    /// Exit after the first sensor message is stored, with an error if it cannot be processed
+    #[structopt(long, env, parse(try_from_str))]
+    pub once: Option<bool>,
+
+    /// Skip retained sensor messages instead of storing their possibly stale readings
+    #[structopt(long, env, parse(try_from_str))]
+    pub ignore_retained: Option<bool>,
+
+    /// Include the unit the sensor is registered with in every posted measurement
+    #[structopt(long, env, parse(try_from_str))]
+    pub send_units: Option<bool>,
+
+    /// Include the Time of the message in every posted measurement, for backends accepting one
+    #[structopt(long, env, parse(try_from_str))]
+    pub send_timestamps: Option<bool>,
+
+    /// Accept payloads without a Time field, storing them at the time they are received
+    #[structopt(long, env, parse(try_from_str))]
+    pub allow_missing_time: Option<bool>,
+
+    /// How --batch-posts frames the measurements, a JSON array (json) or one object per line (ndjson)
+    #[structopt(long, env, default_value = "json")]
+    pub batch_format: BatchFormat,
+
+    /// Drop readings older than the last stored reading of the same sensor
+    #[structopt(long, env, parse(try_from_str))]
+    pub enforce_monotonic_time: Option<bool>,
+
+    /// Suppress a sensor after this many rejected readings in a row, 0 turns it off
+    #[structopt(long, env)]
+    pub deadtime_after: Option<u32>,
+
+    /// Seconds a sensor stays suppressed after too many rejected readings
+    #[structopt(long, env)]
+    pub deadtime_secs: Option<u64>,
+
+    /// Drop messages from a device arriving sooner than this many seconds after the last stored one
+    #[structopt(long, env)]
+    pub min_interval_secs: Option<u64>,
+
+    /// Skip devices that fail to set up in hemrs instead of exiting, at least one must succeed
+    #[structopt(long, env, parse(try_from_str))]
+    pub continue_on_setup_error: Option<bool>,
+
+    /// Identifies this monitor when tagging measurements, defaults to the MQTT client id
+    #[structopt(long, env)]
+    pub instance_id: Option<String>,
+
+    /// Attach the instance id to every measurement posted to hemrs
+    #[structopt(long, env, parse(try_from_str))]
+    pub tag_instance: Option<bool>,
+
+    /// How many topics to subscribe to per SUBSCRIBE packet
+    #[structopt(long, env)]
+    pub subscribe_batch_size: Option<usize>,
+
+    /// Topic on which `pause` and `resume` messages stop and restart storing readings
+    #[structopt(long, env)]
+    pub control_topic: Option<String>,
+
+    /// Retained topic announcing whether the monitor is online, sensor_monitor/<hostname>/status by default
+    #[structopt(long, env)]
+    pub status_topic: Option<String>,
+
+    /// Append payloads that fail to parse to this file as JSON lines and keep going
+    #[structopt(long, env)]
+    pub dead_letter_file: Option<PathBuf>,
+
+    /// JSON Schema to validate sensor payloads against before parsing them
+    #[structopt(long, env)]
+    pub payload_schema: Option<PathBuf>,
+
+    /// Exit with code 3 after this many failed reconnects in a row to a broker, unlimited by default
+    #[structopt(long, env)]
+    pub max_reconnect_attempts: Option<u32>,
+
+    /// Milliseconds before the first reconnect attempt, doubling with every failed attempt
+    #[structopt(long, env)]
+    pub reconnect_delay_ms: Option<u64>,
+
+    /// Longest delay between reconnect attempts in milliseconds
+    #[structopt(long, env)]
+    pub reconnect_max_delay_ms: Option<u64>,
+
+    /// Address to serve the HTTP status endpoints on, e.g. 127.0.0.1:8080
+    #[structopt(long, env)]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Address to serve Prometheus metrics on, 0.0.0.0:9000 when not given
+    #[structopt(long, env)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Keep the last raw payload of every topic and serve it on /last and /last/{topic}
+    #[structopt(long, env, parse(try_from_str))]
+    pub expose_last_payloads: Option<bool>,
+
+    /// Replace spaces and symbols in device names and topics used as metric labels with _
+    #[structopt(long, env, parse(try_from_str))]
+    pub sanitize_labels: Option<bool>,
+
+    /// Most keys each per device, sensor or topic state map holds before evicting the least recently used
+    #[structopt(long, env)]
+    pub state_map_max: Option<usize>,
+
+    /// Set up a device for every topic a wildcard topic matches, named by the topic's templates
+    #[structopt(long, env, parse(try_from_str))]
+    pub auto_register: Option<bool>,
+
+    /// Most devices --auto-register sets up, 100 by default
+    #[structopt(long, env)]
+    pub auto_register_max: Option<usize>,
+
+    /// Suffix of the topics wildcard topics match that are processed, /SENSOR by default, empty for all
+    #[structopt(long, env)]
+    pub sensor_topic_suffix: Option<String>,
+
+    /// Comma separated topic patterns, only configured topics matching one are subscribed to
+    #[structopt(long, env, use_delimiter = true)]
+    pub only_topics: Vec<String>,
+
+    /// Publish every stored measurement retained to the first broker, for dashboards
+    #[structopt(long, env, parse(try_from_str))]
+    pub republish: Option<bool>,
+
+    /// Topic --republish publishes on, {device} and {sensor} are replaced by their names
+    #[structopt(long, env)]
+    pub republish_topic: Option<String>,
+
+    /// TOML or YAML config with one or more broker sections, overrides the mqtt and device options
+    #[structopt(short, long, env)]
+    pub config: Option<PathBuf>,
+
+    /// Format of the config file, toml or yaml, by default taken from its extension
+    #[structopt(long, env)]
+    pub config_format: Option<ConfigFormat>,
+
+    #[structopt(long, default_value = "info")]
     log_level: LogLevel,
+
+    /// Print the options and the config they resolve to as JSON and exit, passwords redacted
+    #[structopt(long)]
+    pub show_config: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
 impl Display for Opts {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "mqtt_host: {}, topic: {}, hemrs_base_url: {}, device_name: {}, device_location: {}",
-            self.mqtt_host, self.topic, self.hemrs_base_url, self.device_name, self.device_location
+            "mqtt_host: {}, mqtt_username: {:?}, mqtt_password: {}, topic: {}, hemrs_base_url: {}, device_name: {}, device_location: {}, config: {:?}",
+            self.mqtt_host,
+            self.mqtt_username,
+            match self.mqtt_password {
+                Some(_) => "<redacted>",
+                None => "None",
+            },
+            self.topic,
+            self.hemrs_base_url,
+            self.device_name,
+            self.device_location,
+            self.config
         )
     }
 }
 
-fn main() -> Result<()> {
+/// What --show-config prints, in the order the fields are declared
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    opts: &'a Opts,
+    config: &'a Config,
+}
+
+/// The options and the config the monitor runs with, as pretty printed JSON
+fn show_config(opts: &Opts) -> Result<String> {
+    let mut config = resolve_config(opts)?;
+    // The monitor only runs the topics matching only_topics
+    config.retain_topics();
+    let effective = EffectiveConfig {
+        opts,
+        config: &config,
+    };
+    Ok(serde_json::to_string_pretty(&effective)?)
+}
+
+/// The config file, or the config built from the mqtt and device options, with the options
+/// overriding it applied
+fn resolve_config(opts: &Opts) -> Result<Config> {
+    let mut config = load_config(opts)?;
+    config.compute_dew_point = opts.compute_dewpoint.unwrap_or(config.compute_dew_point);
+    config.compute_comfort_metrics = opts
+        .compute_comfort_metrics
+        .unwrap_or(config.compute_comfort_metrics);
+    config.batch_posts = opts.batch_posts.unwrap_or(config.batch_posts);
+    config.dry_run = opts.dry_run.unwrap_or(config.dry_run);
+    config.once = opts.once.unwrap_or(config.once);
+    config.ignore_retained = opts.ignore_retained.unwrap_or(config.ignore_retained);
+    config.validate_expected_sensors = opts
+        .validate_expected_sensors
+        .unwrap_or(config.validate_expected_sensors);
+    config.validate_ranges = opts.validate_ranges.unwrap_or(config.validate_ranges);
+    config.send_units = opts.send_units.unwrap_or(config.send_units);
+    config.send_timestamps = opts.send_timestamps.unwrap_or(config.send_timestamps);
+    config.continue_on_setup_error = opts
+        .continue_on_setup_error
+        .unwrap_or(config.continue_on_setup_error);
+    config.enforce_monotonic_time = opts
+        .enforce_monotonic_time
+        .unwrap_or(config.enforce_monotonic_time);
+    config.allow_missing_time = opts.allow_missing_time.unwrap_or(config.allow_missing_time);
+    config.tag_instance = opts.tag_instance.unwrap_or(config.tag_instance);
+    config.expose_last_payloads = opts
+        .expose_last_payloads
+        .unwrap_or(config.expose_last_payloads);
+    config.sanitize_labels = opts.sanitize_labels.unwrap_or(config.sanitize_labels);
+    if config.expose_last_payloads && opts.health_addr.is_none() {
+        return Err(anyhow!("--expose-last-payloads requires --health-addr"));
+    }
+    if opts.instance_id.is_some() {
+        config.instance_id = opts.instance_id.clone();
+    }
+    if opts.deadtime_after.is_some() {
+        config.deadtime_after = opts.deadtime_after;
+    }
+    if opts.deadtime_secs.is_some() {
+        config.deadtime_secs = opts.deadtime_secs;
+    }
+    if opts.min_interval_secs.is_some() {
+        config.min_interval_secs = opts.min_interval_secs;
+    }
+    if opts.max_reconnect_attempts.is_some() {
+        config.max_reconnect_attempts = opts.max_reconnect_attempts;
+    }
+    if opts.reconnect_delay_ms.is_some() {
+        config.reconnect_delay_ms = opts.reconnect_delay_ms;
+    }
+    if opts.reconnect_max_delay_ms.is_some() {
+        config.reconnect_max_delay_ms = opts.reconnect_max_delay_ms;
+    }
+    if opts.state_map_max.is_some() {
+        config.state_map_max = opts.state_map_max;
+    }
+    config.auto_register = opts.auto_register.unwrap_or(config.auto_register);
+    if opts.auto_register_max.is_some() {
+        config.auto_register_max = opts.auto_register_max;
+    }
+    if opts.sensor_topic_suffix.is_some() {
+        config.sensor_topic_suffix = opts.sensor_topic_suffix.clone();
+    }
+    if !opts.only_topics.is_empty() {
+        config.only_topics = opts.only_topics.clone();
+    }
+    config.republish = opts.republish.unwrap_or(config.republish);
+    if opts.republish_topic.is_some() {
+        config.republish_topic = opts.republish_topic.clone();
+    }
+    if opts.control_topic.is_some() {
+        config.control_topic = opts.control_topic.clone();
+    }
+    if opts.round_decimals.is_some() {
+        config.round_decimals = opts.round_decimals;
+    }
+    if opts.status_topic.is_some() {
+        config.status_topic = opts.status_topic.clone();
+    }
+    if opts.subscribe_batch_size.is_some() {
+        config.subscribe_batch_size = opts.subscribe_batch_size;
+    }
+    if opts.dead_letter_file.is_some() {
+        config.dead_letter_file = opts.dead_letter_file.clone();
+    }
+    if opts.payload_schema.is_some() {
+        config.payload_schema = opts.payload_schema.clone();
+    }
+    Ok(config)
+}
+
+fn load_config(opts: &Opts) -> Result<Config> {
+    let transport = match opts.mqtt_tls.unwrap_or_default() {
+        true => opts.mqtt_transport.secure(),
+        false => opts.mqtt_transport,
+    };
+    let (host, failover_hosts) = match opts.mqtt_hosts.split_first() {
+        Some((host, failover_hosts)) => (host.clone(), failover_hosts.to_vec()),
+        None => (opts.mqtt_host.clone(), Vec::new()),
+    };
+    match &opts.config {
+        Some(path) => parse_config(path, opts.config_format),
+        None => Ok(Config {
+            broker: vec![BrokerConfig {
+                host,
+                failover_hosts,
+                port: opts.mqtt_port.unwrap_or(transport.default_port()),
+                transport,
+                ws_path: opts.mqtt_ws_path.clone(),
+                ca_cert: opts.mqtt_ca_cert.clone(),
+                username: opts.mqtt_username.clone(),
+                password: opts.mqtt_password.clone(),
+                topics: vec![TopicConfig {
+                    topic: opts.topic.clone(),
+                    device_name: opts.device_name.clone(),
+                    device_location: opts.device_location.clone(),
+                    qos: 0,
+                    expected_sensors: Vec::new(),
+                    info_topics: Vec::new(),
+                    offsets: HashMap::new(),
+                    payload_root: String::new(),
+                    hemrs_base_url: None,
+                    payload_encoding: PayloadEncoding::None,
+                }],
+            }],
+            ..Default::default()
+        }),
+    }
+}
+
+fn retry_policy(opts: &Opts) -> RetryPolicy {
+    RetryPolicy::new(
+        opts.hemrs_retries,
+        Duration::from_millis(opts.hemrs_retry_delay_ms),
+    )
+}
+
+fn hemrs_urls(opts: &Opts) -> HemrsUrls {
+    let urls = HemrsUrls::new(
+        &opts.hemrs_base_url,
+        &opts.measurements_path,
+        &opts.sensors_path,
+        &opts.devices_path,
+    );
+    match &opts.device_metadata_path {
+        Some(path) => urls.with_device_metadata(&opts.hemrs_base_url, path),
+        None => urls,
+    }
+}
+
+fn hemrs_sink(opts: &Opts, http_client: &reqwest::Client) -> HemrsSink {
+    HemrsSink::new(
+        http_client.clone(),
+        &hemrs_urls(opts).measurements,
+        opts.id_mode,
+        opts.body_wrapper_key.clone(),
+        opts.batch_format,
+        opts.log_post_responses.unwrap_or_default(),
+        retry_policy(opts),
+    )
+    .compress_batches(opts.compress_requests.unwrap_or_default())
+}
+
+fn build_sink(opts: &Opts, http_client: &reqwest::Client) -> Result<Box<dyn Sink>> {
+    match opts.backend {
+        Backend::Hemrs => Ok(Box::new(hemrs_sink(opts, http_client))),
+        #[cfg(feature = "kafka")]
+        Backend::Kafka => {
+            if opts.kafka_brokers.is_empty() {
+                return Err(anyhow!("the kafka backend requires --kafka-brokers"));
+            }
+            Ok(Box::new(sensor_monitor::kafka::KafkaSink::new(
+                opts.kafka_brokers.clone(),
+                &opts.kafka_topic,
+                opts.id_mode,
+                retry_policy(opts),
+            )?))
+        }
+        #[cfg(not(feature = "kafka"))]
+        Backend::Kafka => Err(anyhow!(
+            "sensor_monitor was built without the kafka feature"
+        )),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => {
+            let sink = sensor_monitor::sqlite::SqliteSink::new(&opts.sqlite_path)?;
+            sensor_monitor::sqlite::spawn_syncer(
+                &opts.sqlite_path,
+                hemrs_sink(opts, http_client),
+                Duration::from_secs(opts.sqlite_sync_interval),
+            )?;
+            Ok(Box::new(sink))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err(anyhow!(
+            "sensor_monitor was built without the sqlite feature"
+        )),
+        Backend::Influx => {
+            let Some(bucket) = &opts.influx_bucket else {
+                return Err(anyhow!("the influx backend requires --influx-bucket"));
+            };
+            Ok(Box::new(InfluxSink::new(
+                http_client.clone(),
+                &opts.influx_url,
+                bucket,
+                opts.influx_org.as_deref(),
+                opts.influx_token.clone(),
+                retry_policy(opts),
+            )?))
+        }
+    }
+}
+
+/// Wraps `sink` so failed writes are spooled to `dir`, storing what is already spooled first
+async fn spool_sink(dir: &Path, sink: Box<dyn Sink>, interval: Duration) -> Result<Box<dyn Sink>> {
+    let spool = Arc::new(Spool::open(dir)?);
+    let inner: Arc<dyn Sink> = Arc::from(sink);
+    match spool.flush(inner.as_ref()).await {
+        Ok(0) => {}
+        Ok(stored) => info!("Stored {} spooled measurements", stored),
+        Err(e) => warn!("Flushing the spool failed: {:?}", e),
+    }
+    spawn_flusher(Arc::clone(&spool), Arc::clone(&inner), interval);
+    Ok(Box::new(SpoolSink::new(inner, spool)))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
     let opts = Opts::from_args();
-    let level: Level = opts.log_level.into();
+    let level: Level = opts.log_level.clone().into();
     let subscriber = FmtSubscriber::builder()
         .with_max_level(level)
         .json()
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
-    let _metrics_handler = PrometheusBuilder::new()
+
+    if let Some(Command::Validate { config }) = &opts.command {
+        let path = config
+            .as_ref()
+            .or(opts.config.as_ref())
+            .ok_or_else(|| anyhow!("validate needs a config file, pass --config"))?;
+        let config = parse_config(path, opts.config_format)?;
+        let summary = check_config(&config)?;
+        println!("{} is valid", path.display());
+        println!("{}", summary);
+        return Ok(());
+    }
+    if opts.show_config {
+        println!("{}", show_config(&opts)?);
+        return Ok(());
+    }
+    let mut prometheus = PrometheusBuilder::new();
+    if let Some(addr) = opts.metrics_addr {
+        prometheus = prometheus.with_http_listener(addr);
+    }
+    prometheus
         .install()
-        .expect("failed to install recorder/exporter");
+        .context("failed to install recorder/exporter")?;
+
+    let timeout = Duration::from_secs(opts.hemrs_timeout_secs);
+    let mut client_builder = reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout);
+    if let Some(version) = &opts.hemrs_api_version {
+        let accept = HeaderValue::from_str(&format!("application/vnd.hemrs.{}+json", version))?;
+        client_builder = client_builder.default_headers(HeaderMap::from_iter([(ACCEPT, accept)]));
+    }
+    if let Some(max_idle) = opts.http_pool_max_idle {
+        client_builder = client_builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = opts.http_pool_idle_timeout {
+        client_builder = client_builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    let http_client = client_builder.build()?;
+
+    if let Some(Command::Bench {
+        count,
+        concurrency,
+        cleanup,
+    }) = opts.command
+    {
+        let sink = HemrsSink::new(
+            http_client.clone(),
+            &hemrs_urls(&opts).measurements,
+            opts.id_mode,
+            opts.body_wrapper_key.clone(),
+            opts.batch_format,
+            opts.log_post_responses.unwrap_or_default(),
+            RetryPolicy::default(),
+        );
+        let report = run_bench(
+            &http_client,
+            &hemrs_urls(&opts),
+            &sink,
+            opts.id_mode,
+            count,
+            concurrency,
+            cleanup,
+        )
+        .await?;
+        println!("{}", report);
+        return Ok(());
+    }
+
+    let config = resolve_config(&opts)?;
+    #[cfg(feature = "schema")]
+    let payload_schema = config
+        .payload_schema
+        .as_deref()
+        .map(PayloadSchema::load)
+        .transpose()?;
+    #[cfg(not(feature = "schema"))]
+    if config.payload_schema.is_some() {
+        return Err(anyhow!(
+            "--payload-schema requires sensor_monitor to be built with the schema feature"
+        ));
+    }
+    let sink: Box<dyn Sink> = if config.dry_run {
+        Box::new(DryRunSink::new(opts.id_mode))
+    } else if let Some(dir) = &opts.spool_dir {
+        spool_sink(
+            dir,
+            build_sink(&opts, &http_client)?,
+            Duration::from_secs(opts.spool_flush_interval),
+        )
+        .await?
+    } else {
+        build_sink(&opts, &http_client)?
+    };
+    let mut buffered = None;
+    let sink = match opts.flush_interval_secs {
+        Some(secs) => {
+            let inner: Arc<dyn Sink> = Arc::from(sink);
+            let buffer = Arc::new(MeasurementBuffer::new(opts.flush_max));
+            buffer::spawn_flusher(
+                Arc::clone(&buffer),
+                Arc::clone(&inner),
+                Duration::from_secs(secs),
+            );
+            buffered = Some((Arc::clone(&buffer), Arc::clone(&inner)));
+            Box::new(BufferedSink::new(inner, buffer))
+        }
+        None => sink,
+    };
+    let mut monitor = Monitor::new(
+        config,
+        http_client,
+        hemrs_urls(&opts),
+        opts.id_mode,
+        retry_policy(&opts),
+        sink,
+    );
+    if let Some(path) = &opts.config {
+        monitor = monitor.reload_from(path.clone(), opts.config_format);
+    }
+    #[cfg(feature = "schema")]
+    if let Some(schema) = payload_schema {
+        monitor = monitor.payload_schema(schema);
+    }
+    if let Some(addr) = opts.health_addr {
+        http::serve(addr, monitor.last_payloads(), monitor.health())?;
+    }
+    let result = monitor.run(None).await;
+    if let Some((buffer, sink)) = buffered {
+        match buffer.flush(sink.as_ref()).await {
+            Ok(0) => {}
+            Ok(stored) => info!("Stored {} buffered measurements", stored),
+            Err(e) => error!("Storing the buffered measurements failed: {:#}", e),
+        }
+    }
+    info!("{}", monitor.stats().summary());
+    if let Err(e) = &result {
+        if e.downcast_ref::<ReconnectAttemptsExceeded>().is_some() {
+            error!("{:#}", e);
+            std::process::exit(EXIT_RECONNECT_ATTEMPTS_EXCEEDED);
+        }
+    }
+    result
+}
 
-    let http_client = reqwest::blocking::Client::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let device_id = setup_device(
-        &http_client,
-        &format!("{}/api/devices", opts.hemrs_base_url),
-        &opts.device_name,
-        &opts.device_location,
-    )?;
+    fn parse(args: &[&str]) -> Result<Opts, structopt::clap::Error> {
+        Opts::from_iter_safe(std::iter::once("sensor_monitor").chain(args.iter().copied()))
+    }
 
-    info!("{:?}", device_id);
+    // One test, as the environment is shared by every test thread
+    #[test]
+    fn bool_flags_parse_their_value() {
+        let opts = parse(&[
+            "--once",
+            "false",
+            "--dry-run",
+            "false",
+            "--republish",
+            "true",
+        ])
+        .unwrap();
+        assert_eq!(opts.once, Some(false));
+        assert_eq!(opts.dry_run, Some(false));
+        let config = resolve_config(&opts).unwrap();
+        assert!(!config.once);
+        assert!(!config.dry_run);
+        assert!(config.republish);
+        assert!(!config.batch_posts);
 
-    let sensor_ids = setup_sensors(
-        &http_client,
-        &format!("{}/api/sensors", opts.hemrs_base_url),
-    )?;
+        std::env::set_var("ONCE", "false");
+        std::env::set_var("DRY_RUN", "false");
+        std::env::set_var("BATCH_POSTS", "true");
+        let opts = parse(&[]);
+        std::env::remove_var("ONCE");
+        std::env::remove_var("DRY_RUN");
+        std::env::remove_var("BATCH_POSTS");
+        let config = resolve_config(&opts.unwrap()).unwrap();
+        assert!(!config.once);
+        assert!(!config.dry_run);
+        assert!(config.batch_posts);
 
-    info!("{:?}", sensor_ids);
+        assert!(parse(&["--once", "maybe"]).is_err());
+        assert!(parse(&["--once"]).is_err());
+    }
 
-    let mut mqttoptions = MqttOptions::new(
-        format!(
-            "sensor_monitor_{}",
-            gethostname::gethostname().to_str().unwrap()
-        ),
-        opts.mqtt_host,
-        1883,
-    );
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    const CONFIG: &str = r#"
+default_location = "Hjemme"
 
-    let (client, connection) = Client::new(mqttoptions, 10);
-    client.subscribe(opts.topic, QoS::AtMostOnce)?;
+[[broker]]
+host = "mqtt.local"
+[[broker.topics]]
+topic = "tele/stue/SENSOR"
+device_name = "stue"
+[[broker.topics]]
+topic = "tele/kjokken/SENSOR"
+device_name = "kjokken"
+[[broker.topics]]
+topic = "tele/bad/SENSOR"
+device_name = "bad"
+"#;
 
-    handle_connection(
-        connection,
-        &http_client,
-        &device_id,
-        &sensor_ids,
-        &opts.hemrs_base_url,
-    )?;
-    Ok(())
+    /// Writes `CONFIG` to a file of its own named `name`
+    fn config_file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("sensor_monitor_main_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, CONFIG).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn topics(config: &Config) -> Vec<&str> {
+        config.broker[0]
+            .topics
+            .iter()
+            .map(|topic| topic.topic.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn only_topics_keeps_the_matching_subset() {
+        let path = config_file("only_topics.toml");
+        let opts = parse(&["--config", &path, "--only-topics", "tele/stue/+,tele/bad/#"]).unwrap();
+        assert_eq!(opts.only_topics, ["tele/stue/+", "tele/bad/#"]);
+        let mut config = resolve_config(&opts).unwrap();
+        config.retain_topics();
+        assert_eq!(topics(&config), ["tele/stue/SENSOR", "tele/bad/SENSOR"]);
+
+        let mut config = resolve_config(&parse(&["--config", &path]).unwrap()).unwrap();
+        config.retain_topics();
+        assert_eq!(topics(&config).len(), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_metrics_addresses() {
+        assert_eq!(
+            parse(&["--metrics-addr", "127.0.0.1:9000"])
+                .unwrap()
+                .metrics_addr,
+            Some("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(parse(&[]).unwrap().metrics_addr, None);
+        for addr in ["9000", "localhost", "127.0.0.1:port", "127.0.0.1:70000", ""] {
+            let error = parse(&["--metrics-addr", addr]).unwrap_err();
+            assert!(error.to_string().contains("--metrics-addr"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn show_config_lists_the_topics_run() {
+        let path = config_file("show_config.toml");
+        let shown = show_config(&parse(&["--config", &path]).unwrap()).unwrap();
+        for topic in ["tele/stue/SENSOR", "tele/kjokken/SENSOR", "tele/bad/SENSOR"] {
+            assert!(
+                shown.contains(&format!("\"topic\": \"{}\"", topic)),
+                "{}",
+                shown
+            );
+        }
+
+        let opts = parse(&["--config", &path, "--only-topics", "tele/stue/+"]).unwrap();
+        let shown: serde_json::Value = serde_json::from_str(&show_config(&opts).unwrap()).unwrap();
+        let topics: Vec<&serde_json::Value> = shown["config"]["broker"][0]["topics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|topic| &topic["topic"])
+            .collect();
+        assert_eq!(topics, ["tele/stue/SENSOR"]);
+    }
 }
@@ -0,0 +1,1083 @@
+use std::{
+    collections::{hash_map, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, mpsc::Sender, Arc},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::{join_all, FutureExt, LocalBoxFuture};
+use rumqttc::{qos, AsyncClient, EventLoop, MqttOptions, QoS, TlsConfiguration, Transport};
+use tokio::sync::{watch, Mutex, RwLock};
+use tracing::{error, info, warn};
+
+#[cfg(feature = "schema")]
+use crate::schema::PayloadSchema;
+use crate::{
+    autoregister::{AutoRegister, DEFAULT_AUTO_REGISTER_MAX},
+    config::{
+        reload_config, BrokerConfig, Config, ConfigFormat, MqttTransport, SerialConfig, TopicConfig,
+    },
+    filter::{Deadtime, Filters, MonotonicTime, RateLimit},
+    hem::{setup_device, setup_sensors, Device, HemrsUrls, Registry, ReqwestBackend, SensorIds},
+    http::{Health, LastPayloads},
+    info::{DeviceInfos, DeviceMetadata},
+    labels::Labels,
+    lru::DEFAULT_STATE_MAP_MAX,
+    mqtt::{
+        announce, apply_subscription_diff, diff_subscriptions, disconnect, handle_connection,
+        last_will, subscribe_topics, DeviceContext, Failover, ProcessResult, Processor,
+        DEFAULT_SUBSCRIBE_BATCH_SIZE, STATUS_OFFLINE, STATUS_ONLINE,
+    },
+    republish::{Republisher, DEFAULT_REPUBLISH_TOPIC},
+    retry::RetryPolicy,
+    sink::{IdMode, Sink},
+    smoothing::MovingAverage,
+    stats::RunStats,
+};
+
+pub const DEFAULT_DEADTIME_SECS: u64 = 300;
+
+pub fn default_client_id() -> String {
+    format!(
+        "sensor_monitor_{}",
+        gethostname::gethostname().to_str().unwrap()
+    )
+}
+
+fn default_status_topic() -> String {
+    format!(
+        "sensor_monitor/{}/status",
+        gethostname::gethostname().to_str().unwrap()
+    )
+}
+
+/// Client, topics and devices of a connected broker, replaced on reload while the connection
+/// keeps running
+struct BrokerState {
+    client: AsyncClient,
+    topics: Mutex<Vec<TopicConfig>>,
+    topic_to_device: RwLock<HashMap<String, DeviceContext>>,
+    status_topic: String,
+    /// Connection options of the primary host followed by the failover hosts
+    hosts: Vec<MqttOptions>,
+}
+
+/// Sets up devices and sensors in hemrs and processes readings from all configured sources
+pub struct Monitor {
+    config: Config,
+    config_path: Option<PathBuf>,
+    config_format: Option<ConfigFormat>,
+    http_client: reqwest::Client,
+    urls: HemrsUrls,
+    id_mode: IdMode,
+    retry: RetryPolicy,
+    sink: Box<dyn Sink>,
+    filters: Filters,
+    smoothing: MovingAverage,
+    monotonic_time: MonotonicTime,
+    deadtime: Deadtime,
+    rate_limit: RateLimit,
+    last_payloads: Arc<LastPayloads>,
+    health: Arc<Health>,
+    paused: AtomicBool,
+    labels: Labels,
+    device_infos: DeviceInfos,
+    device_metadata: Option<DeviceMetadata>,
+    stats: RunStats,
+    auto_register: Option<AutoRegister>,
+    #[cfg(feature = "schema")]
+    payload_schema: Option<PayloadSchema>,
+}
+
+impl Monitor {
+    pub fn new(
+        mut config: Config,
+        http_client: reqwest::Client,
+        urls: HemrsUrls,
+        id_mode: IdMode,
+        retry: RetryPolicy,
+        sink: Box<dyn Sink>,
+    ) -> Self {
+        config.retain_topics();
+        let filters = Filters::new(
+            config.sentinels.clone(),
+            config.unit_bounds.clone(),
+            config.sensor_bounds.clone(),
+            config.validate_ranges,
+        );
+        let state_map_max = config.state_map_max.unwrap_or(DEFAULT_STATE_MAP_MAX);
+        let smoothing = MovingAverage::new(config.smoothing.clone(), state_map_max);
+        let monotonic_time = MonotonicTime::new(config.enforce_monotonic_time, state_map_max);
+        let deadtime = Deadtime::new(
+            config.deadtime_after,
+            Duration::from_secs(config.deadtime_secs.unwrap_or(DEFAULT_DEADTIME_SECS)),
+            state_map_max,
+        );
+        let rate_limit = RateLimit::new(
+            config.min_interval_secs.map(Duration::from_secs),
+            state_map_max,
+        );
+        let labels = Labels::new(config.sanitize_labels);
+        let auto_register = config.auto_register.then(|| {
+            AutoRegister::new(
+                ReqwestBackend::new(http_client.clone(), retry),
+                urls.clone(),
+                id_mode == IdMode::Numeric && !config.dry_run,
+                config
+                    .auto_register_max
+                    .unwrap_or(DEFAULT_AUTO_REGISTER_MAX),
+            )
+        });
+        let device_metadata = (urls.device_metadata.is_some() && !config.dry_run)
+            .then(|| DeviceMetadata::new(http_client.clone(), retry, urls.clone(), id_mode));
+        let last_payloads = Arc::new(LastPayloads::new(
+            config.expose_last_payloads,
+            state_map_max,
+        ));
+        Self {
+            config,
+            config_path: None,
+            config_format: None,
+            http_client,
+            urls,
+            id_mode,
+            retry,
+            sink,
+            filters,
+            smoothing,
+            monotonic_time,
+            deadtime,
+            rate_limit,
+            last_payloads,
+            health: Arc::new(Health::new()),
+            paused: AtomicBool::new(false),
+            labels,
+            device_infos: DeviceInfos::new(state_map_max),
+            device_metadata,
+            stats: RunStats::new(),
+            auto_register,
+            #[cfg(feature = "schema")]
+            payload_schema: None,
+        }
+    }
+
+    /// Re-reads the topics of every broker from `path` on SIGHUP, parsed as `format` if given
+    pub fn reload_from(mut self, path: PathBuf, format: Option<ConfigFormat>) -> Self {
+        self.config_path = Some(path);
+        self.config_format = format;
+        self
+    }
+
+    /// Validates every sensor payload against `schema` before parsing it
+    #[cfg(feature = "schema")]
+    pub fn payload_schema(mut self, schema: PayloadSchema) -> Self {
+        self.payload_schema = Some(schema);
+        self
+    }
+
+    /// Last raw payload per topic, shared with the HTTP server
+    pub fn last_payloads(&self) -> Arc<LastPayloads> {
+        Arc::clone(&self.last_payloads)
+    }
+
+    /// Liveness and readiness, shared with the HTTP server
+    pub fn health(&self) -> Arc<Health> {
+        Arc::clone(&self.health)
+    }
+
+    /// Totals since the monitor was created
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    fn hemrs_backend(&self) -> ReqwestBackend {
+        ReqwestBackend::new(self.http_client.clone(), self.retry)
+    }
+
+    /// The hemrs instance of a topic, `None` for the global one
+    fn topic_hemrs(&self, topic: &TopicConfig) -> Option<HemrsUrls> {
+        topic
+            .hemrs_base_url
+            .as_deref()
+            .map(|base_url| self.urls.with_base(base_url))
+    }
+
+    /// Registers the configured sensors in the hemrs instance at `urls`
+    async fn setup_instance_sensors(&self, urls: &HemrsUrls) -> Result<SensorIds> {
+        match self.id_mode {
+            IdMode::Numeric if !self.config.dry_run => {
+                setup_sensors(
+                    &self.hemrs_backend(),
+                    &urls.sensors,
+                    &self.config.sensor_configs(),
+                )
+                .await
+            }
+            _ => Ok(SensorIds::unregistered(&self.config.sensor_configs())),
+        }
+    }
+
+    /// Sets up the device of every topic, devices failing to set up are added to `failed` and
+    /// skipped when `continue_on_setup_error` is set. `devices` holds the registry of every
+    /// hemrs instance by its devices URL.
+    async fn setup_topic_devices(
+        &self,
+        topics: &[TopicConfig],
+        devices: &mut HashMap<String, Registry<Device>>,
+        failed: &mut Vec<String>,
+    ) -> Result<HashMap<String, DeviceContext>> {
+        let mut topic_to_device = HashMap::new();
+        for topic in topics {
+            let hemrs = self.topic_hemrs(topic);
+            let devices_url = &hemrs.as_ref().unwrap_or(&self.urls).devices;
+            let registry = devices
+                .entry(devices_url.clone())
+                .or_insert_with(|| Registry::new(devices_url.clone()));
+            // With auto_register a wildcard topic only names the devices of the topics it matches
+            let template = self.auto_register.is_some() && topic.topic.contains(['+', '#']);
+            let id = match self.id_mode {
+                IdMode::Numeric if !self.config.dry_run && !template => match setup_device(
+                    &self.hemrs_backend(),
+                    registry,
+                    &topic.device_name,
+                    &topic.device_location,
+                )
+                .await
+                {
+                    Ok(id) => id,
+                    Err(e) if self.config.continue_on_setup_error => {
+                        error!(
+                            "Skipping {} on {}, setup failed: {:#}",
+                            topic.device_name, topic.topic, e
+                        );
+                        failed.push(topic.device_name.clone());
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+                // Nothing is registered in hemrs during a dry run
+                _ => 0,
+            };
+            info!("{} {:?}", topic.topic, id);
+            topic_to_device.insert(
+                topic.topic.clone(),
+                DeviceContext {
+                    id,
+                    name: topic.device_name.clone(),
+                    location: topic.device_location.clone(),
+                    expected_sensors: topic.expected_sensors.clone(),
+                    info_topics: topic.info_topics.clone(),
+                    offsets: topic.offsets.clone(),
+                    payload_root: topic.payload_root.clone(),
+                    hemrs,
+                    payload_encoding: topic.payload_encoding,
+                },
+            );
+        }
+        Ok(topic_to_device)
+    }
+
+    /// Runs every broker and serial port until all of them have stopped. When `results` is
+    /// given, the outcome of every processed message is sent on it.
+    pub async fn run(&self, results: Option<Sender<ProcessResult>>) -> Result<()> {
+        self.health.expect_brokers(self.config.broker.len());
+        if !self.config.only_topics.is_empty() && self.config.topic_count() == 0 {
+            return Err(anyhow!(
+                "--only-topics {} matches none of the configured topics",
+                self.config.only_topics.join(",")
+            ));
+        }
+        if self.config.republish && self.config.broker.is_empty() {
+            return Err(anyhow!("--republish needs a [[broker]] to publish to"));
+        }
+        let status_topic = self
+            .config
+            .status_topic
+            .clone()
+            .unwrap_or_else(default_status_topic);
+        // Built first so bad transport settings or certificates fail before anything is set up
+        let broker_options = self
+            .config
+            .broker
+            .iter()
+            .enumerate()
+            .map(|(index, broker)| {
+                std::iter::once(&broker.host)
+                    .chain(&broker.failover_hosts)
+                    .map(|host| build_mqtt_options(index, broker, host, &status_topic))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let sensor_ids = self.setup_instance_sensors(&self.urls).await?;
+        info!("{:?}", sensor_ids);
+        let mut instance_sensor_ids = HashMap::new();
+        let topics = self
+            .config
+            .broker
+            .iter()
+            .flat_map(|broker| &broker.topics)
+            .chain(self.config.serial.iter().map(|serial| &serial.device));
+        for urls in topics.filter_map(|topic| self.topic_hemrs(topic)) {
+            if let hash_map::Entry::Vacant(entry) = instance_sensor_ids.entry(urls.sensors.clone())
+            {
+                let ids = self.setup_instance_sensors(&urls).await?;
+                info!("{} {:?}", urls.sensors, ids);
+                entry.insert(ids);
+            }
+        }
+
+        // Listed once for every broker and serial port
+        let mut devices = HashMap::new();
+        let mut failed = Vec::new();
+        let mut broker_devices = Vec::new();
+        for broker in &self.config.broker {
+            broker_devices.push(
+                self.setup_topic_devices(&broker.topics, &mut devices, &mut failed)
+                    .await?,
+            );
+        }
+        let mut serial_devices = Vec::new();
+        for serial in &self.config.serial {
+            serial_devices.push(
+                self.setup_topic_devices(
+                    std::slice::from_ref(&serial.device),
+                    &mut devices,
+                    &mut failed,
+                )
+                .await?,
+            );
+        }
+        if !failed.is_empty() {
+            let succeeded: Vec<&str> = broker_devices
+                .iter()
+                .chain(serial_devices.iter())
+                .flat_map(|topic_to_device| topic_to_device.values())
+                .map(|device| device.name.as_str())
+                .collect();
+            warn!(
+                "Set up {} devices: {}, failed to set up {}: {}",
+                succeeded.len(),
+                succeeded.join(", "),
+                failed.len(),
+                failed.join(", ")
+            );
+            if succeeded.is_empty() {
+                return Err(anyhow!(
+                    "none of the {} devices could be set up",
+                    failed.len()
+                ));
+            }
+        }
+        self.health.setup_done();
+
+        let batch_size = self
+            .config
+            .subscribe_batch_size
+            .unwrap_or(DEFAULT_SUBSCRIBE_BATCH_SIZE);
+        let (brokers, eventloops): (Vec<_>, Vec<_>) = broker_options
+            .into_iter()
+            .zip(&self.config.broker)
+            .zip(broker_devices)
+            .map(|((hosts, broker), topic_to_device)| {
+                let info_topics: usize = broker
+                    .topics
+                    .iter()
+                    .map(|topic| topic.info_topics.len())
+                    .sum();
+                // Room for every SUBSCRIBE packet and the status, so resubscribing and announcing
+                // never block the connection loop
+                let (client, eventloop) = AsyncClient::new(
+                    hosts[0].clone(),
+                    (broker.topics.len().div_ceil(batch_size.max(1)) + info_topics + 2).max(10),
+                );
+                let state = BrokerState {
+                    client,
+                    topics: Mutex::new(broker.topics.clone()),
+                    topic_to_device: RwLock::new(topic_to_device),
+                    status_topic: status_topic.clone(),
+                    hosts,
+                };
+                (state, eventloop)
+            })
+            .unzip();
+
+        let republisher = self.config.republish.then(|| {
+            Republisher::new(
+                brokers[0].client.clone(),
+                self.config
+                    .republish_topic
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_REPUBLISH_TOPIC.to_string()),
+            )
+        });
+        let processor = Processor {
+            config: &self.config,
+            sink: self.sink.as_ref(),
+            filters: &self.filters,
+            smoothing: &self.smoothing,
+            monotonic_time: &self.monotonic_time,
+            deadtime: &self.deadtime,
+            rate_limit: &self.rate_limit,
+            last_payloads: &self.last_payloads,
+            paused: &self.paused,
+            labels: &self.labels,
+            device_infos: &self.device_infos,
+            device_metadata: self.device_metadata.as_ref(),
+            health: &self.health,
+            stats: &self.stats,
+            auto_register: self.auto_register.as_ref(),
+            republisher: republisher.as_ref(),
+            #[cfg(feature = "schema")]
+            payload_schema: self.payload_schema.as_ref(),
+            sensor_ids,
+            instance_sensor_ids,
+            instance_id: self.config.tag_instance.then(|| {
+                self.config
+                    .instance_id
+                    .clone()
+                    .unwrap_or_else(default_client_id)
+            }),
+        };
+
+        let results = results.as_ref();
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let shutdown_tx = &shutdown_tx;
+        let mut sources: Vec<LocalBoxFuture<Result<()>>> = Vec::new();
+        for ((broker, state), eventloop) in self.config.broker.iter().zip(&brokers).zip(eventloops)
+        {
+            let (processor, shutdown) = (&processor, shutdown.clone());
+            sources.push(
+                async move {
+                    let result = run_broker(
+                        broker, state, eventloop, batch_size, processor, results, shutdown,
+                    )
+                    .await;
+                    if let Err(e) = &result {
+                        error!("Broker {}:{} failed: {:?}", broker.host, broker.port, e);
+                    }
+                    // The first source to finish ends a single message run for all of them
+                    if self.config.once {
+                        shutdown_tx.send_replace(true);
+                    }
+                    result
+                }
+                .boxed_local(),
+            );
+        }
+        for (serial, topic_to_device) in self.config.serial.iter().zip(serial_devices.iter()) {
+            let (processor, shutdown) = (&processor, shutdown.clone());
+            sources.push(
+                async move {
+                    let result =
+                        run_serial(serial, processor, topic_to_device, results, shutdown).await;
+                    if let Err(e) = &result {
+                        error!("Serial port {} failed: {:?}", serial.port, e);
+                    }
+                    if self.config.once {
+                        shutdown_tx.send_replace(true);
+                    }
+                    result
+                }
+                .boxed_local(),
+            );
+        }
+
+        let shutdown_on_signal = async {
+            shutdown_signal().await?;
+            info!("Shutting down gracefully");
+            shutdown_tx.send_replace(true);
+            std::future::pending::<Result<()>>().await
+        };
+
+        // Reloading and waiting for a shutdown signal only stop early on error
+        let outcomes = tokio::select! {
+            outcomes = join_all(sources) => outcomes,
+            Err(e) = self.reload_on_sighup(&brokers, batch_size) => return Err(e),
+            Err(e) = shutdown_on_signal => return Err(e),
+        };
+        outcomes.into_iter().collect()
+    }
+
+    /// Reloads the topics whenever the process receives SIGHUP, never returning unless the
+    /// signal handler cannot be installed
+    async fn reload_on_sighup(&self, brokers: &[BrokerState], batch_size: usize) -> Result<()> {
+        let Some(path) = &self.config_path else {
+            return std::future::pending().await;
+        };
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangups = signal(SignalKind::hangup())?;
+            while hangups.recv().await.is_some() {
+                self.reload(path, brokers, batch_size).await;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (path, brokers, batch_size);
+        std::future::pending().await
+    }
+
+    /// Applies the topics of a reloaded config to the running brokers, matched by host and port.
+    /// A config that fails to parse or devices that fail to set up keep the current topics.
+    /// Other settings and added or removed brokers take effect on restart.
+    async fn reload(&self, path: &Path, brokers: &[BrokerState], batch_size: usize) {
+        info!("Reloading topics from {}", path.display());
+        let Some(mut config) = reload_config(path, self.config_format).await else {
+            return;
+        };
+        config.only_topics = self.config.only_topics.clone();
+        config.retain_topics();
+        for new in &config.broker {
+            if !self
+                .config
+                .broker
+                .iter()
+                .any(|broker| broker.host == new.host && broker.port == new.port)
+            {
+                warn!(
+                    "Broker {}:{} was added, restart to connect to it",
+                    new.host, new.port
+                );
+            }
+        }
+        for (broker, state) in self.config.broker.iter().zip(brokers) {
+            let Some(new) = config
+                .broker
+                .iter()
+                .find(|new| new.host == broker.host && new.port == broker.port)
+            else {
+                warn!(
+                    "Broker {}:{} was removed, restart to disconnect from it",
+                    broker.host, broker.port
+                );
+                continue;
+            };
+            if let Err(e) = self.reload_broker(state, &new.topics, batch_size).await {
+                warn!(
+                    "Reloading topics of {}:{} failed, keeping the current topics: {:#}",
+                    broker.host, broker.port, e
+                );
+            }
+        }
+    }
+
+    async fn reload_broker(
+        &self,
+        state: &BrokerState,
+        topics: &[TopicConfig],
+        batch_size: usize,
+    ) -> Result<()> {
+        let mut current = state.topics.lock().await;
+        // Devices that changed or failed to set up before are set up again
+        let changed: Vec<TopicConfig> = {
+            let topic_to_device = state.topic_to_device.read().await;
+            topics
+                .iter()
+                .filter(|topic| {
+                    !current.contains(topic) || !topic_to_device.contains_key(&topic.topic)
+                })
+                .cloned()
+                .collect()
+        };
+        let mut failed = Vec::new();
+        let devices = self
+            .setup_topic_devices(&changed, &mut HashMap::new(), &mut failed)
+            .await?;
+        let failed_topics: HashSet<String> = changed
+            .iter()
+            .filter(|topic| !devices.contains_key(&topic.topic))
+            .map(|topic| topic.topic.clone())
+            .collect();
+
+        let mut diff = diff_subscriptions(&current, topics);
+        {
+            let mut topic_to_device = state.topic_to_device.write().await;
+            for topic in &diff.unsubscribe {
+                topic_to_device.remove(topic);
+            }
+            topic_to_device.extend(devices);
+            diff.subscribe
+                .retain(|topic| topic_to_device.contains_key(&topic.topic));
+        }
+        apply_subscription_diff(&state.client, &diff, batch_size).await?;
+
+        let info_topics = |topics: &[TopicConfig]| -> HashSet<(String, u8)> {
+            topics
+                .iter()
+                .flat_map(|topic| {
+                    topic
+                        .info_topics
+                        .iter()
+                        .map(|info_topic| (info_topic.clone(), topic.qos))
+                })
+                .collect()
+        };
+        let (old_info, new_info) = (info_topics(&current), info_topics(topics));
+        for (info_topic, _) in old_info.difference(&new_info) {
+            if !new_info.iter().any(|(new, _)| new == info_topic) {
+                info!("Unsubscribing from removed info topic {}", info_topic);
+                state.client.unsubscribe(info_topic).await?;
+            }
+        }
+        for (info_topic, topic_qos) in new_info.difference(&old_info) {
+            info!("Subscribing to added info topic {}", info_topic);
+            state.client.subscribe(info_topic, qos(*topic_qos)?).await?;
+        }
+
+        info!(
+            "Reloaded topics, subscribed to {} and unsubscribed from {}",
+            diff.subscribe.len(),
+            diff.unsubscribe.len()
+        );
+        *current = subscribed_topics(&current, topics, &failed_topics);
+        Ok(())
+    }
+}
+
+/// Topics subscribed after a reload, topics whose setup failed keep their old config or are left
+/// out, so the next reload sets them up and subscribes to them again
+fn subscribed_topics(
+    old: &[TopicConfig],
+    new: &[TopicConfig],
+    failed_topics: &HashSet<String>,
+) -> Vec<TopicConfig> {
+    new.iter()
+        .filter_map(|topic| {
+            if failed_topics.contains(&topic.topic) {
+                old.iter().find(|old| old.topic == topic.topic).cloned()
+            } else {
+                Some(topic.clone())
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "websocket")]
+const DEFAULT_WS_PATH: &str = "/mqtt";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// TLS settings for the broker, verifying it against `ca_cert` when given
+fn tls_config(broker: &BrokerConfig) -> Result<TlsConfiguration> {
+    match &broker.ca_cert {
+        Some(path) => {
+            let ca = std::fs::read(path)
+                .with_context(|| format!("failed to read CA certificate {}", path.display()))?;
+            Ok(TlsConfiguration::Simple {
+                ca,
+                alpn: None,
+                client_auth: None,
+            })
+        }
+        None => Ok(TlsConfiguration::default()),
+    }
+}
+
+/// Connection options for `host` of the `index`th broker: transport, credentials, client id,
+/// keep-alive and the offline last will. WebSocket brokers are addressed by URL.
+fn build_mqtt_options(
+    index: usize,
+    broker: &BrokerConfig,
+    host: &str,
+    status_topic: &str,
+) -> Result<MqttOptions> {
+    let client_id = broker_client_id(index);
+    match (broker.transport, &broker.ws_path) {
+        (MqttTransport::Tcp | MqttTransport::Tls, Some(_)) => {
+            return Err(anyhow!(
+                "ws_path is only used with the ws and wss transports, {} uses {:?}",
+                broker.host,
+                broker.transport
+            ))
+        }
+        (_, Some(path)) if !path.starts_with('/') => {
+            return Err(anyhow!("ws_path {:?} must start with /", path))
+        }
+        _ => {}
+    }
+    if broker.ca_cert.is_some()
+        && matches!(broker.transport, MqttTransport::Tcp | MqttTransport::Ws)
+    {
+        return Err(anyhow!(
+            "ca_cert is only used with the tls and wss transports, {} uses {:?}",
+            broker.host,
+            broker.transport
+        ));
+    }
+    let mut mqttoptions = match broker.transport {
+        MqttTransport::Tcp => MqttOptions::new(client_id, host, broker.port),
+        MqttTransport::Tls => {
+            let mut mqttoptions = MqttOptions::new(client_id, host, broker.port);
+            mqttoptions.set_transport(Transport::tls_with_config(tls_config(broker)?));
+            mqttoptions
+        }
+        #[cfg(feature = "websocket")]
+        MqttTransport::Ws | MqttTransport::Wss => {
+            let (scheme, transport) = match broker.transport {
+                MqttTransport::Ws => ("ws", Transport::Ws),
+                _ => ("wss", Transport::wss_with_config(tls_config(broker)?)),
+            };
+            let ws_path = broker.ws_path.as_deref().unwrap_or(DEFAULT_WS_PATH);
+            let url = format!("{}://{}:{}{}", scheme, host, broker.port, ws_path);
+            let mut mqttoptions = MqttOptions::new(client_id, url, broker.port);
+            mqttoptions.set_transport(transport);
+            mqttoptions
+        }
+        #[cfg(not(feature = "websocket"))]
+        MqttTransport::Ws | MqttTransport::Wss => {
+            return Err(anyhow!(
+                "broker {} uses a WebSocket transport but sensor_monitor was built without the websocket feature",
+                broker.host
+            ))
+        }
+    };
+    match (&broker.username, &broker.password) {
+        (Some(username), Some(password)) => {
+            mqttoptions.set_credentials(username, password);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(anyhow!(
+                "broker {} needs both a username and a password",
+                broker.host
+            ))
+        }
+    }
+    mqttoptions.set_keep_alive(KEEP_ALIVE);
+    mqttoptions.set_last_will(last_will(status_topic));
+    Ok(mqttoptions)
+}
+
+/// Waits for SIGINT, or SIGTERM on unix
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+fn broker_client_id(index: usize) -> String {
+    match index {
+        0 => default_client_id(),
+        _ => format!("{}_{}", default_client_id(), index),
+    }
+}
+
+async fn run_broker(
+    broker: &BrokerConfig,
+    state: &BrokerState,
+    mut eventloop: EventLoop,
+    batch_size: usize,
+    processor: &Processor<'_>,
+    results: Option<&Sender<ProcessResult>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let subscribe = async || -> Result<()> {
+        let topics = state.topics.lock().await;
+        subscribe_topics(&state.client, &topics, batch_size).await?;
+        for topic in topics.iter() {
+            for info_topic in &topic.info_topics {
+                info!("Subscribing to info topic {}", info_topic);
+                state.client.subscribe(info_topic, qos(topic.qos)?).await?;
+            }
+        }
+        if let Some(control_topic) = &processor.config.control_topic {
+            info!("Subscribing to control topic {}", control_topic);
+            state
+                .client
+                .subscribe(control_topic, QoS::AtLeastOnce)
+                .await?;
+        }
+        Ok(())
+    };
+    subscribe().await?;
+    let on_connect = async |resubscribe: bool| -> Result<()> {
+        if resubscribe {
+            subscribe().await?;
+        }
+        announce(&state.client, &state.status_topic, STATUS_ONLINE).await
+    };
+
+    let name = format!("{}:{}", broker.host, broker.port);
+    let mut failover = Failover::new(name.clone(), state.hosts.clone());
+    handle_connection(
+        &mut eventloop,
+        &mut failover,
+        processor,
+        &state.topic_to_device,
+        results,
+        on_connect,
+        shutdown,
+    )
+    .await?;
+    // Sent before the DISCONNECT, which also keeps the broker from publishing the will
+    if let Err(e) = announce(&state.client, &state.status_topic, STATUS_OFFLINE).await {
+        warn!("Announcing {} as offline failed: {}", name, e);
+    }
+    disconnect(&state.client, &mut eventloop, &name).await;
+    Ok(())
+}
+
+#[cfg(feature = "serial")]
+async fn run_serial(
+    serial: &SerialConfig,
+    processor: &Processor<'_>,
+    topic_to_device: &HashMap<String, DeviceContext>,
+    results: Option<&Sender<ProcessResult>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    crate::serial::handle_serial(serial, processor, topic_to_device, results, shutdown).await
+}
+
+#[cfg(not(feature = "serial"))]
+async fn run_serial(
+    serial: &SerialConfig,
+    _processor: &Processor<'_>,
+    _topic_to_device: &HashMap<String, DeviceContext>,
+    _results: Option<&Sender<ProcessResult>>,
+    _shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "serial port {} configured but sensor_monitor was built without the serial feature",
+        serial.port
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        mqtt::Backoff,
+        testing::{
+            fixtures::{topic_config as topic, MockBroker},
+            InMemorySink,
+        },
+    };
+
+    const DHT11: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.5,"Humidity":40.0,"DewPoint":7.5},"TempUnit":"C"}"#;
+
+    /// Monitor identifying devices by name, so nothing is set up in hemrs, subscribed to
+    /// `tele/<name>/SENSOR` on every broker in `brokers`
+    fn monitor(brokers: &[(&str, &MockBroker)]) -> Monitor {
+        let config = brokers
+            .iter()
+            .map(|(name, broker)| {
+                format!(
+                    "[[broker]]\nhost = \"127.0.0.1\"\nport = {}\n[[broker.topics]]\ntopic = \"tele/{name}/SENSOR\"\ndevice_name = \"{name}\"\n",
+                    broker.port
+                )
+            })
+            .collect::<String>();
+        Monitor::new(
+            toml::from_str(&config).unwrap(),
+            reqwest::Client::new(),
+            HemrsUrls::new(
+                "http://127.0.0.1:9",
+                "/api/measurements",
+                "/api/sensors",
+                "/api/devices",
+            ),
+            IdMode::Name,
+            RetryPolicy::default(),
+            Box::new(InMemorySink::new()),
+        )
+    }
+
+    /// Runs the monitor until it processed `count` messages, failing if it stops before
+    async fn processed(monitor: &Monitor, count: usize) -> Vec<ProcessResult> {
+        let (sender, receiver) = mpsc::channel();
+        let collect = async {
+            let mut results = Vec::new();
+            while results.len() < count {
+                match receiver.try_recv() {
+                    Ok(result) => results.push(result),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+            results
+        };
+        let run = async {
+            tokio::select! {
+                result = monitor.run(Some(sender)) => panic!("monitor stopped: {:?}", result),
+                results = collect => results,
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), run)
+            .await
+            .expect("messages were not processed in time")
+    }
+
+    #[test]
+    fn topics_failing_setup_are_not_recorded_as_subscribed() {
+        let old = vec![topic("tele/a/SENSOR", "a"), topic("tele/b/SENSOR", "b")];
+        let new = vec![
+            topic("tele/a/SENSOR", "a"),
+            topic("tele/b/SENSOR", "b2"),
+            topic("tele/c/SENSOR", "c"),
+        ];
+        let failed = HashSet::from(["tele/b/SENSOR".to_string(), "tele/c/SENSOR".to_string()]);
+
+        let current = subscribed_topics(&old, &new, &failed);
+        assert_eq!(current, old);
+        // The next reload still sees the failed topics as changed or new
+        let diff = diff_subscriptions(&current, &new);
+        assert_eq!(diff.subscribe, vec![topic("tele/c/SENSOR", "c")]);
+        assert!(diff.unsubscribe.is_empty());
+
+        assert_eq!(subscribed_topics(&old, &new, &HashSet::new()), new);
+    }
+
+    #[tokio::test]
+    async fn keeps_processing_after_a_failed_message() {
+        let broker = MockBroker::start(&[("tele/a/SENSOR", "{"), ("tele/a/SENSOR", DHT11)]);
+        let results = processed(&monitor(&[("a", &broker)]), 2).await;
+        assert!(results[0].error.is_some());
+        assert!(results[1].stored);
+    }
+
+    #[tokio::test]
+    async fn failing_messages_on_one_broker_leave_the_others_running() {
+        let failing = MockBroker::start(&[("tele/a/SENSOR", "{"), ("tele/a/SENSOR", DHT11)]);
+        let healthy = MockBroker::start(&[("tele/b/SENSOR", DHT11)]);
+        let monitor = monitor(&[("a", &failing), ("b", &healthy)]);
+
+        let mut results = processed(&monitor, 3).await;
+        results.sort_by_key(|result| (result.topic.clone(), result.stored));
+        let outcomes: Vec<(&str, bool, bool)> = results
+            .iter()
+            .map(|result| (result.topic.as_str(), result.stored, result.error.is_some()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("tele/a/SENSOR", false, true),
+                ("tele/a/SENSOR", true, false),
+                ("tele/b/SENSOR", true, false),
+            ]
+        );
+    }
+
+    fn broker_config(host: &str) -> BrokerConfig {
+        toml::from_str(&format!("host = \"{host}\"\ntopics = []")).unwrap()
+    }
+
+    #[test]
+    fn sets_a_retained_offline_last_will_on_the_status_topic() {
+        let broker = broker_config("mqtt.local");
+        let mqttoptions =
+            build_mqtt_options(0, &broker, &broker.host, "sensor_monitor/pi/status").unwrap();
+        let will = mqttoptions.last_will().unwrap();
+        assert_eq!(will.topic, "sensor_monitor/pi/status");
+        assert_eq!(&will.message[..], STATUS_OFFLINE.as_bytes());
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
+    }
+
+    #[test]
+    fn failover_rotates_through_the_hosts_with_backoff_between_rounds() {
+        let mut broker = broker_config("primary");
+        broker.failover_hosts = vec!["secondary".to_string(), "tertiary".to_string()];
+        let hosts = std::iter::once(&broker.host)
+            .chain(&broker.failover_hosts)
+            .map(|host| build_mqtt_options(0, &broker, host, "status").unwrap())
+            .collect();
+        let mut failover = Failover::new("primary".to_string(), hosts);
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        assert_eq!(failover.active_host(), "primary:1883");
+
+        let attempts: Vec<(String, Duration)> = (0..6)
+            .map(|_| {
+                let delay = failover.on_failure(&mut backoff);
+                (failover.active_host(), delay)
+            })
+            .collect();
+        assert_eq!(
+            attempts,
+            [
+                ("secondary:1883".to_string(), Duration::ZERO),
+                ("tertiary:1883".to_string(), Duration::ZERO),
+                ("primary:1883".to_string(), Duration::from_secs(1)),
+                ("secondary:1883".to_string(), Duration::ZERO),
+                ("tertiary:1883".to_string(), Duration::ZERO),
+                ("primary:1883".to_string(), Duration::from_secs(2)),
+            ]
+        );
+        // The broker keeps its name whichever host is in use
+        assert_eq!(failover.broker(), "primary");
+    }
+
+    #[test]
+    fn names_the_client_after_the_host_and_broker() {
+        let broker = broker_config("mqtt.local");
+        let hostname = gethostname::gethostname().into_string().unwrap();
+        let first = build_mqtt_options(0, &broker, &broker.host, "status").unwrap();
+        assert_eq!(first.client_id(), format!("sensor_monitor_{}", hostname));
+        assert_eq!(first.keep_alive(), Duration::from_secs(5));
+        assert_eq!(first.broker_address(), ("mqtt.local".to_string(), 1883));
+        assert!(first.credentials().is_none());
+
+        let third = build_mqtt_options(2, &broker, &broker.host, "status").unwrap();
+        assert_eq!(third.client_id(), format!("sensor_monitor_{}_2", hostname));
+        assert_eq!(third.keep_alive(), KEEP_ALIVE);
+
+        let mut broker = broker;
+        broker.username = Some("monitor".to_string());
+        assert!(build_mqtt_options(0, &broker, &broker.host, "status").is_err());
+        broker.password = Some("secret".to_string());
+        let authenticated = build_mqtt_options(0, &broker, &broker.host, "status").unwrap();
+        assert_eq!(
+            authenticated.credentials(),
+            Some(("monitor".to_string(), "secret".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_up_only_the_topics_matching_only_topics() {
+        let config = r#"
+            default_location = "Stue"
+            only_topics = ["tele/stue/+", "tele/bad/#"]
+            [[broker]]
+            host = "127.0.0.1"
+            topics = [
+                { topic = "tele/stue/SENSOR", device_name = "stue" },
+                { topic = "tele/kjokken/SENSOR", device_name = "kjokken" },
+                { topic = "tele/bad/SENSOR", device_name = "bad" },
+            ]
+        "#;
+        let monitor = Monitor::new(
+            toml::from_str(config).unwrap(),
+            reqwest::Client::new(),
+            HemrsUrls::new(
+                "http://127.0.0.1:9",
+                "/api/measurements",
+                "/api/sensors",
+                "/api/devices",
+            ),
+            IdMode::Name,
+            RetryPolicy::default(),
+            Box::new(InMemorySink::new()),
+        );
+        let topic_to_device = monitor
+            .setup_topic_devices(
+                &monitor.config.broker[0].topics,
+                &mut HashMap::new(),
+                &mut Vec::new(),
+            )
+            .await
+            .unwrap();
+        let mut topics: Vec<&str> = topic_to_device.keys().map(String::as_str).collect();
+        topics.sort();
+        assert_eq!(topics, ["tele/bad/SENSOR", "tele/stue/SENSOR"]);
+    }
+}
@@ -1,151 +1,2429 @@
-use anyhow::{Error, Result};
-use chrono::NaiveDateTime;
-use reqwest::blocking::Client;
-use rumqttc::{Connection, Event, Packet};
-use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::hem::{DeviceId, SensorIds};
+use anyhow::{anyhow, Context, Error, Result};
+use base64::prelude::*;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use futures::{stream::FuturesUnordered, StreamExt};
+use metrics::{counter, gauge, histogram, Gauge};
+use rumqttc::{
+    qos, AsyncClient, ConnectionError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet,
+    Publish, QoS, SubscribeFilter,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::sync::{watch, RwLock};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+#[cfg(feature = "schema")]
+use crate::schema::PayloadSchema;
+use crate::{
+    autoregister::AutoRegister,
+    config::{Config, PayloadEncoding, TopicConfig},
+    deadletter::{self, DeadLetter},
+    filter::{Deadtime, Filters, MonotonicTime, RateLimit},
+    hem::{DeviceId, HemrsUrls, SensorIds},
+    http::{Health, LastPayloads},
+    info::{parse_device_info, DeviceInfos, DeviceMetadata},
+    labels::Labels,
+    republish::Republisher,
+    sink::Sink,
+    smoothing::MovingAverage,
+    stats::RunStats,
+};
+
+/// Accepts Tasmota times with a UTC suffix or offset as well as without, naive times are taken
+/// to be UTC
+fn de_flexible_time<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    if let Ok(time) = DateTime::parse_from_rfc3339(&time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(&time, "%Y-%m-%dT%H:%M:%S%.f")
+        .map(|time| time.and_utc())
+        .map_err(|e| de::Error::custom(format!("invalid time {:?}: {}", time, e)))
+}
+
+fn de_optional_flexible_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    de_flexible_time(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a number or a numeric string")]
+enum FlexibleF32 {
+    Number(f32),
+    String(String),
+}
+
+impl FlexibleF32 {
+    fn into_f32<E: de::Error>(self) -> Result<f32, E> {
+        match self {
+            FlexibleF32::Number(value) => Ok(value),
+            FlexibleF32::String(value) => value
+                .trim()
+                .parse()
+                .map_err(|e| E::custom(format!("invalid number {:?}: {}", value, e))),
+        }
+    }
+}
+
+/// Accepts a number or a numeric string, some Tasmota firmware versions quote their readings
+pub fn de_f32_flexible<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    FlexibleF32::deserialize(deserializer)?.into_f32()
+}
+
+fn de_optional_f32_flexible<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<FlexibleF32>::deserialize(deserializer)?
+        .map(FlexibleF32::into_f32)
+        .transpose()
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct DS18B20 {
     #[serde(rename = "Id")]
     _id: String,
+    #[serde(deserialize_with = "de_f32_flexible")]
     temperature: f32,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct DHT11 {
+    #[serde(deserialize_with = "de_f32_flexible")]
+    temperature: f32,
+    #[serde(deserialize_with = "de_f32_flexible")]
+    humidity: f32,
+    #[serde(default, deserialize_with = "de_optional_f32_flexible")]
+    dew_point: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct BME280 {
+    #[serde(deserialize_with = "de_f32_flexible")]
     temperature: f32,
+    #[serde(deserialize_with = "de_f32_flexible")]
     humidity: f32,
-    dew_point: f32,
+    #[serde(deserialize_with = "de_f32_flexible")]
+    pressure: f32,
+    #[serde(default, deserialize_with = "de_optional_f32_flexible")]
+    dew_point: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct SHT3X {
+    #[serde(deserialize_with = "de_f32_flexible")]
+    temperature: f32,
+    #[serde(deserialize_with = "de_f32_flexible")]
+    humidity: f32,
+    #[serde(default, deserialize_with = "de_optional_f32_flexible")]
+    dew_point: Option<f32>,
+}
+
+/// Power monitoring of Tasmota plugs, the other fields they send are ignored
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct Energy {
+    voltage: Option<f32>,
+    current: Option<f32>,
+    power: Option<f32>,
+    total: Option<f32>,
+}
+
+fn default_temp_unit() -> String {
+    "C".to_string()
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct SensorEntry {
-    #[serde(rename = "Time")]
-    _time: NaiveDateTime,
+    /// Absent on minimal firmware that does not stamp its messages
+    #[serde(
+        rename = "Time",
+        default,
+        deserialize_with = "de_optional_flexible_time"
+    )]
+    time: Option<DateTime<Utc>>,
     #[serde(rename = "DS18B20")]
     ds18b20: Option<DS18B20>,
     #[serde(rename = "DHT11")]
     dht11: Option<DHT11>,
-    #[serde(rename = "TempUnit")]
-    _temp_unit: String,
+    #[serde(rename = "BME280")]
+    bme280: Option<BME280>,
+    #[serde(rename = "SHT3X")]
+    sht3x: Option<SHT3X>,
+    #[serde(rename = "ENERGY")]
+    energy: Option<Energy>,
+    /// `C` or `F`, the unit of every temperature and dew point in the payload. Plugs without a
+    /// temperature sensor leave it out.
+    #[serde(rename = "TempUnit", default = "default_temp_unit")]
+    temp_unit: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceContext {
+    pub id: DeviceId,
+    pub name: String,
+    pub location: String,
+    pub expected_sensors: Vec<String>,
+    pub info_topics: Vec<String>,
+    /// Calibration offset per sensor key, sensors without one are stored as read
+    pub offsets: HashMap<String, f32>,
+    /// JSON pointer to the sensor object in the payload, empty for the payload itself
+    pub payload_root: String,
+    /// hemrs instance of the device when it is not the global one
+    pub hemrs: Option<HemrsUrls>,
+    pub payload_encoding: PayloadEncoding,
+}
+
+impl DeviceContext {
+    /// Whether the device should report `sensor`, every sensor is expected when none are listed
+    pub fn expects(&self, sensor: &str) -> bool {
+        self.expected_sensors.is_empty()
+            || self
+                .expected_sensors
+                .iter()
+                .any(|expected| expected.eq_ignore_ascii_case(sensor))
+    }
+
+    /// Whether `sensor` is listed explicitly, for sensors only warned about when listed
+    pub fn lists(&self, sensor: &str) -> bool {
+        !self.expected_sensors.is_empty() && self.expects(sensor)
+    }
+
+    pub fn offset(&self, sensor_key: &str) -> f32 {
+        self.offsets.get(sensor_key).copied().unwrap_or(0.0)
+    }
+
+    /// The reading with the calibration offset of its sensor applied
+    pub fn calibrate(&self, sensor_key: &str, raw: f32) -> f32 {
+        raw + self.offset(sensor_key)
+    }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct Measurement {
-    device: i32,
-    sensor: i32,
-    measurement: f32,
+    pub device: i32,
+    pub sensor: i32,
+    /// Wider than the readings so a rounded value is posted exactly as rounded
+    pub measurement: f64,
+    /// Unit the sensor is registered with, only set when sending units is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Monitor instance that wrote the measurement, only set when tagging is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// `Time` of the message the reading came from, only set when sending timestamps is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub device_name: String,
+    #[serde(skip)]
+    pub sensor_name: String,
+    /// Where hemrs stores the measurement when it is not the global measurements endpoint
+    #[serde(skip)]
+    pub hemrs_url: Option<String>,
 }
 
 impl Measurement {
-    pub fn new(device: i32, sensor: i32, measurement: f32) -> Self {
+    pub fn new(device: &DeviceContext, sensor: i32, sensor_name: &str, measurement: f64) -> Self {
         Self {
-            device,
+            device: device.id,
             sensor,
             measurement,
+            unit: None,
+            instance: None,
+            timestamp: None,
+            device_name: device.name.clone(),
+            sensor_name: sensor_name.to_string(),
+            hemrs_url: device.hemrs.as_ref().map(|urls| urls.measurements.clone()),
         }
     }
 }
 
-pub fn store_measurement(
-    client: &reqwest::blocking::Client,
-    url: &str,
-    entry: SensorEntry,
-    device_id: &DeviceId,
-    sensor_ids: &SensorIds,
+/// Everything shared by all sources needed to turn a payload into stored measurements
+pub struct Processor<'a> {
+    pub config: &'a Config,
+    pub sink: &'a dyn Sink,
+    pub filters: &'a Filters,
+    pub smoothing: &'a MovingAverage,
+    pub monotonic_time: &'a MonotonicTime,
+    pub deadtime: &'a Deadtime,
+    pub rate_limit: &'a RateLimit,
+    pub last_payloads: &'a LastPayloads,
+    /// Set while ingestion is paused through the control topic
+    pub paused: &'a AtomicBool,
+    pub labels: &'a Labels,
+    pub device_infos: &'a DeviceInfos,
+    /// Stores device info in hemrs, when enabled
+    pub device_metadata: Option<&'a DeviceMetadata>,
+    pub health: &'a Health,
+    pub stats: &'a RunStats,
+    /// Sets up devices for the topics wildcard topics match, when enabled
+    pub auto_register: Option<&'a AutoRegister>,
+    /// Publishes stored measurements back to MQTT, when enabled
+    pub republisher: Option<&'a Republisher>,
+    #[cfg(feature = "schema")]
+    pub payload_schema: Option<&'a PayloadSchema>,
+    pub sensor_ids: SensorIds,
+    /// Sensors registered in the hemrs instances of devices not using the global one, by the
+    /// sensors URL of the instance
+    pub instance_sensor_ids: HashMap<String, SensorIds>,
+    pub instance_id: Option<String>,
+}
+
+impl Processor<'_> {
+    /// Whether a message on `topic` should be dropped because ingestion is paused
+    pub fn drop_paused(&self, topic: &str) -> bool {
+        if !self.paused.load(Ordering::SeqCst) {
+            return false;
+        }
+        debug!("Ingestion paused, dropping message on {}", topic);
+        counter!("sensor_paused_messages_total", "topic" => self.labels.value(topic)).increment(1);
+        true
+    }
+}
+
+pub fn fahrenheit_to_celsius(fahrenheit: f32) -> f32 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Rounds to `decimals` places, ties to even. The value is taken as the decimal it was read as,
+/// so 22.55 is a tie rather than the 22.549999 it is stored as.
+pub fn round_half_even(value: f32, decimals: u32) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    let decimal: f64 = value.to_string().parse().unwrap_or(value.into());
+    let mut scaled = decimal * factor;
+    let tie = scaled.floor() + 0.5;
+    if (scaled - tie).abs() <= scaled.abs() * 4.0 * f64::EPSILON {
+        scaled = tie;
+    }
+    scaled.round_ties_even() / factor
+}
+
+/// Dew point in °C from temperature in °C and relative humidity in %, using the Magnus formula
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    const B: f32 = 17.62;
+    const C: f32 = 243.12;
+    let gamma = (humidity / 100.0).ln() + B * temperature / (C + temperature);
+    C * gamma / (B - gamma)
+}
+
+/// Heat index in °C from temperature in °C and relative humidity in %, using the NOAA formula
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + humidity * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_3 * t + 10.143_332 * humidity
+            - 0.224_755_42 * t * humidity
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * humidity * humidity
+            + 0.001_228_74 * t * t * humidity
+            + 0.000_852_82 * t * humidity * humidity
+            - 0.000_001_99 * t * t * humidity * humidity;
+        if humidity < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - humidity) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if humidity > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (humidity - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Absolute humidity in g/m³ from temperature in °C and relative humidity in %
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let saturation_pressure = 6.112 * (17.67 * temperature / (temperature + 243.5)).exp();
+    saturation_pressure * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// Filters and transforms a raw reading into the measurement to store, if it is accepted
+fn process_reading(
+    processor: &Processor<'_>,
+    device: &DeviceContext,
+    topic: &str,
+    time: Option<DateTime<Utc>>,
+    sensor_key: &'static str,
+    raw: f32,
+) -> Option<Measurement> {
+    let sensor_ids = match &device.hemrs {
+        Some(urls) => match processor.instance_sensor_ids.get(&urls.sensors) {
+            Some(sensor_ids) => sensor_ids,
+            None => {
+                warn!(
+                    "{} uses hemrs at {}, which was not set up at startup, dropping {}",
+                    device.name, urls.sensors, sensor_key
+                );
+                return None;
+            }
+        },
+        None => &processor.sensor_ids,
+    };
+    let Some(sensor) = sensor_ids.get(sensor_key) else {
+        debug!("No sensor configured for {}, dropping {}", sensor_key, raw);
+        return None;
+    };
+    if processor.deadtime.suppressed(&device.name, sensor_key) {
+        return None;
+    }
+    let device_label = processor.labels.value(&device.name);
+    gauge!("sensor_raw_value", "device" => device_label.clone(), "sensor" => sensor_key).set(raw);
+    let accepted = processor
+        .filters
+        .accept(topic, sensor_key, &sensor.unit, raw);
+    processor
+        .deadtime
+        .record(&device.name, sensor_key, accepted);
+    // Without a device time there is nothing to check the order against
+    if !accepted
+        || time.is_some_and(|time| {
+            !processor
+                .monotonic_time
+                .accept(&device.name, sensor_key, time)
+        })
+    {
+        return None;
+    }
+    // Filters see the raw reading, so sentinels match what the sensor actually sent
+    let calibrated = device.calibrate(sensor_key, raw);
+    let value = processor
+        .smoothing
+        .smooth(&device.name, sensor_key, calibrated);
+    debug!(
+        "{} raw value {} transformed value {}",
+        sensor_key, raw, value
+    );
+    gauge!("sensor_transformed_value", "device" => device_label, "sensor" => sensor_key).set(value);
+    let stored = match processor.config.round_decimals {
+        Some(decimals) => round_half_even(value, decimals),
+        None => value.into(),
+    };
+    let mut measurement = Measurement::new(device, sensor.id, &sensor.name, stored);
+    if processor.config.send_units {
+        measurement.unit = Some(sensor.unit.clone());
+    }
+    if processor.config.send_timestamps {
+        measurement.timestamp = time;
+    }
+    measurement.instance = processor.instance_id.clone();
+    Some(measurement)
+}
+
+impl SensorEntry {
+    /// Names of the sensor blocks present in the payload
+    fn sensors(&self) -> Vec<&'static str> {
+        let mut sensors = Vec::new();
+        if self.ds18b20.is_some() {
+            sensors.push("DS18B20");
+        }
+        if self.dht11.is_some() {
+            sensors.push("DHT11");
+        }
+        if self.bme280.is_some() {
+            sensors.push("BME280");
+        }
+        if self.sht3x.is_some() {
+            sensors.push("SHT3X");
+        }
+        if self.energy.is_some() {
+            sensors.push("ENERGY");
+        }
+        sensors
+    }
+
+    /// Converts the temperatures and dew points to °C, humidity and pressure are left as they are
+    fn convert_to_celsius(&mut self) {
+        if let Some(ds18b20) = &mut self.ds18b20 {
+            ds18b20.temperature = fahrenheit_to_celsius(ds18b20.temperature);
+        }
+        if let Some(dht11) = &mut self.dht11 {
+            dht11.temperature = fahrenheit_to_celsius(dht11.temperature);
+            dht11.dew_point = dht11.dew_point.map(fahrenheit_to_celsius);
+        }
+        if let Some(bme280) = &mut self.bme280 {
+            bme280.temperature = fahrenheit_to_celsius(bme280.temperature);
+            bme280.dew_point = bme280.dew_point.map(fahrenheit_to_celsius);
+        }
+        if let Some(sht3x) = &mut self.sht3x {
+            sht3x.temperature = fahrenheit_to_celsius(sht3x.temperature);
+            sht3x.dew_point = sht3x.dew_point.map(fahrenheit_to_celsius);
+        }
+    }
+}
+
+/// Warns when the payload does not contain exactly the sensors expected for the device
+fn validate_expected_sensors(processor: &Processor, entry: &SensorEntry, device: &DeviceContext) {
+    if device.expected_sensors.is_empty() {
+        return;
+    }
+    let present = entry.sensors();
+    let matches = present.len() == device.expected_sensors.len()
+        && present.iter().all(|sensor| device.expects(sensor));
+    if !matches {
+        warn!(
+            "{} reported sensors {:?}, expected {:?}",
+            device.name, present, device.expected_sensors
+        );
+        counter!("sensor_unexpected_sensor_set_total", "device" => processor.labels.value(&device.name))
+            .increment(1);
+    }
+}
+
+pub async fn store_measurement(
+    processor: &Processor<'_>,
+    topic: &str,
+    mut entry: SensorEntry,
+    device: &DeviceContext,
 ) -> Result<()> {
+    match entry.temp_unit.as_str() {
+        "C" => {}
+        "F" => entry.convert_to_celsius(),
+        unit => warn!(
+            "{} reports unknown TempUnit {:?}, storing temperatures as sent",
+            device.name, unit
+        ),
+    }
+    if processor.config.validate_expected_sensors {
+        validate_expected_sensors(processor, &entry, device);
+    }
+    let time = match entry.time {
+        Some(time) => Some(time),
+        None if processor.config.allow_missing_time => {
+            debug!("{} sent no Time, using the current time", device.name);
+            None
+        }
+        None => return Err(anyhow!("payload from {} has no Time", device.name)),
+    };
+    let mut measurements = Vec::new();
     match entry.dht11 {
         Some(dht11) => {
             info!("Logging DHT11");
-            let dht11_temperature =
-                Measurement::new(*device_id, sensor_ids.dht11_temperature, dht11.temperature);
-            let dht11_humidity =
-                Measurement::new(*device_id, sensor_ids.dht11_humidity, dht11.humidity);
-            let dht11_dew_point =
-                Measurement::new(*device_id, sensor_ids.dht11_dew_point, dht11.dew_point);
-            client.post(url).json(&dht11_temperature).send()?;
-            client.post(url).json(&dht11_humidity).send()?;
-            client.post(url).json(&dht11_dew_point).send()?;
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "dht11_temperature",
+                dht11.temperature,
+            ));
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "dht11_humidity",
+                dht11.humidity,
+            ));
+            // Derived values are computed from the calibrated readings
+            let (temperature, humidity) = (
+                device.calibrate("dht11_temperature", dht11.temperature),
+                device.calibrate("dht11_humidity", dht11.humidity),
+            );
+            let dew_point = match dht11.dew_point {
+                Some(dew_point) => Some(dew_point),
+                None if processor.config.compute_dew_point => {
+                    Some(dew_point(temperature, humidity))
+                }
+                None => None,
+            };
+            match dew_point {
+                Some(dew_point) => measurements.extend(process_reading(
+                    processor,
+                    device,
+                    topic,
+                    time,
+                    "dht11_dew_point",
+                    dew_point,
+                )),
+                None => debug!("DHT11 without DewPoint"),
+            }
+            if processor.config.compute_comfort_metrics {
+                measurements.extend(process_reading(
+                    processor,
+                    device,
+                    topic,
+                    time,
+                    "dht11_heat_index",
+                    heat_index(temperature, humidity),
+                ));
+                measurements.extend(process_reading(
+                    processor,
+                    device,
+                    topic,
+                    time,
+                    "dht11_absolute_humidity",
+                    absolute_humidity(temperature, humidity),
+                ));
+            }
         }
-        None => {
+        None if device.expects("DHT11") => {
             warn!("Unable to process DHT11");
         }
+        None => debug!("{} has no DHT11", device.name),
     }
 
     match entry.ds18b20 {
         Some(ds18b20) => {
             info!("Logging DS18B20");
-            let ds18b20_entry =
-                Measurement::new(*device_id, sensor_ids.ds18b20, ds18b20.temperature);
-            client.post(url).json(&ds18b20_entry).send()?;
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "ds18b20",
+                ds18b20.temperature,
+            ));
         }
-        None => {
+        None if device.expects("DS18B20") => {
             warn!("Unable to process DS18B20");
         }
+        None => debug!("{} has no DS18B20", device.name),
     }
 
+    match entry.bme280 {
+        Some(bme280) => {
+            info!("Logging BME280");
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "bme280_temperature",
+                bme280.temperature,
+            ));
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "bme280_humidity",
+                bme280.humidity,
+            ));
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "bme280_pressure",
+                bme280.pressure,
+            ));
+            let dew_point = match bme280.dew_point {
+                Some(dew_point) => Some(dew_point),
+                None if processor.config.compute_dew_point => Some(dew_point(
+                    device.calibrate("bme280_temperature", bme280.temperature),
+                    device.calibrate("bme280_humidity", bme280.humidity),
+                )),
+                None => None,
+            };
+            match dew_point {
+                Some(dew_point) => measurements.extend(process_reading(
+                    processor,
+                    device,
+                    topic,
+                    time,
+                    "bme280_dew_point",
+                    dew_point,
+                )),
+                None => debug!("BME280 without DewPoint"),
+            }
+        }
+        None => debug!("No BME280 in payload"),
+    }
+
+    match entry.sht3x {
+        Some(sht3x) => {
+            info!("Logging SHT3X");
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "sht3x_temperature",
+                sht3x.temperature,
+            ));
+            measurements.extend(process_reading(
+                processor,
+                device,
+                topic,
+                time,
+                "sht3x_humidity",
+                sht3x.humidity,
+            ));
+            let dew_point = match sht3x.dew_point {
+                Some(dew_point) => Some(dew_point),
+                None if processor.config.compute_dew_point => Some(dew_point(
+                    device.calibrate("sht3x_temperature", sht3x.temperature),
+                    device.calibrate("sht3x_humidity", sht3x.humidity),
+                )),
+                None => None,
+            };
+            match dew_point {
+                Some(dew_point) => measurements.extend(process_reading(
+                    processor,
+                    device,
+                    topic,
+                    time,
+                    "sht3x_dew_point",
+                    dew_point,
+                )),
+                None => debug!("SHT3X without DewPoint"),
+            }
+        }
+        None if device.lists("SHT3X") => {
+            warn!("Unable to process SHT3X");
+        }
+        None => debug!("{} has no SHT3X", device.name),
+    }
+
+    match entry.energy {
+        Some(energy) => {
+            info!("Logging ENERGY");
+            let readings = [
+                ("energy_voltage", energy.voltage),
+                ("energy_current", energy.current),
+                ("energy_power", energy.power),
+                ("energy_total", energy.total),
+            ];
+            for (key, value) in readings {
+                if let Some(value) = value {
+                    measurements
+                        .extend(process_reading(processor, device, topic, time, key, value));
+                }
+            }
+        }
+        None => debug!("No ENERGY in payload"),
+    }
+
+    if processor.config.batch_posts {
+        processor.sink.write_measurements(&measurements).await?;
+        for measurement in &measurements {
+            record_stored(processor, measurement);
+        }
+    } else {
+        for measurement in &measurements {
+            processor.sink.write_measurement(measurement).await?;
+            record_stored(processor, measurement);
+        }
+    }
+    if let Some(republisher) = processor.republisher {
+        for measurement in &measurements {
+            republisher.publish(measurement);
+        }
+    }
     Ok(())
 }
 
-pub fn handle_incomming(
-    inc: Packet,
-    http_client: &Client,
-    device_id: &DeviceId,
-    sensor_ids: &SensorIds,
-    url: &str,
+/// Counts a measurement the sink accepted and exports it as the latest value of its sensor
+fn record_stored(processor: &Processor, measurement: &Measurement) {
+    counter!("sensor_measurements_posted_total").increment(1);
+    processor.stats.measurement();
+    gauge!(
+        "sensor_last_value",
+        "device" => processor.labels.value(&measurement.device_name),
+        "sensor" => processor.sensor_ids.key(&measurement.sensor_name).to_string()
+    )
+    .set(measurement.measurement);
+}
+
+pub const DEFAULT_SUBSCRIBE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_SENSOR_TOPIC_SUFFIX: &str = "/SENSOR";
+pub const DEFAULT_SLOW_PARSE_THRESHOLD_MS: u64 = 50;
+
+/// Subscribes to all topics using one SUBSCRIBE packet per `batch_size` topics
+pub async fn subscribe_topics(
+    client: &AsyncClient,
+    topics: &[TopicConfig],
+    batch_size: usize,
 ) -> Result<()> {
-    if let Packet::Publish(p) = inc {
-        let payload = String::from_utf8(p.payload.to_vec())?;
-        info!("Got payload! {}", payload);
-        match serde_json::from_str::<SensorEntry>(&payload) {
-            Ok(sensor) => {
-                store_measurement(
-                    http_client,
-                    &format!("{}/api/measurements", url),
-                    sensor,
-                    device_id,
-                    sensor_ids,
-                )?;
-                Ok(())
+    let filters = topics
+        .iter()
+        .map(|topic| Ok(SubscribeFilter::new(topic.topic.clone(), qos(topic.qos)?)))
+        .collect::<Result<Vec<_>>>()?;
+    for batch in filters.chunks(batch_size.max(1)) {
+        info!("Subscribing to {} topics", batch.len());
+        client.subscribe_many(batch.to_vec()).await?;
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the subscription `pattern` by the MQTT wildcard rules, `+` matching
+/// exactly one level and a trailing `#` any number of levels, including none
+pub fn match_topic(pattern: &str, topic: &str) -> bool {
+    // Wildcards in the first level do not match system topics such as $SYS/...
+    if topic.starts_with('$') && (pattern.starts_with('+') || pattern.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for filter in pattern.split('/') {
+        match (filter, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (filter, Some(level)) if filter == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Device of the topic, falling back to wildcard topics when there is no exact match. When
+/// several wildcard topics match, the one with the most literal levels wins.
+pub fn find_device<'a>(
+    topic_to_device: &'a HashMap<String, DeviceContext>,
+    topic: &str,
+) -> Option<&'a DeviceContext> {
+    if let Some(device) = topic_to_device.get(topic) {
+        return Some(device);
+    }
+    topic_to_device
+        .iter()
+        .filter(|(pattern, _)| pattern.contains(['+', '#']) && match_topic(pattern, topic))
+        .max_by_key(|(pattern, _)| {
+            let literal_levels = pattern
+                .split('/')
+                .filter(|level| *level != "+" && *level != "#")
+                .count();
+            // Ties go to the alphabetically first pattern, so the choice does not depend on
+            // the map's iteration order
+            (literal_levels, std::cmp::Reverse(pattern.as_str()))
+        })
+        .map(|(_, device)| device)
+}
+
+/// Subscription changes needed to go from one topic list to another
+#[derive(Debug, Default, PartialEq)]
+pub struct SubscriptionDiff {
+    /// Topics that are new or changed QoS
+    pub subscribe: Vec<TopicConfig>,
+    pub unsubscribe: Vec<String>,
+}
+
+pub fn diff_subscriptions(old: &[TopicConfig], new: &[TopicConfig]) -> SubscriptionDiff {
+    let subscribe = new
+        .iter()
+        .filter(|topic| {
+            !old.iter()
+                .any(|old| old.topic == topic.topic && old.qos == topic.qos)
+        })
+        .cloned()
+        .collect();
+    let unsubscribe = old
+        .iter()
+        .filter(|topic| !new.iter().any(|new| new.topic == topic.topic))
+        .map(|topic| topic.topic.clone())
+        .collect();
+    SubscriptionDiff {
+        subscribe,
+        unsubscribe,
+    }
+}
+
+/// Applies a subscription diff on reload, leaving unchanged subscriptions alone
+pub async fn apply_subscription_diff(
+    client: &AsyncClient,
+    diff: &SubscriptionDiff,
+    batch_size: usize,
+) -> Result<()> {
+    for topic in &diff.unsubscribe {
+        info!("Unsubscribing from removed topic {}", topic);
+        client.unsubscribe(topic).await?;
+    }
+    for topic in &diff.subscribe {
+        info!("Subscribing to added topic {}", topic.topic);
+    }
+    if !diff.subscribe.is_empty() {
+        subscribe_topics(client, &diff.subscribe, batch_size).await?;
+    }
+    Ok(())
+}
+
+/// Outcome of processing a single message, for embedders that want to react to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessResult {
+    pub topic: String,
+    pub stored: bool,
+    pub error: Option<String>,
+}
+
+pub fn report_result(results: Option<&Sender<ProcessResult>>, topic: &str, result: &Result<bool>) {
+    if let Some(results) = results {
+        let process_result = ProcessResult {
+            topic: topic.to_string(),
+            stored: matches!(result, Ok(true)),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        // Nobody listening anymore is not an error for the monitor itself
+        let _ = results.send(process_result);
+    }
+}
+
+/// Decodes a payload sent with `encoding` to the JSON it carries
+pub fn decode_payload(bytes: &[u8], encoding: PayloadEncoding) -> Result<String> {
+    let bytes = match encoding {
+        PayloadEncoding::Base64 | PayloadEncoding::Base64Gzip => BASE64_STANDARD
+            .decode(bytes.trim_ascii())
+            .context("payload is not valid base64")?,
+        PayloadEncoding::None | PayloadEncoding::Gzip => bytes.to_vec(),
+    };
+    let bytes = match encoding {
+        PayloadEncoding::Gzip | PayloadEncoding::Base64Gzip => {
+            let mut json = Vec::new();
+            GzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut json)
+                .context("payload is not valid gzip")?;
+            json
+        }
+        PayloadEncoding::None | PayloadEncoding::Base64 => bytes,
+    };
+    String::from_utf8(bytes).context("payload is not valid UTF-8")
+}
+
+/// Parses the sensor object found at the JSON pointer `root` in the payload, the whole payload
+/// when `root` is empty
+pub fn parse_sensor_entry(payload: &str, root: &str) -> serde_json::Result<SensorEntry> {
+    if root.is_empty() {
+        return serde_json::from_str(payload);
+    }
+    let mut value: serde_json::Value = serde_json::from_str(payload)?;
+    match value.pointer_mut(root) {
+        Some(entry) => serde_json::from_value(entry.take()),
+        None => Err(serde::de::Error::custom(format!(
+            "payload has nothing at {}",
+            root
+        ))),
+    }
+}
+
+/// Parses and stores a payload, returning whether it belonged to a known device
+pub async fn handle_payload(
+    topic: &str,
+    payload: &str,
+    processor: &Processor<'_>,
+    topic_to_device: &HashMap<String, DeviceContext>,
+) -> Result<bool> {
+    let registered;
+    let device = match find_device(topic_to_device, topic) {
+        Some(device) => match processor.auto_register {
+            Some(auto_register) if !topic_to_device.contains_key(topic) => {
+                match auto_register.device(topic, device).await? {
+                    Some(device) => {
+                        registered = device;
+                        registered.as_ref()
+                    }
+                    None => return Ok(false),
+                }
             }
-            Err(e) => {
-                warn!("Error = {:?}", e);
-                Err(Error::new(e))
+            _ => device,
+        },
+        None => {
+            warn!("Got payload on unknown topic {}", topic);
+            return Ok(false);
+        }
+    };
+    Span::current().record("device_id", device.id);
+    info!("Got payload! {}", payload);
+    // Some firmware prepends a UTF-8 byte order mark, which serde_json rejects
+    let payload = payload.trim_start_matches('\u{feff}').trim();
+    #[cfg(feature = "schema")]
+    if let Some(schema) = processor.payload_schema {
+        let violations = schema.violations(payload);
+        if !violations.is_empty() {
+            let e = anyhow!("payload violates the schema: {}", violations.join("; "));
+            return reject_payload(processor, topic, payload, e);
+        }
+    }
+    let start = Instant::now();
+    let parsed = parse_sensor_entry(payload, &device.payload_root);
+    let elapsed = start.elapsed();
+    histogram!("sensor_payload_parse_duration_seconds", "topic" => processor.labels.value(topic))
+        .record(elapsed.as_secs_f64());
+    let threshold = Duration::from_millis(
+        processor
+            .config
+            .slow_parse_threshold_ms
+            .unwrap_or(DEFAULT_SLOW_PARSE_THRESHOLD_MS),
+    );
+    if elapsed > threshold {
+        warn!(
+            "Parsing {} byte payload on {} took {:?}",
+            payload.len(),
+            topic,
+            elapsed
+        );
+    }
+    if parsed.is_ok() {
+        // Seconds since the epoch, so `time() - sensor_last_seen_timestamp_seconds` is the age
+        gauge!(
+            "sensor_last_seen_timestamp_seconds",
+            "topic" => processor.labels.value(topic),
+            "device" => processor.labels.value(&device.name)
+        )
+        .set(Utc::now().timestamp_millis() as f64 / 1000.0);
+    }
+    match parsed {
+        Ok(_) if !processor.rate_limit.accept(&device.name) => {
+            debug!(
+                "Dropping message from {}, too soon after the last one",
+                device.name
+            );
+            counter!("sensor_messages_ratelimited_total", "device" => processor.labels.value(&device.name))
+                .increment(1);
+            Ok(false)
+        }
+        Ok(sensor) => {
+            store_measurement(processor, topic, sensor, device).await?;
+            Ok(true)
+        }
+        Err(e) => reject_payload(processor, topic, payload, Error::new(e)),
+    }
+}
+
+/// Counts a payload that could not be parsed and dead letters it, or fails with `e` when there
+/// is no dead letter file
+fn reject_payload(processor: &Processor, topic: &str, payload: &str, e: Error) -> Result<bool> {
+    warn!("Error = {:#}", e);
+    counter!("sensor_messages_failed_total", "topic" => processor.labels.value(topic)).increment(1);
+    processor.stats.parse_failure();
+    match &processor.config.dead_letter_file {
+        Some(path) => {
+            deadletter::append(path, &DeadLetter::new(topic, payload, &e))?;
+            Ok(false)
+        }
+        None => Err(e),
+    }
+}
+
+/// Handles a message on the control topic, `pause` stops storing readings until `resume`
+fn handle_control(payload: &[u8], paused: &AtomicBool) {
+    match String::from_utf8_lossy(payload).trim() {
+        "pause" => {
+            if !paused.swap(true, Ordering::SeqCst) {
+                info!("Pausing ingestion");
             }
         }
+        "resume" => {
+            if paused.swap(false, Ordering::SeqCst) {
+                info!("Resuming ingestion");
+            }
+        }
+        command => warn!("Unknown control command {:?}", command),
+    }
+    gauge!("sensor_ingestion_paused").set(if paused.load(Ordering::SeqCst) {
+        1.0
+    } else {
+        0.0
+    });
+}
+
+/// Span every line logged while processing a message is emitted in, so the JSON logs of one
+/// message share its topic and, once it is known, its device id
+pub fn message_span(topic: &str) -> Span {
+    info_span!("message", topic, device_id = field::Empty)
+}
+
+/// Handles a packet, returning whether it was a sensor message that got stored
+pub async fn handle_incomming(
+    inc: Packet,
+    processor: &Processor<'_>,
+    topic_to_device: &HashMap<String, DeviceContext>,
+    results: Option<&Sender<ProcessResult>>,
+) -> Result<bool> {
+    if let Packet::Publish(p) = inc {
+        let span = message_span(&p.topic);
+        handle_publish(p, processor, topic_to_device, results)
+            .instrument(span)
+            .await
     } else {
         info!("Got packet {:?}", inc);
-        Ok(())
+        Ok(false)
     }
 }
 
-pub fn handle_connection(
-    mut connection: Connection,
-    http_client: &Client,
-    device_id: &DeviceId,
-    sensor_ids: &SensorIds,
-    url: &str,
+async fn handle_publish(
+    p: Publish,
+    processor: &Processor<'_>,
+    topic_to_device: &HashMap<String, DeviceContext>,
+    results: Option<&Sender<ProcessResult>>,
+) -> Result<bool> {
+    counter!("sensor_messages_total", "topic" => processor.labels.value(&p.topic)).increment(1);
+    processor.stats.message();
+    if processor.config.control_topic.as_deref() == Some(p.topic.as_str()) {
+        handle_control(&p.payload, processor.paused);
+        return Ok(false);
+    }
+    processor.last_payloads.record(&p.topic, &p.payload);
+    let info_device = topic_to_device
+        .values()
+        .find(|device| device.info_topics.contains(&p.topic));
+    if let Some(device) = info_device {
+        match parse_device_info(&p.payload) {
+            Ok(info) => {
+                let changed = processor
+                    .device_infos
+                    .update(processor.labels, device, info);
+                if let (Some(info), Some(metadata)) = (changed, processor.device_metadata) {
+                    if let Err(e) = metadata.store(device, &info).await {
+                        warn!("{:?}", e);
+                        counter!("sensor_device_info_errors_total").increment(1);
+                    }
+                }
+            }
+            Err(e) => warn!("Invalid INFO payload from {}: {:?}", device.name, e),
+        }
+        return Ok(false);
+    }
+    if p.retain && processor.config.ignore_retained {
+        debug!("Skipping retained message on {}", p.topic);
+        return Ok(false);
+    }
+    let suffix = processor
+        .config
+        .sensor_topic_suffix
+        .as_deref()
+        .unwrap_or(DEFAULT_SENSOR_TOPIC_SUFFIX);
+    // A wildcard topic also matches e.g. stat/<device>/RESULT, topics configured as they are
+    // are always processed
+    if !p.topic.ends_with(suffix) && !topic_to_device.contains_key(&p.topic) {
+        debug!(
+            "Skipping message on {}, it does not end in {}",
+            p.topic, suffix
+        );
+        return Ok(false);
+    }
+    if processor.drop_paused(&p.topic) {
+        return Ok(false);
+    }
+    let encoding = find_device(topic_to_device, &p.topic)
+        .map_or(PayloadEncoding::None, |device| device.payload_encoding);
+    let result = match decode_payload(&p.payload, encoding) {
+        Ok(payload) => handle_payload(&p.topic, &payload, processor, topic_to_device).await,
+        Err(e) => reject_payload(processor, &p.topic, &String::from_utf8_lossy(&p.payload), e),
+    };
+    report_result(results, &p.topic, &result);
+    result
+}
+
+/// Short, bounded description of why a connection failed, used as metric label
+fn connection_error_reason(e: &ConnectionError) -> String {
+    match e {
+        ConnectionError::MqttState(_) => "mqtt_state".to_string(),
+        ConnectionError::NetworkTimeout => "network_timeout".to_string(),
+        ConnectionError::FlushTimeout => "flush_timeout".to_string(),
+        ConnectionError::Io(e) => format!("io_{:?}", e.kind()),
+        ConnectionError::ConnectionRefused(code) => format!("refused_{:?}", code),
+        ConnectionError::NotConnAck(_) => "not_connack".to_string(),
+        ConnectionError::RequestsDone => "requests_done".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// Returned when a broker could not be reconnected to within `max_reconnect_attempts`
+#[derive(Debug)]
+pub struct ReconnectAttemptsExceeded {
+    pub broker: String,
+    pub attempts: u32,
+}
+
+impl std::fmt::Display for ReconnectAttemptsExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "giving up on {} after {} failed reconnect attempts",
+            self.broker, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for ReconnectAttemptsExceeded {}
+
+pub const DEFAULT_RECONNECT_DELAY_MS: u64 = 500;
+pub const DEFAULT_RECONNECT_MAX_DELAY_MS: u64 = 60_000;
+
+/// Delay between reconnect attempts, doubling from `base` with every failed attempt up to `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Connection options for every host of a broker, the primary first, and the one in use
+#[derive(Debug)]
+pub struct Failover {
+    broker: String,
+    hosts: Vec<MqttOptions>,
+    active: usize,
+}
+
+impl Failover {
+    /// `broker` names the broker in logs and metrics, whichever host is in use
+    pub fn new(broker: String, hosts: Vec<MqttOptions>) -> Self {
+        Self {
+            broker,
+            hosts,
+            active: 0,
+        }
+    }
+
+    pub fn broker(&self) -> &str {
+        &self.broker
+    }
+
+    pub fn active(&self) -> &MqttOptions {
+        &self.hosts[self.active]
+    }
+
+    pub fn active_host(&self) -> String {
+        let (host, port) = self.active().broker_address();
+        format!("{}:{}", host, port)
+    }
+
+    /// Moves on to the next host after a failed connection and returns how long to wait before
+    /// connecting to it. The next host is tried right away, only starting over from the first
+    /// host waits for the backoff delay.
+    pub fn on_failure(&mut self, backoff: &mut Backoff) -> Duration {
+        self.active = (self.active + 1) % self.hosts.len();
+        match self.active {
+            0 => backoff.next_delay(),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Messages of a broker processed at the same time, polling the connection waits for one of them
+/// to finish once this many are in flight
+const MAX_IN_FLIGHT_MESSAGES: usize = 16;
+
+/// How long to wait for the DISCONNECT to be sent when shutting down
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports the broker as disconnected however handling its connection ends, a message error
+/// included, so a stopped broker does not keep reporting itself connected
+struct DisconnectedOnDrop<'a> {
+    connection_state: Gauge,
+    health: &'a Health,
+    broker: &'a str,
+}
+
+impl Drop for DisconnectedOnDrop<'_> {
+    fn drop(&mut self) {
+        self.connection_state.set(0);
+        self.health.set_connected(self.broker, false);
+    }
+}
+
+/// Processes events until the connection fails for good, `shutdown` is set or, with `once`, the
+/// first sensor message is stored. Messages are processed while the connection keeps being
+/// polled, and failed connections fail over to the next host of the broker, backing off once all
+/// of them failed. `on_connect` is called on every connection, told whether to resubscribe
+/// because the broker did not keep the session. On shutdown no new events are polled and the
+/// messages in flight are finished.
+pub async fn handle_connection(
+    eventloop: &mut EventLoop,
+    failover: &mut Failover,
+    processor: &Processor<'_>,
+    topic_to_device: &RwLock<HashMap<String, DeviceContext>>,
+    results: Option<&Sender<ProcessResult>>,
+    on_connect: impl AsyncFn(bool) -> Result<()>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    for item in connection.iter() {
-        match item {
+    let name = failover.broker().to_string();
+    let broker = name.as_str();
+    let connection_state = gauge!("mqtt_connection_state", "broker" => broker.to_string());
+    connection_state.set(0);
+    let _disconnected = DisconnectedOnDrop {
+        connection_state: connection_state.clone(),
+        health: processor.health,
+        broker,
+    };
+    info!("Connecting to {} on {}", broker, failover.active_host());
+    let mut failed_attempts = 0;
+    let mut connected_before = false;
+    let mut backoff = Backoff::new(
+        Duration::from_millis(
+            processor
+                .config
+                .reconnect_delay_ms
+                .unwrap_or(DEFAULT_RECONNECT_DELAY_MS),
+        ),
+        Duration::from_millis(
+            processor
+                .config
+                .reconnect_max_delay_ms
+                .unwrap_or(DEFAULT_RECONNECT_MAX_DELAY_MS),
+        ),
+    );
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        let event = tokio::select! {
+            Some(result) = in_flight.next(), if !in_flight.is_empty() => {
+                match result {
+                    Ok(true) if processor.config.once => {
+                        info!("Stored a message from {}, stopping", broker);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) if processor.config.once => return Err(e),
+                    Err(e) => message_failed(broker, &e),
+                }
+                continue;
+            }
+            event = eventloop.poll(), if in_flight.len() < MAX_IN_FLIGHT_MESSAGES => event,
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        match event {
             Ok(event) => match event {
-                Event::Incoming(inc) => {
-                    handle_incomming(inc, http_client, device_id, sensor_ids, url)?
+                Event::Incoming(Packet::ConnAck(ack)) => {
+                    info!(
+                        "Connected to {} on {}, session present {}",
+                        broker,
+                        failover.active_host(),
+                        ack.session_present
+                    );
+                    connection_state.set(1);
+                    processor.health.set_connected(broker, true);
+                    failed_attempts = 0;
+                    backoff.reset();
+                    let resubscribe = connected_before && !ack.session_present;
+                    if resubscribe {
+                        info!("Resubscribing to {}", broker);
+                    }
+                    on_connect(resubscribe).await?;
+                    connected_before = true;
                 }
+                Event::Incoming(Packet::Disconnect) => {
+                    warn!("Disconnected from {} by the broker", broker);
+                    connection_state.set(0);
+                    processor.health.set_connected(broker, false);
+                    counter!(
+                        "mqtt_disconnects_total",
+                        "broker" => broker.to_string(),
+                        "reason" => "disconnect"
+                    )
+                    .increment(1);
+                }
+                Event::Incoming(inc) => in_flight.push(async move {
+                    let topic_to_device = topic_to_device.read().await;
+                    handle_incomming(inc, processor, &topic_to_device, results).await
+                }),
                 Event::Outgoing(out) => {
                     info!("Sending {:?}", out)
                 }
             },
             Err(e) => {
-                warn!("Error = {:?}", e);
+                let reason = connection_error_reason(&e);
+                warn!("Disconnected from {} ({}): {}", broker, reason, e);
+                connection_state.set(0);
+                processor.health.set_connected(broker, false);
+                counter!(
+                    "mqtt_disconnects_total",
+                    "broker" => broker.to_string(),
+                    "reason" => reason
+                )
+                .increment(1);
+                failed_attempts += 1;
+                if let Some(max) = processor.config.max_reconnect_attempts {
+                    if failed_attempts > max {
+                        return Err(ReconnectAttemptsExceeded {
+                            broker: broker.to_string(),
+                            attempts: max,
+                        }
+                        .into());
+                    }
+                }
+                let delay = failover.on_failure(&mut backoff);
+                eventloop.mqtt_options = failover.active().clone();
+                info!(
+                    "Reconnecting to {} on {} in {:?}",
+                    broker,
+                    failover.active_host(),
+                    delay
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                }
             }
         }
     }
+    // The connection is closed by the caller from here on
+    connection_state.set(0);
+    processor.health.set_connected(broker, false);
+    while let Some(result) = in_flight.next().await {
+        match result {
+            Ok(_) => {}
+            Err(e) if processor.config.once => return Err(e),
+            Err(e) => message_failed(broker, &e),
+        }
+    }
+    Ok(())
+}
+
+/// Logs and counts a message that failed to parse or store, the connection carries on with the
+/// messages after it
+fn message_failed(broker: &str, e: &Error) {
+    warn!("Processing a message from {} failed: {:?}", broker, e);
+    counter!("sensor_message_errors_total", "broker" => broker.to_string()).increment(1);
+}
+
+pub const STATUS_ONLINE: &str = "online";
+pub const STATUS_OFFLINE: &str = "offline";
+
+/// Will the broker publishes on the status topic when the connection is lost
+pub fn last_will(status_topic: &str) -> LastWill {
+    LastWill::new(status_topic, STATUS_OFFLINE, QoS::AtLeastOnce, true)
+}
+
+/// Publishes `status` retained on the status topic
+pub async fn announce(client: &AsyncClient, status_topic: &str, status: &str) -> Result<()> {
+    info!("Announcing {} on {}", status, status_topic);
+    client
+        .publish(status_topic, QoS::AtLeastOnce, true, status)
+        .await?;
     Ok(())
 }
+
+/// Sends a DISCONNECT to the broker, giving up after `DISCONNECT_TIMEOUT`
+pub async fn disconnect(client: &AsyncClient, eventloop: &mut EventLoop, broker: &str) {
+    if let Err(e) = client.disconnect().await {
+        warn!("Disconnecting from {} failed: {}", broker, e);
+        return;
+    }
+    let sent = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    })
+    .await;
+    match sent {
+        Ok(true) => info!("Disconnected from {}", broker),
+        _ => warn!("Could not disconnect from {} cleanly", broker),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tracing::Level;
+
+    use super::*;
+    use crate::{
+        retry::RetryPolicy,
+        sink::{BatchFormat, HemrsSink, IdMode},
+        testing::{
+            fixtures::{
+                logged, topic_config, topic_to_device, MockBroker, MockServer, ProcessorState,
+            },
+            InMemorySink,
+        },
+    };
+
+    const TOPIC: &str = "tele/stue/SENSOR";
+    const DHT11: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.5,"Humidity":40.0,"DewPoint":7.5},"TempUnit":"C"}"#;
+
+    struct FailingSink;
+
+    #[async_trait]
+    impl Sink for FailingSink {
+        async fn write_measurement(&self, _: &Measurement) -> Result<()> {
+            Err(anyhow!("hemrs answered 400 Bad Request"))
+        }
+    }
+
+    /// Exported metrics lines starting with `name`
+    fn metric(rendered: &str, name: &str) -> Vec<String> {
+        rendered
+            .lines()
+            .filter(|line| line.starts_with(name))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Measurements stored from `payload` on `TOPIC` of device 1, `stue`
+    async fn stored(config: Config, payload: &str) -> Vec<Measurement> {
+        let state = ProcessorState::new(config);
+        let sink = InMemorySink::new();
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        handle_payload(TOPIC, payload, &state.processor(&sink), &devices)
+            .await
+            .unwrap();
+        sink.measurements()
+    }
+
+    fn values(measurements: &[Measurement]) -> Vec<f64> {
+        measurements
+            .iter()
+            .map(|measurement| measurement.measurement)
+            .collect()
+    }
+
+    #[test]
+    fn counts_messages_and_stored_measurements() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let state = ProcessorState::new(Config::default());
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let sink = InMemorySink::new();
+                let processor = state.processor(&sink);
+                let stored = handle_publish(
+                    Publish::new(TOPIC, QoS::AtMostOnce, DHT11),
+                    &processor,
+                    &devices,
+                    None,
+                )
+                .await;
+                assert!(stored.unwrap());
+                assert_eq!(sink.measurements().len(), 3);
+                let unparsable = Publish::new(TOPIC, QoS::AtMostOnce, "{");
+                assert!(handle_publish(unparsable, &processor, &devices, None)
+                    .await
+                    .is_err());
+
+                // Measurements the sink fails to store are not counted as posted
+                let processor = state.processor(&FailingSink);
+                assert!(handle_publish(
+                    Publish::new(TOPIC, QoS::AtMostOnce, DHT11),
+                    &processor,
+                    &devices,
+                    None,
+                )
+                .await
+                .is_err());
+            })
+        });
+
+        let rendered = handle.render();
+        assert_eq!(
+            metric(&rendered, "sensor_messages_total"),
+            [format!("sensor_messages_total{{topic=\"{}\"}} 3", TOPIC)]
+        );
+        assert_eq!(
+            metric(&rendered, "sensor_messages_failed_total"),
+            [format!(
+                "sensor_messages_failed_total{{topic=\"{}\"}} 1",
+                TOPIC
+            )]
+        );
+        assert_eq!(
+            metric(&rendered, "sensor_measurements_posted_total"),
+            ["sensor_measurements_posted_total 3"]
+        );
+        assert!(metric(&rendered, "sensor_last_value").contains(
+            &"sensor_last_value{device=\"stue\",sensor=\"dht11_temperature\"} 21.5".to_string()
+        ));
+        assert!(state.stats.summary().contains("posted 3 measurements"));
+    }
+
+    #[test]
+    fn parses_time_with_and_without_offset() {
+        let time = |time: &str| de_flexible_time(serde_json::Value::from(time));
+        let expected: DateTime<Utc> = "2026-10-15T08:00:00Z".parse().unwrap();
+        for written in [
+            "2026-10-15T08:00:00",
+            "2026-10-15T08:00:00Z",
+            "2026-10-15T10:00:00+02:00",
+            "2026-10-15T03:00:00-05:00",
+        ] {
+            assert_eq!(time(written).unwrap(), expected, "{}", written);
+        }
+        assert_eq!(
+            time("2026-10-15T08:00:00.250").unwrap(),
+            expected + chrono::Duration::milliseconds(250)
+        );
+        assert!(time("15.10.2026 08:00").is_err());
+    }
+
+    #[test]
+    fn diffs_only_changed_subscriptions() {
+        let mut requalified = topic_config("tele/b/SENSOR", "b");
+        requalified.qos = 1;
+        let old = [
+            topic_config("tele/a/SENSOR", "a"),
+            topic_config("tele/b/SENSOR", "b"),
+            topic_config("tele/c/SENSOR", "c"),
+        ];
+        let new = [
+            topic_config("tele/a/SENSOR", "a"),
+            requalified.clone(),
+            topic_config("tele/d/SENSOR", "d"),
+        ];
+
+        let diff = diff_subscriptions(&old, &new);
+        assert_eq!(
+            diff.subscribe,
+            [requalified, topic_config("tele/d/SENSOR", "d")]
+        );
+        assert_eq!(diff.unsubscribe, ["tele/c/SENSOR"]);
+
+        let unchanged = diff_subscriptions(&old, &old);
+        assert!(unchanged.subscribe.is_empty() && unchanged.unsubscribe.is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), Duration::from_secs(10));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn matches_topics_against_wildcards() {
+        assert!(match_topic("tele/+/SENSOR", "tele/kitchen/SENSOR"));
+        assert!(!match_topic("tele/+/SENSOR", "tele/kitchen/STATE"));
+        assert!(!match_topic("tele/+/SENSOR", "tele/kitchen/sub/SENSOR"));
+        assert!(match_topic("tele/#", "tele/kitchen/SENSOR"));
+        assert!(match_topic("tele/#", "tele"));
+        assert!(match_topic("#", "tele/kitchen/SENSOR"));
+        assert!(!match_topic("#", "$SYS/broker/uptime"));
+        assert!(match_topic("tele/kitchen/SENSOR", "tele/kitchen/SENSOR"));
+        assert!(!match_topic("tele/kitchen", "tele/kitchen/SENSOR"));
+    }
+
+    #[test]
+    fn finds_the_most_specific_device() {
+        let mut devices = topic_to_device("tele/#", 1, "any");
+        devices.extend(topic_to_device("tele/+/SENSOR", 2, "sensors"));
+        devices.extend(topic_to_device("tele/stue/SENSOR", 3, "stue"));
+        let id = |topic: &str| find_device(&devices, topic).map(|device| device.id);
+
+        assert_eq!(id("tele/stue/SENSOR"), Some(3));
+        assert_eq!(id("tele/kitchen/SENSOR"), Some(2));
+        assert_eq!(id("tele/kitchen/STATE"), Some(1));
+        assert_eq!(id("stat/kitchen/SENSOR"), None);
+    }
+
+    #[test]
+    fn rounds_half_to_even() {
+        assert_eq!(round_half_even(22.54999, 2), 22.55);
+        assert_eq!(round_half_even(22.55, 1), 22.6);
+        assert_eq!(round_half_even(22.65, 1), 22.6);
+        assert_eq!(round_half_even(45.74, 1), 45.7);
+        assert_eq!(round_half_even(2.5, 0), 2.0);
+        assert_eq!(round_half_even(-2.5, 0), -2.0);
+        assert_eq!(round_half_even(21.0, 3), 21.0);
+    }
+
+    #[tokio::test]
+    async fn rounds_stored_values_only_when_configured() {
+        const PAYLOAD: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":22.55,"Humidity":45.7,"DewPoint":9.95},"TempUnit":"C"}"#;
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let stored = |config: Config| async {
+            let state = ProcessorState::new(config);
+            let sink = InMemorySink::new();
+            handle_payload(TOPIC, PAYLOAD, &state.processor(&sink), &devices)
+                .await
+                .unwrap();
+            sink.measurements()
+                .iter()
+                .map(|measurement| measurement.measurement)
+                .collect::<Vec<f64>>()
+        };
+
+        let values = [22.55f32, 45.7, 9.95].map(f64::from);
+        assert_eq!(stored(Config::default()).await, values);
+        let config = Config {
+            round_decimals: Some(1),
+            ..Config::default()
+        };
+        assert_eq!(stored(config).await, [22.6, 45.7, 10.0]);
+    }
+
+    #[test]
+    fn computes_the_dew_point_of_known_readings() {
+        assert!((dew_point(20.0, 50.0) - 9.26).abs() < 0.05);
+        assert!((dew_point(30.0, 80.0) - 26.17).abs() < 0.05);
+        assert!((dew_point(25.0, 100.0) - 25.0).abs() < 0.05);
+    }
+
+    #[tokio::test]
+    async fn computes_the_dew_point_from_calibrated_readings() {
+        const PAYLOAD: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.5,"Humidity":46.0},"TempUnit":"C"}"#;
+        let state = ProcessorState::new(Config {
+            compute_dew_point: true,
+            ..Config::default()
+        });
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        devices.get_mut(TOPIC).unwrap().offsets = HashMap::from([
+            ("dht11_temperature".to_string(), -1.5),
+            ("dht11_humidity".to_string(), 4.0),
+        ]);
+        handle_payload(TOPIC, PAYLOAD, &state.processor(&sink), &devices)
+            .await
+            .unwrap();
+
+        let values: Vec<f64> = sink
+            .measurements()
+            .iter()
+            .map(|measurement| measurement.measurement)
+            .collect();
+        let expected = [20.0, 50.0, dew_point(20.0, 50.0)].map(f64::from);
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn reads_numbers_and_numeric_strings() {
+        let value = |value: serde_json::Value| de_f32_flexible(value);
+        assert_eq!(value(serde_json::json!(22.5)).unwrap(), 22.5);
+        assert_eq!(value(serde_json::json!("22.5")).unwrap(), 22.5);
+        assert_eq!(value(serde_json::json!(" -3 ")).unwrap(), -3.0);
+        assert!(value(serde_json::json!("warm")).is_err());
+        assert!(value(serde_json::json!(null)).is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_quoted_readings() {
+        const QUOTED: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":"21.5","Humidity":"40.0","DewPoint":7.5},"TempUnit":"C"}"#;
+        let state = ProcessorState::new(Config::default());
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let sink = InMemorySink::new();
+        handle_payload(TOPIC, QUOTED, &state.processor(&sink), &devices)
+            .await
+            .unwrap();
+        let values: Vec<f64> = sink
+            .measurements()
+            .iter()
+            .map(|measurement| measurement.measurement)
+            .collect();
+        assert_eq!(values, [21.5, 40.0, 7.5]);
+    }
+
+    #[test]
+    fn decodes_each_payload_encoding() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gzip, DHT11.as_bytes()).unwrap();
+        let gzipped = gzip.finish().unwrap();
+        let encoded = [
+            (PayloadEncoding::None, DHT11.as_bytes().to_vec()),
+            (
+                PayloadEncoding::Base64,
+                BASE64_STANDARD.encode(DHT11).into_bytes(),
+            ),
+            (PayloadEncoding::Gzip, gzipped.clone()),
+            (
+                PayloadEncoding::Base64Gzip,
+                format!("{}\n", BASE64_STANDARD.encode(&gzipped)).into_bytes(),
+            ),
+        ];
+        for (encoding, bytes) in encoded {
+            assert_eq!(
+                decode_payload(&bytes, encoding).unwrap(),
+                DHT11,
+                "{:?}",
+                encoding
+            );
+        }
+
+        let error =
+            |bytes: &[u8], encoding| format!("{:#}", decode_payload(bytes, encoding).unwrap_err());
+        assert!(error(b"not base64!", PayloadEncoding::Base64).contains("base64"));
+        assert!(error(DHT11.as_bytes(), PayloadEncoding::Gzip).contains("gzip"));
+        assert!(error(&[0xff, 0xfe], PayloadEncoding::None).contains("UTF-8"));
+    }
+
+    #[test]
+    fn reads_sht3x_with_and_without_dew_point() {
+        let entry = parse_sensor_entry(
+            r#"{"SHT3X":{"Temperature":22.1,"Humidity":48.5,"DewPoint":10.6},"TempUnit":"C"}"#,
+            "",
+        )
+        .unwrap();
+        let sht3x = entry.sht3x.unwrap();
+        assert_eq!(
+            (sht3x.temperature, sht3x.humidity, sht3x.dew_point),
+            (22.1, 48.5, Some(10.6))
+        );
+
+        let entry =
+            parse_sensor_entry(r#"{"SHT3X":{"Temperature":"22.1","Humidity":48.5}}"#, "").unwrap();
+        let sht3x = entry.sht3x.unwrap();
+        assert_eq!(
+            (sht3x.temperature, sht3x.humidity, sht3x.dew_point),
+            (22.1, 48.5, None)
+        );
+    }
+
+    #[test]
+    fn warns_about_missing_sht3x_only_when_listed() {
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        let (stored, warnings) = logged(
+            Level::WARN,
+            handle_payload(TOPIC, DHT11, &state.processor(&sink), &devices),
+        );
+        assert!(stored.unwrap());
+        assert!(
+            warnings.iter().all(|line| !line.contains("SHT3X")),
+            "{warnings:?}"
+        );
+
+        devices.get_mut(TOPIC).unwrap().expected_sensors = vec!["SHT3X".to_string()];
+        let (_, warnings) = logged(
+            Level::WARN,
+            handle_payload(TOPIC, DHT11, &state.processor(&sink), &devices),
+        );
+        assert!(warnings
+            .iter()
+            .any(|line| line.contains("Unable to process SHT3X")));
+    }
+
+    #[tokio::test]
+    async fn converts_fahrenheit_to_celsius() {
+        assert_eq!(fahrenheit_to_celsius(32.0), 0.0);
+        assert_eq!(fahrenheit_to_celsius(212.0), 100.0);
+        assert_eq!(fahrenheit_to_celsius(-40.0), -40.0);
+
+        const FAHRENHEIT: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":212.0,"Humidity":40.0,"DewPoint":32.0},"TempUnit":"F"}"#;
+        // Humidity is not a temperature and is left as it is
+        assert_eq!(
+            values(&stored(Config::default(), FAHRENHEIT).await),
+            [100.0, 40.0, 0.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn computes_comfort_metrics_of_known_readings() {
+        let close = |value: f32, expected: f32| (value - expected).abs() < 0.1;
+        // 90 °F at 70 % feels like 106 °F in the NWS heat index table
+        assert!(close(heat_index(32.22, 70.0), 41.07));
+        // Below 80 °F the simple formula is used
+        assert!(close(heat_index(20.0, 50.0), 19.36));
+        assert!(close(absolute_humidity(20.0, 50.0), 8.64));
+        assert!(close(absolute_humidity(25.0, 60.0), 13.82));
+        assert!(close(absolute_humidity(0.0, 100.0), 4.85));
+
+        let config = Config {
+            compute_comfort_metrics: true,
+            ..Config::default()
+        };
+        let measurements = stored(config, DHT11).await;
+        let sensors: Vec<&str> = measurements
+            .iter()
+            .map(|measurement| measurement.sensor_name.as_str())
+            .collect();
+        assert_eq!(
+            sensors,
+            [
+                "DHT11 Temperature",
+                "DHT11 Humidity",
+                "DHT11 Dew Point",
+                "DHT11 Heat Index",
+                "DHT11 Absolute Humidity"
+            ]
+        );
+        assert_eq!(
+            values(&measurements)[3..],
+            [heat_index(21.5, 40.0), absolute_humidity(21.5, 40.0)].map(f64::from)
+        );
+    }
+
+    #[tokio::test]
+    async fn serializes_units_only_when_sent() {
+        let measurement = &stored(Config::default(), DHT11).await[0];
+        assert_eq!(
+            serde_json::to_value(measurement).unwrap(),
+            serde_json::json!({"device": 1, "sensor": 0, "measurement": 21.5})
+        );
+
+        let config = Config {
+            send_units: true,
+            ..Config::default()
+        };
+        let measurements = stored(config, DHT11).await;
+        let json: Vec<serde_json::Value> = measurements
+            .iter()
+            .map(|measurement| serde_json::to_value(measurement).unwrap())
+            .collect();
+        assert_eq!(
+            json[0],
+            serde_json::json!({"device": 1, "sensor": 0, "measurement": 21.5, "unit": "°C"})
+        );
+        assert_eq!(json[1]["unit"], "%");
+    }
+
+    #[tokio::test]
+    async fn tags_measurements_with_the_instance() {
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let mut processor = state.processor(&sink);
+        processor.instance_id = Some("monitor-a".to_string());
+        handle_payload(TOPIC, DHT11, &processor, &devices)
+            .await
+            .unwrap();
+        for measurement in sink.measurements() {
+            assert_eq!(measurement.instance.as_deref(), Some("monitor-a"));
+        }
+        assert_eq!(
+            serde_json::to_value(&sink.measurements()[0]).unwrap(),
+            serde_json::json!({"device": 1, "sensor": 0, "measurement": 21.5, "instance": "monitor-a"})
+        );
+
+        let untagged = &stored(Config::default(), DHT11).await[0];
+        assert!(serde_json::to_value(untagged)
+            .unwrap()
+            .get("instance")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stores_payloads_starting_with_a_byte_order_mark() {
+        let with_bom = format!("\u{feff}{DHT11}\n");
+        assert_eq!(
+            values(&stored(Config::default(), &with_bom).await),
+            [21.5, 40.0, 7.5]
+        );
+        // Decoding keeps the mark, it is stripped before parsing
+        let decoded = decode_payload(with_bom.as_bytes(), PayloadEncoding::None).unwrap();
+        assert!(decoded.starts_with('\u{feff}'));
+    }
+
+    #[tokio::test]
+    async fn stores_payloads_without_time_only_when_allowed() {
+        const UNSTAMPED: &str =
+            r#"{"DHT11":{"Temperature":21.5,"Humidity":40.0,"DewPoint":7.5},"TempUnit":"C"}"#;
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let error = handle_payload(TOPIC, UNSTAMPED, &state.processor(&sink), &devices)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "payload from stue has no Time");
+        assert!(sink.measurements().is_empty());
+
+        let config = Config {
+            allow_missing_time: true,
+            send_timestamps: true,
+            ..Config::default()
+        };
+        let measurements = stored(config, UNSTAMPED).await;
+        assert_eq!(values(&measurements), [21.5, 40.0, 7.5]);
+        // Stored at the time they are received, which hemrs fills in
+        assert!(measurements.iter().all(|m| m.timestamp.is_none()));
+    }
+
+    #[test]
+    fn warns_when_the_sensors_differ_from_the_expected_ones() {
+        let state = ProcessorState::new(Config {
+            validate_expected_sensors: true,
+            ..Config::default()
+        });
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        let mut warnings_expecting = |expected: &[&str]| {
+            devices.get_mut(TOPIC).unwrap().expected_sensors =
+                expected.iter().map(|sensor| sensor.to_string()).collect();
+            let (stored, warnings) = logged(
+                Level::WARN,
+                handle_payload(TOPIC, DHT11, &state.processor(&sink), &devices),
+            );
+            assert!(stored.unwrap());
+            warnings
+                .into_iter()
+                .filter(|line| line.contains("reported sensors"))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(warnings_expecting(&["DHT11"]).is_empty());
+        assert!(warnings_expecting(&[]).is_empty());
+        let warnings = warnings_expecting(&["DS18B20"]);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(
+            warnings[0].contains(r#"stue reported sensors ["DHT11"], expected ["DS18B20"]"#),
+            "{warnings:?}"
+        );
+        let warnings = warnings_expecting(&["DHT11", "DS18B20"]);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+    }
+
+    #[tokio::test]
+    async fn adds_the_offsets_of_each_sensor() {
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        devices.get_mut(TOPIC).unwrap().offsets = HashMap::from([
+            ("dht11_temperature".to_string(), 0.5),
+            ("dht11_humidity".to_string(), -6.0),
+            ("ds18b20".to_string(), 10.0),
+        ]);
+        handle_payload(TOPIC, DHT11, &state.processor(&sink), &devices)
+            .await
+            .unwrap();
+        // The dew point has no offset and is stored as sent
+        assert_eq!(values(&sink.measurements()), [22.0, 34.0, 7.5]);
+    }
+
+    #[test]
+    fn does_not_warn_about_sensors_a_device_lacks() {
+        const DS18B20: &str = r#"{"Time":"2026-10-15T08:00:00","DS18B20":{"Id":"01193B2D7E6B","Temperature":19.5},"TempUnit":"C"}"#;
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        devices.get_mut(TOPIC).unwrap().expected_sensors = vec!["DS18B20".to_string()];
+        let (stored, warnings) = logged(
+            Level::WARN,
+            handle_payload(TOPIC, DS18B20, &state.processor(&sink), &devices),
+        );
+        assert!(stored.unwrap());
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(values(&sink.measurements()), [19.5]);
+
+        // Without a list every built in sensor is expected
+        devices.get_mut(TOPIC).unwrap().expected_sensors.clear();
+        let (_, warnings) = logged(
+            Level::WARN,
+            handle_payload(TOPIC, DS18B20, &state.processor(&sink), &devices),
+        );
+        assert!(
+            warnings
+                .iter()
+                .any(|line| line.contains("Unable to process DHT11")),
+            "{warnings:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_energy_readings() {
+        const PLUG: &str = r#"{"Time":"2026-10-15T08:00:00","ENERGY":{"TotalStartTime":"2026-01-01T00:00:00","Total":12.345,"Yesterday":0.5,"Today":0.2,"Power":45,"ApparentPower":50,"Factor":0.9,"Voltage":230,"Current":0.196}}"#;
+        let entry = parse_sensor_entry(PLUG, "").unwrap();
+        let energy = entry.energy.unwrap();
+        assert_eq!(
+            (energy.voltage, energy.current, energy.power, energy.total),
+            (Some(230.0), Some(0.196), Some(45.0), Some(12.345))
+        );
+        // Plugs without a temperature sensor leave out TempUnit
+        assert_eq!(entry.temp_unit, "C");
+
+        let measurements = stored(Config::default(), PLUG).await;
+        let sensors: Vec<&str> = measurements
+            .iter()
+            .map(|measurement| measurement.sensor_name.as_str())
+            .collect();
+        assert_eq!(
+            sensors,
+            [
+                "ENERGY Voltage",
+                "ENERGY Current",
+                "ENERGY Power",
+                "ENERGY Total"
+            ]
+        );
+
+        // Readings a plug does not report are skipped
+        const PARTIAL: &str = r#"{"Time":"2026-10-15T08:00:00","ENERGY":{"Power":45}}"#;
+        assert_eq!(values(&stored(Config::default(), PARTIAL).await), [45.0]);
+    }
+
+    #[tokio::test]
+    async fn reads_bme280_readings() {
+        const BME280: &str = r#"{"Time":"2026-10-15T08:00:00","BME280":{"Temperature":22.3,"Humidity":41.2,"DewPoint":8.4,"Pressure":1013.2},"PressureUnit":"hPa","TempUnit":"C"}"#;
+        let bme280 = parse_sensor_entry(BME280, "").unwrap().bme280.unwrap();
+        assert_eq!(
+            (
+                bme280.temperature,
+                bme280.humidity,
+                bme280.pressure,
+                bme280.dew_point
+            ),
+            (22.3, 41.2, 1013.2, Some(8.4))
+        );
+
+        let measurements = stored(Config::default(), BME280).await;
+        let stored: Vec<(&str, f64)> = measurements
+            .iter()
+            .map(|m| (m.sensor_name.as_str(), m.measurement))
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                ("BME280 Temperature", f64::from(22.3f32)),
+                ("BME280 Humidity", f64::from(41.2f32)),
+                ("BME280 Pressure", f64::from(1013.2f32)),
+                ("BME280 Dew Point", f64::from(8.4f32)),
+            ]
+        );
+
+        // Older firmware leaves out the dew point
+        let without = parse_sensor_entry(
+            r#"{"BME280":{"Temperature":22.3,"Humidity":41.2,"Pressure":1013.2}}"#,
+            "",
+        )
+        .unwrap();
+        assert_eq!(without.bme280.unwrap().dew_point, None);
+        assert!(parse_sensor_entry(r#"{"BME280":{"Temperature":22.3}}"#, "").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_sensors_at_the_payload_root() {
+        let nested = format!(r#"{{"StatusSNS":{DHT11}}}"#);
+        let flat = parse_sensor_entry(DHT11, "").unwrap();
+        let rooted = parse_sensor_entry(&nested, "/StatusSNS").unwrap();
+        assert_eq!(flat.sensors(), ["DHT11"]);
+        assert_eq!(rooted.sensors(), ["DHT11"]);
+        assert_eq!(rooted.time, flat.time);
+
+        // Without the root the nested payload has no sensors, and no Time either
+        assert!(parse_sensor_entry(&nested, "")
+            .unwrap()
+            .sensors()
+            .is_empty());
+        let error = parse_sensor_entry(DHT11, "/StatusSNS").unwrap_err();
+        assert_eq!(error.to_string(), "payload has nothing at /StatusSNS");
+
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        devices.get_mut(TOPIC).unwrap().payload_root = "/StatusSNS".to_string();
+        handle_payload(TOPIC, &nested, &state.processor(&sink), &devices)
+            .await
+            .unwrap();
+        assert_eq!(values(&sink.measurements()), [21.5, 40.0, 7.5]);
+    }
+
+    #[tokio::test]
+    async fn skips_retained_messages_when_configured() {
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let retained = || {
+            let mut publish = Publish::new(TOPIC, QoS::AtMostOnce, DHT11);
+            publish.retain = true;
+            publish
+        };
+        for (ignore_retained, expected) in [(true, 0), (false, 3)] {
+            let state = ProcessorState::new(Config {
+                ignore_retained,
+                ..Config::default()
+            });
+            let sink = InMemorySink::new();
+            let processor = state.processor(&sink);
+            let stored = handle_publish(retained(), &processor, &devices, None).await;
+            assert_eq!(stored.unwrap(), expected > 0);
+            assert_eq!(sink.measurements().len(), expected);
+
+            // Live messages are always processed
+            let live = Publish::new(TOPIC, QoS::AtMostOnce, DHT11);
+            assert!(handle_publish(live, &processor, &devices, None)
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_result_messages_on_wildcard_topics() {
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let processor = state.processor(&sink);
+        let mut devices = topic_to_device("+/stue/+", 1, "stue");
+        let publish = |topic: &str| Publish::new(topic, QoS::AtMostOnce, DHT11);
+
+        let result = handle_publish(publish("stat/stue/RESULT"), &processor, &devices, None).await;
+        assert!(!result.unwrap());
+        assert!(sink.measurements().is_empty());
+        assert!(handle_publish(publish(TOPIC), &processor, &devices, None)
+            .await
+            .unwrap());
+        assert_eq!(sink.measurements().len(), 3);
+
+        // A topic configured as it is is processed whatever it ends in
+        devices.extend(topic_to_device("stat/stue/RESULT", 1, "stue"));
+        assert!(
+            handle_publish(publish("stat/stue/RESULT"), &processor, &devices, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    /// Runs `handle_connection` against `broker` subscribed to `TOPIC`, until it returns or
+    /// `shutdown` is set
+    async fn connection(
+        broker: &MockBroker,
+        processor: &Processor<'_>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let options = MqttOptions::new("sensor_monitor_test", "127.0.0.1", broker.port);
+        let (client, mut eventloop) = AsyncClient::new(options.clone(), 10);
+        client.subscribe(TOPIC, QoS::AtMostOnce).await?;
+        let mut failover = Failover::new("mock".to_string(), vec![options]);
+        let devices = RwLock::new(topic_to_device(TOPIC, 1, "stue"));
+        handle_connection(
+            &mut eventloop,
+            &mut failover,
+            processor,
+            &devices,
+            None,
+            async |_| Ok(()),
+            shutdown,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn stops_after_the_first_message_with_once() {
+        let state = ProcessorState::new(Config {
+            once: true,
+            ..Config::default()
+        });
+        let sink = InMemorySink::new();
+        let processor = state.processor(&sink);
+        let (_shutdown, stop) = watch::channel(false);
+
+        // Returns by itself once the message is stored, there is no shutdown
+        let broker = MockBroker::start(&[(TOPIC, DHT11)]);
+        let connected = connection(&broker, &processor, stop.clone());
+        tokio::time::timeout(Duration::from_secs(10), connected)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(values(&sink.measurements()), [21.5, 40.0, 7.5]);
+
+        // and fails when the message does
+        let broker = MockBroker::start(&[(TOPIC, "{")]);
+        let connected = connection(&broker, &processor, stop);
+        let result = tokio::time::timeout(Duration::from_secs(10), connected)
+            .await
+            .unwrap();
+        assert!(result.is_err());
+        assert_eq!(sink.measurements().len(), 3);
+    }
+
+    #[test]
+    fn records_post_durations_after_processing() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let state = ProcessorState::new(Config::default());
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // The DHT11 readings are stored, then hemrs fails the next message
+        let server = MockServer::start(&[200, 200, 200, 400]);
+        let sink = HemrsSink::new(
+            reqwest::Client::new(),
+            &server.url,
+            IdMode::Numeric,
+            None,
+            BatchFormat::Json,
+            false,
+            RetryPolicy::new(0, Duration::ZERO),
+        );
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let processor = state.processor(&sink);
+                assert!(handle_payload(TOPIC, DHT11, &processor, &devices)
+                    .await
+                    .unwrap());
+                assert!(handle_payload(TOPIC, DHT11, &processor, &devices)
+                    .await
+                    .is_err());
+            })
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains("# TYPE hemrs_post_duration_seconds summary"));
+        assert!(
+            metric(&rendered, "hemrs_post_duration_seconds_count")
+                .contains(&"hemrs_post_duration_seconds_count{outcome=\"success\"} 3".to_string()),
+            "{rendered}"
+        );
+        assert!(
+            metric(&rendered, "hemrs_post_duration_seconds_count")
+                .contains(&"hemrs_post_duration_seconds_count{outcome=\"failure\"} 1".to_string()),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn connection_gauge_drops_to_zero_on_disconnect() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        // Stays disconnected until the test shuts down
+        let state = ProcessorState::new(Config {
+            reconnect_delay_ms: Some(60_000),
+            ..Config::default()
+        });
+        let sink = InMemorySink::new();
+        let broker = MockBroker::start_closing(&[]);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let connected = |value: u8| {
+            let gauge = format!("mqtt_connection_state{{broker=\"mock\"}} {value}");
+            let handle = &handle;
+            async move {
+                while !metric(&handle.render(), "mqtt_connection_state").contains(&gauge) {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let options = MqttOptions::new("sensor_monitor_test", "127.0.0.1", broker.port);
+                let (client, mut eventloop) = AsyncClient::new(options.clone(), 10);
+                let mut failover = Failover::new("mock".to_string(), vec![options]);
+                let devices = RwLock::new(HashMap::new());
+                let (shutdown, stop) = watch::channel(false);
+                let processor = state.processor(&sink);
+                let connection = handle_connection(
+                    &mut eventloop,
+                    &mut failover,
+                    &processor,
+                    &devices,
+                    None,
+                    async |_| Ok(()),
+                    stop,
+                );
+                let observe = async {
+                    connected(1).await;
+                    // The broker drops the connection on this
+                    client
+                        .publish("tele/stue/STATE", QoS::AtMostOnce, false, "{}")
+                        .await
+                        .unwrap();
+                    connected(0).await;
+                    shutdown.send(true).unwrap();
+                };
+                let (result, ()) = tokio::time::timeout(
+                    Duration::from_secs(10),
+                    futures::future::join(connection, observe),
+                )
+                .await
+                .unwrap();
+                result.unwrap();
+            })
+        });
+
+        assert!(metric(&handle.render(), "mqtt_disconnects_total")[0]
+            .starts_with("mqtt_disconnects_total{broker=\"mock\",reason="));
+    }
+
+    #[test]
+    fn logs_a_message_within_its_span() {
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let devices = topic_to_device(TOPIC, 7, "stue");
+        let publish = Packet::Publish(Publish::new(TOPIC, QoS::AtMostOnce, DHT11));
+        let (stored, lines) = logged(
+            Level::INFO,
+            handle_incomming(publish, &state.processor(&sink), &devices, None),
+        );
+        assert!(stored.unwrap());
+        let span = format!(r#"message{{topic="{TOPIC}" device_id=7}}: "#);
+        assert!(lines.len() >= 2, "{lines:?}");
+        assert!(lines.iter().all(|line| line.contains(&span)), "{lines:?}");
+
+        // Until a device is found only the topic is known
+        let unknown = Packet::Publish(Publish::new("tele/gang/SENSOR", QoS::AtMostOnce, DHT11));
+        let (_, lines) = logged(
+            Level::INFO,
+            handle_incomming(unknown, &state.processor(&sink), &devices, None),
+        );
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(
+            lines[0].contains(r#"message{topic="tele/gang/SENSOR"}: "#),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn records_when_a_device_was_last_seen() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut devices = topic_to_device(TOPIC, 1, "stue");
+        devices.extend(topic_to_device("tele/gang/SENSOR", 2, "gang"));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let before = Utc::now().timestamp_millis() as f64 / 1000.0;
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let processor = state.processor(&sink);
+                handle_payload(TOPIC, DHT11, &processor, &devices)
+                    .await
+                    .unwrap();
+                // Unparsable payloads do not count as seen
+                assert!(
+                    handle_payload("tele/gang/SENSOR", "{", &processor, &devices)
+                        .await
+                        .is_err()
+                );
+            })
+        });
+        let after = Utc::now().timestamp_millis() as f64 / 1000.0;
+
+        let last_seen = metric(&handle.render(), "sensor_last_seen_timestamp_seconds");
+        assert_eq!(last_seen.len(), 1, "{last_seen:?}");
+        let (labels, seen) = last_seen[0].rsplit_once(' ').unwrap();
+        assert_eq!(
+            labels,
+            format!(r#"sensor_last_seen_timestamp_seconds{{topic="{TOPIC}",device="stue"}}"#)
+        );
+        let seen: f64 = seen.parse().unwrap();
+        assert!((before..=after).contains(&seen), "{before} {seen} {after}");
+    }
+}
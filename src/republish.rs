@@ -0,0 +1,102 @@
+use metrics::counter;
+use rumqttc::{AsyncClient, QoS};
+use tracing::{debug, warn};
+
+use crate::mqtt::Measurement;
+
+pub const DEFAULT_REPUBLISH_TOPIC: &str = "sensor_monitor/{device}/{sensor}";
+
+/// Publishes every stored measurement back to MQTT, retained, so dashboards like Home Assistant
+/// can subscribe to the normalized values
+pub struct Republisher {
+    client: AsyncClient,
+    /// Topic with `{device}` and `{sensor}` replaced by the device and sensor name
+    template: String,
+}
+
+impl Republisher {
+    pub fn new(client: AsyncClient, template: String) -> Self {
+        Self { client, template }
+    }
+
+    pub fn topic(&self, measurement: &Measurement) -> String {
+        self.template
+            .replace("{device}", &measurement.device_name)
+            .replace("{sensor}", &measurement.sensor_name)
+    }
+
+    /// Queues the value without waiting, a full request queue drops it rather than holding up
+    /// the message
+    pub fn publish(&self, measurement: &Measurement) {
+        let topic = self.topic(measurement);
+        // As f32 it prints at the precision it was read with, 21.7 rather than 21.700000762939453
+        let value = (measurement.measurement as f32).to_string();
+        debug!("Republishing {} on {}", value, topic);
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtMostOnce, true, value)
+        {
+            warn!("Republishing on {} failed: {}", topic, e);
+            counter!("sensor_republish_failed_total").increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rumqttc::MqttOptions;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        mqtt::handle_payload,
+        testing::{
+            fixtures::{topic_to_device, MockBroker, ProcessorState, Published},
+            InMemorySink,
+        },
+    };
+
+    const TOPIC: &str = "tele/stue/SENSOR";
+    const DHT11: &str = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.7,"Humidity":40.0,"DewPoint":7.5},"TempUnit":"C"}"#;
+
+    #[tokio::test]
+    async fn republishes_every_stored_measurement_retained() {
+        let broker = MockBroker::start(&[]);
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("republish", "127.0.0.1", broker.port), 16);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let republisher = Republisher::new(client, "home/{device}/{sensor}/state".to_string());
+
+        let state = ProcessorState::new(Config::default());
+        let sink = InMemorySink::new();
+        let mut processor = state.processor(&sink);
+        processor.republisher = Some(&republisher);
+        let devices = topic_to_device(TOPIC, 1, "stue");
+        handle_payload(TOPIC, DHT11, &processor, &devices)
+            .await
+            .unwrap();
+
+        let expected: Vec<Published> = sink
+            .measurements()
+            .iter()
+            .map(|measurement| Published {
+                topic: republisher.topic(measurement),
+                payload: (measurement.measurement as f32).to_string(),
+                retain: true,
+            })
+            .collect();
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected[0].topic, "home/stue/DHT11 Temperature/state");
+        assert_eq!(expected[0].payload, "21.7");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while broker.published().len() < expected.len() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("measurements were not republished in time");
+        assert_eq!(broker.published(), expected);
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use metrics::counter;
+use reqwest::{RequestBuilder, Response};
+use tracing::warn;
+
+/// How often a failed hemrs request is retried and how long to wait between attempts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self { retries, delay }
+    }
+
+    /// Sends the request, retrying connection errors, timeouts and 5xx responses. Any other
+    /// response, 4xx included, is returned right away for the caller to handle.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            // Streaming bodies cannot be cloned and are only sent once
+            let Some(current) = request.try_clone() else {
+                return request.send().await;
+            };
+            let reason = match current.send().await {
+                Ok(response) if response.status().is_server_error() && attempt < self.retries => {
+                    format!("status {}", response.status())
+                }
+                Err(e)
+                    if (e.is_connect() || e.is_timeout() || e.is_request())
+                        && attempt < self.retries =>
+                {
+                    e.to_string()
+                }
+                outcome => return outcome,
+            };
+            attempt += 1;
+            warn!(
+                "hemrs request failed with {}, retry {} of {} in {:?}",
+                reason, attempt, self.retries, self.delay
+            );
+            counter!("hemrs_retries_total").increment(1);
+            tokio::time::sleep(self.delay).await;
+        }
+    }
+}
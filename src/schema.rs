@@ -0,0 +1,96 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+
+/// JSON Schema every sensor payload is checked against before it is deserialized, for clearer
+/// diagnostics on structurally wrong messages than serde gives
+pub struct PayloadSchema {
+    validator: Validator,
+}
+
+impl PayloadSchema {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read payload schema {}", path.display()))?;
+        let schema: Value = serde_json::from_str(&contents)
+            .with_context(|| format!("payload schema {} is not JSON", path.display()))?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| anyhow!("invalid payload schema {}: {}", path.display(), e))?;
+        Ok(Self { validator })
+    }
+
+    /// Every way `payload` violates the schema, empty when it conforms. Payloads that are not
+    /// JSON at all are left to the deserializer to report.
+    pub fn violations(&self, payload: &str) -> Vec<String> {
+        let Ok(payload) = serde_json::from_str::<Value>(payload) else {
+            return Vec::new();
+        };
+        self.validator
+            .iter_errors(&payload)
+            .map(|e| format!("{} at {:?}", e, e.instance_path().as_str()))
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for PayloadSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadSchema").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::config_file;
+
+    const SCHEMA: &str = r#"{
+        "type": "object",
+        "properties": {
+            "DHT11": {
+                "type": "object",
+                "properties": {
+                    "Temperature": {"type": "number"},
+                    "Humidity": {"type": "number"}
+                },
+                "required": ["Temperature", "Humidity"]
+            }
+        }
+    }"#;
+
+    fn schema() -> PayloadSchema {
+        PayloadSchema::load(&config_file("payload_schema.json", SCHEMA)).unwrap()
+    }
+
+    #[test]
+    fn accepts_conforming_payloads() {
+        let schema = schema();
+        assert!(schema
+            .violations(
+                r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.5,"Humidity":40.0}}"#
+            )
+            .is_empty());
+        // Left to the deserializer to report
+        assert!(schema.violations("{").is_empty());
+    }
+
+    #[test]
+    fn reports_every_violation_with_its_path() {
+        let violations = schema().violations(r#"{"DHT11":{"Temperature":"21.5"}}"#);
+        assert_eq!(violations.len(), 2, "{:?}", violations);
+        assert!(violations
+            .iter()
+            .any(|violation| violation.ends_with("at \"/DHT11/Temperature\"")));
+        assert!(violations.iter().any(
+            |violation| violation.contains("Humidity") && violation.ends_with("at \"/DHT11\"")
+        ));
+    }
+
+    #[test]
+    fn rejects_schemas_that_are_not_json() {
+        let path = config_file("broken_schema.json", "{");
+        let error = PayloadSchema::load(&path).unwrap_err();
+        assert!(format!("{:#}", error).contains("is not JSON"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, ErrorKind},
+    sync::mpsc::Sender,
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    config::SerialConfig,
+    mqtt::{handle_payload, message_span, report_result, DeviceContext, ProcessResult, Processor},
+};
+
+/// Lines read from the port but not processed yet before reading waits
+const LINE_BUFFER: usize = 64;
+
+/// How often reading checks whether the lines are still being processed
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reads lines from the port until it closes, blocking, and sends the non-empty ones on `lines`
+fn read_lines(port: &str, baud_rate: u32, lines: mpsc::Sender<String>) -> Result<()> {
+    let serial_port = serialport::new(port, baud_rate)
+        .timeout(READ_TIMEOUT)
+        .open()?;
+    info!("Reading {} at {} baud", port, baud_rate);
+    let mut reader = BufReader::new(serial_port);
+    let mut line = String::new();
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            // Nobody processing the lines anymore means the monitor is stopping
+            Err(e) if e.kind() == ErrorKind::TimedOut && lines.is_closed() => return Ok(()),
+            // Keep what was read so far of the line and wait for the rest
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        }
+        let payload = line.trim();
+        // Nobody processing the lines anymore means the monitor is stopping
+        if !payload.is_empty() && lines.blocking_send(payload.to_string()).is_err() {
+            return Ok(());
+        }
+        line.clear();
+    }
+}
+
+/// Processes the lines of the port until it closes or `shutdown` is set
+pub async fn handle_serial(
+    serial: &SerialConfig,
+    processor: &Processor<'_>,
+    topic_to_device: &HashMap<String, DeviceContext>,
+    results: Option<&Sender<ProcessResult>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let (lines_tx, mut lines) = mpsc::channel(LINE_BUFFER);
+    let (port, baud_rate) = (serial.port.clone(), serial.baud_rate);
+    let reader = tokio::task::spawn_blocking(move || read_lines(&port, baud_rate, lines_tx));
+    loop {
+        let payload = tokio::select! {
+            payload = lines.recv() => match payload {
+                Some(payload) => payload,
+                None => break,
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        processor
+            .last_payloads
+            .record(&serial.device.topic, payload.as_bytes());
+        processor.stats.message();
+        if processor.drop_paused(&serial.device.topic) {
+            continue;
+        }
+        // The first line after opening the port is often cut off, so a bad line is not fatal here
+        let result = handle_payload(&serial.device.topic, &payload, processor, topic_to_device)
+            .instrument(message_span(&serial.device.topic))
+            .await;
+        report_result(results, &serial.device.topic, &result);
+        match result {
+            Ok(true) if processor.config.once => break,
+            Ok(_) => {}
+            Err(e) if processor.config.once => {
+                drop(lines);
+                return Err(e);
+            }
+            Err(e) => warn!("Error = {:?}", e),
+        }
+    }
+    // Lets the reader notice it can stop
+    drop(lines);
+    reader.await?
+}
@@ -0,0 +1,696 @@
+use std::{io::Write, time::Instant};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use metrics::{counter, histogram};
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    RequestBuilder, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{debug, info, warn};
+
+use crate::{mqtt::Measurement, retry::RetryPolicy};
+
+/// How devices and sensors are identified towards hemrs
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IdMode {
+    #[default]
+    Numeric,
+    Name,
+}
+
+impl std::str::FromStr for IdMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(IdMode::Numeric),
+            "name" => Ok(IdMode::Name),
+            _ => Err("unknown id mode".to_string()),
+        }
+    }
+}
+
+/// How a batch of measurements is framed in a single request
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchFormat {
+    /// A JSON array, sent as `application/json`
+    #[default]
+    Json,
+    /// One JSON object per line, sent as `application/x-ndjson`
+    Ndjson,
+}
+
+impl std::str::FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(BatchFormat::Json),
+            "ndjson" => Ok(BatchFormat::Ndjson),
+            _ => Err("unknown batch format".to_string()),
+        }
+    }
+}
+
+impl BatchFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BatchFormat::Json => "application/json",
+            BatchFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Frames the measurement bodies as a single request body
+    pub fn frame(&self, bodies: &[Value]) -> serde_json::Result<Vec<u8>> {
+        match self {
+            BatchFormat::Json => serde_json::to_vec(bodies),
+            BatchFormat::Ndjson => {
+                let mut framed = Vec::new();
+                for body in bodies {
+                    serde_json::to_writer(&mut framed, body)?;
+                    framed.push(b'\n');
+                }
+                Ok(framed)
+            }
+        }
+    }
+}
+
+/// Compresses a request body with gzip, to be sent with `Content-Encoding: gzip`
+pub fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Where measurements are stored
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Hemrs,
+    Kafka,
+    Sqlite,
+    Influx,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hemrs" => Ok(Backend::Hemrs),
+            "kafka" => Ok(Backend::Kafka),
+            "sqlite" => Ok(Backend::Sqlite),
+            "influx" => Ok(Backend::Influx),
+            _ => Err("unknown backend".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct NamedMeasurement<'a> {
+    device_name: &'a str,
+    sensor_name: &'a str,
+    value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl<'a> From<&'a Measurement> for NamedMeasurement<'a> {
+    fn from(measurement: &'a Measurement) -> Self {
+        Self {
+            device_name: &measurement.device_name,
+            sensor_name: &measurement.sensor_name,
+            value: measurement.measurement,
+            unit: measurement.unit.as_deref(),
+            instance: measurement.instance.as_deref(),
+            timestamp: measurement.timestamp,
+        }
+    }
+}
+
+pub fn to_value(measurement: &Measurement, id_mode: IdMode) -> serde_json::Result<Value> {
+    match id_mode {
+        IdMode::Numeric => serde_json::to_value(measurement),
+        IdMode::Name => serde_json::to_value(NamedMeasurement::from(measurement)),
+    }
+}
+
+/// Serializes the measurement as JSON the same way it is posted to hemrs
+pub fn to_json(measurement: &Measurement, id_mode: IdMode) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&to_value(measurement, id_mode)?)
+}
+
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()>;
+
+    /// Stores several measurements at once, sinks without a batch API store them one by one
+    async fn write_measurements(&self, measurements: &[Measurement]) -> Result<()> {
+        for measurement in measurements {
+            self.write_measurement(measurement).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Sink logging every measurement the way it would be posted instead of storing it
+pub struct DryRunSink {
+    id_mode: IdMode,
+}
+
+impl DryRunSink {
+    pub fn new(id_mode: IdMode) -> Self {
+        Self { id_mode }
+    }
+}
+
+#[async_trait]
+impl Sink for DryRunSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        info!(
+            "Dry run, not posting {}",
+            to_value(measurement, self.id_mode)?
+        );
+        Ok(())
+    }
+}
+
+/// Per item outcome hemrs may report for a batch, `{"results": [{"success": true}, ..]}`
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    results: Vec<BatchItemResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchItemResult {
+    success: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+pub struct HemrsSink {
+    client: reqwest::Client,
+    url: String,
+    id_mode: IdMode,
+    wrapper_key: Option<String>,
+    batch_format: BatchFormat,
+    log_responses: bool,
+    retry: RetryPolicy,
+    /// Gzip batch bodies, single measurements are small enough to be sent as they are
+    compress_batches: bool,
+}
+
+impl HemrsSink {
+    /// Posts to `url`. With a `wrapper_key` the measurement is posted nested under that key,
+    /// `{"data": {..}}`
+    pub fn new(
+        client: reqwest::Client,
+        url: &str,
+        id_mode: IdMode,
+        wrapper_key: Option<String>,
+        batch_format: BatchFormat,
+        log_responses: bool,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            client,
+            url: url.to_string(),
+            id_mode,
+            wrapper_key,
+            batch_format,
+            log_responses,
+            retry,
+            compress_batches: false,
+        }
+    }
+
+    /// Sends batches gzip compressed, for hemrs instances behind a slow link
+    pub fn compress_batches(mut self, compress: bool) -> Self {
+        self.compress_batches = compress;
+        self
+    }
+
+    /// Measurements endpoint of the hemrs instance the measurement is stored in
+    fn url<'a>(&'a self, measurement: &'a Measurement) -> &'a str {
+        measurement.hemrs_url.as_deref().unwrap_or(&self.url)
+    }
+
+    /// Sends a POST to hemrs, recording how long it took including retries in
+    /// `hemrs_post_duration_seconds`, labeled with whether it succeeded
+    async fn post(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let start = Instant::now();
+        let response = self.retry.send(request).await;
+        let outcome = match &response {
+            Ok(response) if response.status().is_success() => "success",
+            _ => "failure",
+        };
+        histogram!("hemrs_post_duration_seconds", "outcome" => outcome)
+            .record(start.elapsed().as_secs_f64());
+        response
+    }
+
+    /// Reads the status and body of a response, logging them at debug level when enabled
+    async fn read_response(&self, response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if self.log_responses {
+            debug!("hemrs answered {} {}", status, body);
+        }
+        (status, body)
+    }
+
+    pub fn body(&self, measurement: &Measurement) -> serde_json::Result<Value> {
+        let body = to_value(measurement, self.id_mode)?;
+        Ok(match &self.wrapper_key {
+            Some(key) => Value::Object(Map::from_iter([(key.clone(), body)])),
+            None => body,
+        })
+    }
+
+    /// Posts the measurements as one JSON array. Items hemrs reports as failed are retried one by
+    /// one, and if the whole batch is rejected every item is posted on its own so a single bad
+    /// reading does not take the others down with it.
+    async fn post_batch(&self, url: &str, measurements: &[Measurement]) -> Result<()> {
+        if measurements.is_empty() {
+            return Ok(());
+        }
+        let bodies = measurements
+            .iter()
+            .map(|measurement| self.body(measurement))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, self.batch_format.content_type());
+        let body = self.batch_format.frame(&bodies)?;
+        request = if self.compress_batches {
+            request.header(CONTENT_ENCODING, "gzip").body(gzip(&body)?)
+        } else {
+            request.body(body)
+        };
+        let response = match self.post(request).await {
+            Ok(response) => Ok(self.read_response(response).await),
+            Err(e) => Err(e),
+        };
+        let retry: Vec<&Measurement> = match response {
+            Ok((status, body)) if status.is_success() => {
+                match serde_json::from_str::<BatchResponse>(&body) {
+                    Ok(batch) => batch
+                        .results
+                        .iter()
+                        .zip(measurements)
+                        .filter(|(result, _)| !result.success)
+                        .map(|(result, measurement)| {
+                            warn!(
+                                "hemrs rejected {:?} in batch: {}",
+                                measurement,
+                                result.error.as_deref().unwrap_or("no reason given")
+                            );
+                            measurement
+                        })
+                        .collect(),
+                    // No per item results means the whole batch was stored
+                    Err(_) => Vec::new(),
+                }
+            }
+            Ok((status, _)) => {
+                warn!(
+                    "Batch of {} measurements failed with {}, posting them one by one",
+                    measurements.len(),
+                    status
+                );
+                measurements.iter().collect()
+            }
+            Err(e) => {
+                warn!(
+                    "Batch of {} measurements failed: {}, posting them one by one",
+                    measurements.len(),
+                    e
+                );
+                measurements.iter().collect()
+            }
+        };
+
+        let mut failed = 0;
+        for measurement in retry {
+            if let Err(e) = self.post_checked(measurement).await {
+                warn!("Batch item failed: {:#}", e);
+                failed += 1;
+            }
+        }
+        counter!("hemrs_batch_item_failures_total").increment(failed);
+        if failed > 0 {
+            return Err(anyhow!(
+                "{} of {} measurements in batch could not be stored",
+                failed,
+                measurements.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Posts a single measurement, failing on error responses as well
+    pub async fn post_checked(&self, measurement: &Measurement) -> Result<()> {
+        let response = self
+            .post(
+                self.client
+                    .post(self.url(measurement))
+                    .json(&self.body(measurement)?),
+            )
+            .await
+            .with_context(|| posting(measurement))?;
+        let (status, _) = self.read_response(response).await;
+        if !status.is_success() {
+            return Err(anyhow!("hemrs answered {}", status)).with_context(|| posting(measurement));
+        }
+        Ok(())
+    }
+}
+
+/// Error context naming the measurement by device and sensor name, as their ids are 0 when
+/// hemrs identifies them by name
+fn posting(measurement: &Measurement) -> String {
+    format!(
+        "posting {} for device {} sensor {}",
+        measurement.measurement, measurement.device_name, measurement.sensor_name
+    )
+}
+
+#[async_trait]
+impl Sink for HemrsSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        self.post_checked(measurement).await
+    }
+
+    /// Posts the measurements in one batch per hemrs instance they are stored in
+    async fn write_measurements(&self, measurements: &[Measurement]) -> Result<()> {
+        let mut urls: Vec<&str> = Vec::new();
+        for measurement in measurements {
+            let url = self.url(measurement);
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        for url in urls {
+            let batch: Vec<Measurement> = measurements
+                .iter()
+                .filter(|measurement| self.url(measurement) == url)
+                .cloned()
+                .collect();
+            self.post_batch(url, &batch).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, time::Duration};
+
+    use tracing::Level;
+
+    use super::*;
+    use crate::testing::fixtures::{logged, MockServer};
+
+    fn measurement() -> Measurement {
+        Measurement {
+            device: 1,
+            sensor: 2,
+            measurement: 21.5,
+            unit: None,
+            instance: None,
+            timestamp: None,
+            device_name: "stue".to_string(),
+            sensor_name: "ds18b20".to_string(),
+            hemrs_url: None,
+        }
+    }
+
+    fn sink(server: &MockServer, retries: u32) -> HemrsSink {
+        HemrsSink::new(
+            reqwest::Client::new(),
+            &format!("{}/api/measurements", server.url),
+            IdMode::Numeric,
+            None,
+            BatchFormat::Json,
+            false,
+            RetryPolicy::new(retries, Duration::from_millis(1)),
+        )
+    }
+
+    #[test]
+    fn serializes_timestamps_as_iso_8601() {
+        let mut measurement = measurement();
+        assert!(to_value(&measurement, IdMode::Numeric).unwrap()["timestamp"].is_null());
+        measurement.timestamp = Some("2026-10-15T10:00:00Z".parse().unwrap());
+        for id_mode in [IdMode::Numeric, IdMode::Name] {
+            assert_eq!(
+                to_value(&measurement, id_mode).unwrap()["timestamp"],
+                "2026-10-15T10:00:00Z"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_stored() {
+        let server = MockServer::start(&[503, 503, 200]);
+        sink(&server, 3)
+            .write_measurement(&measurement())
+            .await
+            .unwrap();
+        assert_eq!(server.bodies().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn fails_fast_on_client_errors() {
+        let server = MockServer::start(&[400]);
+        let e = sink(&server, 3)
+            .write_measurement(&measurement())
+            .await
+            .unwrap_err();
+        assert_eq!(server.bodies().len(), 1);
+        assert!(format!("{:#}", e).contains("400"));
+    }
+
+    #[tokio::test]
+    async fn fails_once_retries_are_used_up() {
+        let server = MockServer::start(&[503]);
+        let e = sink(&server, 2)
+            .write_measurement(&measurement())
+            .await
+            .unwrap_err();
+        assert_eq!(server.bodies().len(), 3);
+        assert!(format!("{:#}", e).contains("503"));
+    }
+
+    #[tokio::test]
+    async fn names_the_failing_measurement() {
+        let context = "posting 21.5 for device stue sensor ds18b20";
+        let server = MockServer::start(&[500]);
+        let e = sink(&server, 0)
+            .write_measurement(&measurement())
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), context);
+
+        // Nothing listens on port 9 of localhost, so the request itself fails
+        let unreachable = HemrsSink::new(
+            reqwest::Client::new(),
+            "http://127.0.0.1:9/api/measurements",
+            IdMode::Numeric,
+            None,
+            BatchFormat::Json,
+            false,
+            RetryPolicy::new(0, Duration::from_millis(1)),
+        );
+        let e = unreachable
+            .write_measurement(&measurement())
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), context);
+        assert!(e.chain().count() > 1);
+    }
+
+    #[tokio::test]
+    async fn wraps_the_body_under_the_wrapper_key() {
+        let server = MockServer::start(&[200]);
+        let sink = |id_mode, wrapper_key: Option<&str>| {
+            HemrsSink::new(
+                reqwest::Client::new(),
+                &server.url,
+                id_mode,
+                wrapper_key.map(str::to_string),
+                BatchFormat::Json,
+                false,
+                RetryPolicy::default(),
+            )
+        };
+        let measurement = measurement();
+        assert_eq!(
+            sink(IdMode::Numeric, None).body(&measurement).unwrap(),
+            serde_json::json!({"device": 1, "sensor": 2, "measurement": 21.5})
+        );
+        assert_eq!(
+            sink(IdMode::Numeric, Some("data"))
+                .body(&measurement)
+                .unwrap(),
+            serde_json::json!({"data": {"device": 1, "sensor": 2, "measurement": 21.5}})
+        );
+        assert_eq!(
+            sink(IdMode::Name, Some("data")).body(&measurement).unwrap(),
+            serde_json::json!({"data": {"device_name": "stue", "sensor_name": "ds18b20", "value": 21.5}})
+        );
+
+        sink(IdMode::Numeric, Some("data"))
+            .write_measurement(&measurement)
+            .await
+            .unwrap();
+        let posted: Value = serde_json::from_slice(&server.bodies()[0]).unwrap();
+        assert_eq!(posted["data"]["measurement"], 21.5);
+    }
+
+    #[tokio::test]
+    async fn frames_batches_as_ndjson() {
+        let bodies = [
+            serde_json::json!({"a": 1}),
+            serde_json::json!({"b": [2, 3]}),
+        ];
+        assert_eq!(
+            BatchFormat::Ndjson.frame(&bodies).unwrap(),
+            b"{\"a\":1}\n{\"b\":[2,3]}\n"
+        );
+        assert_eq!(
+            BatchFormat::Json.frame(&bodies).unwrap(),
+            b"[{\"a\":1},{\"b\":[2,3]}]"
+        );
+        assert!(BatchFormat::Ndjson.frame(&[]).unwrap().is_empty());
+
+        let server = MockServer::start(&[200]);
+        let sink = HemrsSink::new(
+            reqwest::Client::new(),
+            &server.url,
+            IdMode::Numeric,
+            None,
+            BatchFormat::Ndjson,
+            false,
+            RetryPolicy::default(),
+        );
+        let mut second = measurement();
+        second.sensor = 3;
+        sink.write_measurements(&[measurement(), second])
+            .await
+            .unwrap();
+        let posted = String::from_utf8(server.bodies()[0].clone()).unwrap();
+        let sensors: Vec<i64> = posted
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["sensor"]
+                    .as_i64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(sensors, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn compresses_batches_with_gzip() {
+        let decompress = |body: &[u8]| {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(body)
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            decompressed
+        };
+        let body = br#"[{"device":1,"sensor":2,"measurement":21.5}]"#;
+        assert_eq!(decompress(&gzip(body).unwrap()), body);
+
+        let server = MockServer::start(&[200]);
+        let sink = sink(&server, 0).compress_batches(true);
+        sink.write_measurements(&[measurement(), measurement()])
+            .await
+            .unwrap();
+        // Single measurements are sent as they are
+        sink.write_measurement(&measurement()).await.unwrap();
+
+        let bodies = server.bodies();
+        let batch: Value = serde_json::from_slice(&decompress(&bodies[0])).unwrap();
+        assert_eq!(batch.as_array().unwrap().len(), 2);
+        assert_eq!(batch[0], sink.body(&measurement()).unwrap());
+        let single: Value = serde_json::from_slice(&bodies[1]).unwrap();
+        assert_eq!(single, sink.body(&measurement()).unwrap());
+    }
+
+    #[test]
+    fn dry_runs_log_without_posting() {
+        let server = MockServer::start(&[200]);
+        let sink = DryRunSink::new(IdMode::Numeric);
+        let (result, lines) = logged(Level::INFO, async {
+            sink.write_measurement(&measurement()).await?;
+            sink.write_measurements(&[measurement(), measurement()])
+                .await
+        });
+        result.unwrap();
+        let expected = format!(
+            "Dry run, not posting {}",
+            to_value(&measurement(), IdMode::Numeric).unwrap()
+        );
+        assert_eq!(
+            lines.iter().filter(|line| line.contains(&expected)).count(),
+            3
+        );
+        assert!(server.bodies().is_empty());
+    }
+
+    #[tokio::test]
+    async fn posts_to_the_hemrs_instance_of_each_device() {
+        let default = MockServer::start(&[200, 200]);
+        let other = MockServer::start(&[200, 200]);
+        let sink = sink(&default, 0);
+        let mut remote = measurement();
+        remote.device = 2;
+        remote.hemrs_url = Some(format!("{}/api/measurements", other.url));
+
+        sink.write_measurement(&measurement()).await.unwrap();
+        sink.write_measurement(&remote).await.unwrap();
+        sink.write_measurements(&[measurement(), remote.clone(), measurement()])
+            .await
+            .unwrap();
+
+        let devices = |server: &MockServer| -> Vec<Value> {
+            server
+                .bodies()
+                .iter()
+                .map(|body| {
+                    let body: Value = serde_json::from_slice(body).unwrap();
+                    match body {
+                        Value::Array(batch) => {
+                            batch.iter().map(|item| item["device"].clone()).collect()
+                        }
+                        single => single["device"].clone(),
+                    }
+                })
+                .collect()
+        };
+        assert_eq!(
+            devices(&default),
+            [serde_json::json!(1), serde_json::json!([1, 1])]
+        );
+        assert_eq!(
+            devices(&other),
+            [serde_json::json!(2), serde_json::json!([2])]
+        );
+    }
+}
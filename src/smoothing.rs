@@ -0,0 +1,40 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::lru::BoundedMap;
+
+/// Moving average over the last readings of each device and sensor, for sensors with a window
+#[derive(Debug)]
+pub struct MovingAverage {
+    windows: HashMap<String, usize>,
+    readings: Mutex<BoundedMap<(String, &'static str), VecDeque<f32>>>,
+}
+
+impl MovingAverage {
+    pub fn new(windows: HashMap<String, usize>, capacity: usize) -> Self {
+        Self {
+            windows,
+            readings: Mutex::new(BoundedMap::new("smoothing", capacity)),
+        }
+    }
+
+    /// Adds the reading to the window of the sensor and returns the average of the window, or
+    /// the reading itself if the sensor is not smoothed
+    pub fn smooth(&self, device: &str, sensor_key: &'static str, value: f32) -> f32 {
+        let window = match self.windows.get(sensor_key) {
+            Some(window) if *window > 1 => *window,
+            _ => return value,
+        };
+        let mut readings = self.readings.lock().unwrap();
+        let buffer = readings.get_or_insert_with((device.to_string(), sensor_key), || {
+            VecDeque::with_capacity(window)
+        });
+        if buffer.len() == window {
+            buffer.pop_front();
+        }
+        buffer.push_back(value);
+        buffer.iter().sum::<f32>() / buffer.len() as f32
+    }
+}
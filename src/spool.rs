@@ -0,0 +1,296 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{mqtt::Measurement, sink::Sink};
+
+const SPOOL_FILE: &str = "spool.jsonl";
+
+/// Everything needed to store the measurement later, including the names the wire format skips
+#[derive(Serialize, Deserialize, Debug)]
+struct SpooledMeasurement {
+    device: i32,
+    sensor: i32,
+    measurement: f64,
+    unit: Option<String>,
+    instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+    device_name: String,
+    sensor_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hemrs_url: Option<String>,
+}
+
+impl From<&Measurement> for SpooledMeasurement {
+    fn from(measurement: &Measurement) -> Self {
+        Self {
+            device: measurement.device,
+            sensor: measurement.sensor,
+            measurement: measurement.measurement,
+            unit: measurement.unit.clone(),
+            instance: measurement.instance.clone(),
+            timestamp: measurement.timestamp,
+            device_name: measurement.device_name.clone(),
+            sensor_name: measurement.sensor_name.clone(),
+            hemrs_url: measurement.hemrs_url.clone(),
+        }
+    }
+}
+
+impl From<SpooledMeasurement> for Measurement {
+    fn from(spooled: SpooledMeasurement) -> Self {
+        Self {
+            device: spooled.device,
+            sensor: spooled.sensor,
+            measurement: spooled.measurement,
+            unit: spooled.unit,
+            instance: spooled.instance,
+            timestamp: spooled.timestamp,
+            device_name: spooled.device_name,
+            sensor_name: spooled.sensor_name,
+            hemrs_url: spooled.hemrs_url,
+        }
+    }
+}
+
+/// Append-only file of measurements that could not be stored, one JSON object per line in the
+/// order they failed
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    /// Held while the file is appended to or rewritten, never across a write to the sink
+    file: Mutex<()>,
+}
+
+impl Spool {
+    /// Uses `spool.jsonl` in `dir`, creating the directory if needed
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create spool directory {}", dir.display()))?;
+        Ok(Self {
+            path: dir.join(SPOOL_FILE),
+            file: Mutex::new(()),
+        })
+    }
+
+    pub fn push(&self, measurement: &Measurement) -> Result<()> {
+        let mut line = serde_json::to_string(&SpooledMeasurement::from(measurement))?;
+        line.push('\n');
+        let _file = self.file.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("failed to spool to {}", self.path.display()))?;
+        counter!("spool_measurements_total").increment(1);
+        Ok(())
+    }
+
+    fn lines(&self) -> Result<Vec<String>> {
+        let _file = self.file.lock().unwrap();
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", self.path.display())),
+        }
+    }
+
+    /// Drops the first `count` lines, keeping whatever was appended since they were read
+    fn remove_first(&self, count: usize) -> Result<usize> {
+        let _file = self.file.lock().unwrap();
+        let contents = fs::read_to_string(&self.path)?;
+        let remaining: Vec<&str> = contents.lines().skip(count).collect();
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut rewritten = remaining.join("\n");
+        if !remaining.is_empty() {
+            rewritten.push('\n');
+        }
+        fs::write(&tmp, rewritten)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(remaining.len())
+    }
+
+    /// Stores the spooled measurements in order until one fails, removing the stored ones.
+    /// Returns how many were stored.
+    pub async fn flush(&self, sink: &dyn Sink) -> Result<usize> {
+        let lines = self.lines()?;
+        if lines.is_empty() {
+            gauge!("spool_pending_measurements").set(0.0);
+            return Ok(0);
+        }
+        let mut done = 0;
+        let mut stored = 0;
+        for line in &lines {
+            match serde_json::from_str::<SpooledMeasurement>(line) {
+                Ok(spooled) => {
+                    if let Err(e) = sink.write_measurement(&spooled.into()).await {
+                        warn!("Flushing the spool stopped: {:#}", e);
+                        break;
+                    }
+                    stored += 1;
+                }
+                // Most likely a line cut short by a crash, it can never be stored
+                Err(e) => warn!("Dropping unreadable spool entry {:?}: {}", line, e),
+            }
+            done += 1;
+        }
+        let pending = self.remove_first(done)?;
+        gauge!("spool_pending_measurements").set(pending as f64);
+        counter!("spool_flushed_measurements_total").increment(stored as u64);
+        Ok(stored)
+    }
+}
+
+/// Sink spooling measurements the inner sink fails to store, so they can be stored later
+pub struct SpoolSink {
+    inner: Arc<dyn Sink>,
+    spool: Arc<Spool>,
+}
+
+impl SpoolSink {
+    pub fn new(inner: Arc<dyn Sink>, spool: Arc<Spool>) -> Self {
+        Self { inner, spool }
+    }
+}
+
+#[async_trait]
+impl Sink for SpoolSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        if let Err(e) = self.inner.write_measurement(measurement).await {
+            warn!("Storing {:?} failed, spooling it: {:#}", measurement, e);
+            self.spool.push(measurement)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the measurements as one batch, spooling all of them when it fails. A batch the
+    /// inner sink stored in part is spooled whole, so some of it may be stored twice.
+    async fn write_measurements(&self, measurements: &[Measurement]) -> Result<()> {
+        if let Err(e) = self.inner.write_measurements(measurements).await {
+            warn!(
+                "Storing {} measurements failed, spooling them: {:#}",
+                measurements.len(),
+                e
+            );
+            for measurement in measurements {
+                self.spool.push(measurement)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flushes the spool to `sink` every `interval` from a background task
+pub fn spawn_flusher(spool: Arc<Spool>, sink: Arc<dyn Sink>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match spool.flush(sink.as_ref()).await {
+                Ok(0) => {}
+                Ok(stored) => info!("Stored {} spooled measurements", stored),
+                Err(e) => warn!("Flushing the spool failed: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        retry::RetryPolicy,
+        sink::{BatchFormat, HemrsSink, IdMode},
+        testing::fixtures::MockServer,
+    };
+
+    fn measurement(device: i32, device_name: &str) -> Measurement {
+        Measurement {
+            device,
+            sensor: 1,
+            measurement: 21.5,
+            unit: None,
+            instance: None,
+            timestamp: None,
+            device_name: device_name.to_string(),
+            sensor_name: "ds18b20".to_string(),
+            hemrs_url: None,
+        }
+    }
+
+    fn hemrs(server: &MockServer) -> Arc<dyn Sink> {
+        Arc::new(HemrsSink::new(
+            reqwest::Client::new(),
+            &server.url,
+            IdMode::Numeric,
+            None,
+            BatchFormat::Json,
+            false,
+            RetryPolicy::default(),
+        ))
+    }
+
+    fn spool(name: &str) -> Arc<Spool> {
+        let dir = std::env::temp_dir().join(format!(
+            "sensor_monitor_spool_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Arc::new(Spool::open(&dir).unwrap())
+    }
+
+    #[tokio::test]
+    async fn spools_on_failure_and_drains_on_recovery() {
+        // Both measurements fail, the first flush is answered 502 and the second succeeds
+        let server = MockServer::start(&[503, 503, 502, 200]);
+        let spool = spool("recovery");
+        let sink = SpoolSink::new(hemrs(&server), Arc::clone(&spool));
+        sink.write_measurement(&measurement(1, "stue"))
+            .await
+            .unwrap();
+        sink.write_measurement(&measurement(2, "kjeller"))
+            .await
+            .unwrap();
+        assert_eq!(spool.lines().unwrap().len(), 2);
+
+        assert_eq!(spool.flush(hemrs(&server).as_ref()).await.unwrap(), 0);
+        assert_eq!(spool.lines().unwrap().len(), 2);
+
+        assert_eq!(spool.flush(hemrs(&server).as_ref()).await.unwrap(), 2);
+        assert!(spool.lines().unwrap().is_empty());
+        let devices: Vec<i32> = server.bodies()[3..]
+            .iter()
+            .map(|body| {
+                serde_json::from_slice::<serde_json::Value>(body).unwrap()["device"]
+                    .as_i64()
+                    .unwrap() as i32
+            })
+            .collect();
+        assert_eq!(devices, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn spools_failed_batches() {
+        // The batch and then each of its measurements on their own fail
+        let server = MockServer::start(&[503]);
+        let spool = spool("batch");
+        let sink = SpoolSink::new(hemrs(&server), Arc::clone(&spool));
+        let batch = [measurement(1, "stue"), measurement(2, "kjeller")];
+        sink.write_measurements(&batch).await.unwrap();
+        assert_eq!(server.bodies().len(), 3);
+        assert_eq!(spool.lines().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use metrics::{counter, gauge};
+use rusqlite::{params, Connection};
+use tracing::{info, warn};
+
+use crate::{
+    mqtt::Measurement,
+    sink::{HemrsSink, Sink},
+};
+
+/// Rows uploaded per round of the syncer
+const SYNC_BATCH_SIZE: usize = 100;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS measurements (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    device INTEGER NOT NULL,
+    sensor INTEGER NOT NULL,
+    measurement REAL NOT NULL,
+    unit TEXT,
+    instance TEXT,
+    device_name TEXT NOT NULL,
+    sensor_name TEXT NOT NULL,
+    synced INTEGER NOT NULL DEFAULT 0,
+    hemrs_url TEXT,
+    timestamp TEXT
+)";
+
+/// Columns added to the schema since the first release, with their types
+const ADDED_COLUMNS: [(&str, &str); 2] = [("hemrs_url", "TEXT"), ("timestamp", "TEXT")];
+
+fn open(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.execute(SCHEMA, [])?;
+    // Databases created by older versions lack the columns added since
+    for (column, column_type) in ADDED_COLUMNS {
+        let exists = connection
+            .prepare("SELECT 1 FROM pragma_table_info('measurements') WHERE name = ?1")?
+            .exists([column])?;
+        if !exists {
+            connection.execute(
+                &format!(
+                    "ALTER TABLE measurements ADD COLUMN {} {}",
+                    column, column_type
+                ),
+                [],
+            )?;
+        }
+    }
+    Ok(connection)
+}
+
+/// Stores measurements in a local SQLite database, to be uploaded to hemrs by [`spawn_syncer`]
+pub struct SqliteSink {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSink {
+    /// Opens the database, creating it and the schema on first run
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(Self {
+            connection: Arc::new(Mutex::new(open(path)?)),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        let (connection, measurement) = (Arc::clone(&self.connection), measurement.clone());
+        tokio::task::spawn_blocking(move || {
+            connection.lock().unwrap().execute(
+                "INSERT INTO measurements
+                    (device, sensor, measurement, unit, instance, device_name, sensor_name,
+                        hemrs_url, timestamp)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    measurement.device,
+                    measurement.sensor,
+                    measurement.measurement,
+                    measurement.unit,
+                    measurement.instance,
+                    measurement.device_name,
+                    measurement.sensor_name,
+                    measurement.hemrs_url,
+                    measurement.timestamp,
+                ],
+            )
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+/// Uploads unsynced rows oldest first and marks them as sent, stopping at the first failure so
+/// the rest is retried in the next round. Returns how many rows were uploaded.
+async fn sync(connection: &Mutex<Connection>, hemrs: &HemrsSink) -> Result<usize> {
+    let rows = unsynced(&connection.lock().unwrap())?;
+    let mut synced = 0;
+    for (id, measurement) in rows {
+        hemrs.post_checked(&measurement).await?;
+        connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE measurements SET synced = 1 WHERE id = ?1", [id])?;
+        synced += 1;
+    }
+    Ok(synced)
+}
+
+fn unsynced(connection: &Connection) -> Result<Vec<(i64, Measurement)>> {
+    let mut statement = connection.prepare(
+        "SELECT id, device, sensor, measurement, unit, instance, device_name, sensor_name,
+                hemrs_url, timestamp
+            FROM measurements WHERE synced = 0 ORDER BY id LIMIT ?1",
+    )?;
+    let rows = statement
+        .query_map([SYNC_BATCH_SIZE], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                Measurement {
+                    device: row.get(1)?,
+                    sensor: row.get(2)?,
+                    measurement: row.get(3)?,
+                    unit: row.get(4)?,
+                    instance: row.get(5)?,
+                    timestamp: row.get(9)?,
+                    device_name: row.get(6)?,
+                    sensor_name: row.get(7)?,
+                    hemrs_url: row.get(8)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Uploads the measurements stored in the database to hemrs every `interval` from a background
+/// task with its own connection
+pub fn spawn_syncer(path: &Path, hemrs: HemrsSink, interval: Duration) -> Result<()> {
+    let connection = Mutex::new(open(path)?);
+    tokio::spawn(async move {
+        loop {
+            match sync(&connection, &hemrs).await {
+                Ok(0) => tokio::time::sleep(interval).await,
+                Ok(synced) => {
+                    info!("Uploaded {} stored measurements to hemrs", synced);
+                    counter!("sqlite_synced_measurements_total").increment(synced as u64);
+                    // A full batch likely means there is more to catch up on
+                    if synced < SYNC_BATCH_SIZE {
+                        tokio::time::sleep(interval).await;
+                    }
+                }
+                Err(e) => {
+                    warn!("Uploading stored measurements failed: {:?}", e);
+                    counter!("sqlite_sync_errors_total").increment(1);
+                    tokio::time::sleep(interval).await;
+                }
+            }
+            let pending: rusqlite::Result<i64> = connection.lock().unwrap().query_row(
+                "SELECT COUNT(*) FROM measurements WHERE synced = 0",
+                [],
+                |row| row.get(0),
+            );
+            if let Ok(pending) = pending {
+                gauge!("sqlite_unsynced_measurements").set(pending as f64);
+            }
+        }
+    });
+    Ok(())
+}
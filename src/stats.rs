@@ -0,0 +1,81 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Totals over the whole run, logged as a summary when the monitor stops
+#[derive(Debug)]
+pub struct RunStats {
+    started: Instant,
+    messages: AtomicU64,
+    measurements: AtomicU64,
+    parse_failures: AtomicU64,
+}
+
+impl Default for RunStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            messages: AtomicU64::new(0),
+            measurements: AtomicU64::new(0),
+            parse_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn measurement(&self) {
+        self.measurements.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failure(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Processed {} messages, posted {} measurements, {} parse failures, uptime {}s",
+            self.messages.load(Ordering::Relaxed),
+            self.measurements.load(Ordering::Relaxed),
+            self.parse_failures.load(Ordering::Relaxed),
+            self.uptime().as_secs()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_the_run() {
+        let stats = RunStats::new();
+        assert_eq!(
+            stats.summary(),
+            "Processed 0 messages, posted 0 measurements, 0 parse failures, uptime 0s"
+        );
+        for _ in 0..3 {
+            stats.message();
+        }
+        for _ in 0..5 {
+            stats.measurement();
+        }
+        stats.parse_failure();
+        assert_eq!(
+            stats.summary(),
+            "Processed 3 messages, posted 5 measurements, 1 parse failures, uptime 0s"
+        );
+    }
+}
@@ -0,0 +1,511 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{mqtt::Measurement, sink::Sink};
+
+/// Sink recording every measurement in memory, for testing without a hemrs instance.
+#[derive(Default, Debug)]
+pub struct InMemorySink {
+    measurements: Mutex<Vec<Measurement>>,
+}
+
+impl InMemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn measurements(&self) -> Vec<Measurement> {
+        self.measurements.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Sink for InMemorySink {
+    async fn write_measurement(&self, measurement: &Measurement) -> Result<()> {
+        self.measurements.lock().unwrap().push(measurement.clone());
+        Ok(())
+    }
+}
+
+/// Helpers for the crate's own tests
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::{
+        collections::HashMap,
+        future::Future,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use serde::{de::DeserializeOwned, Serialize};
+    use tracing::Level;
+
+    use crate::{
+        config::{Config, PayloadEncoding, TopicConfig},
+        filter::{Deadtime, Filters, MonotonicTime, RateLimit},
+        hem::{DeviceId, HttpBackend, SensorIds},
+        http::{Health, LastPayloads},
+        info::DeviceInfos,
+        labels::Labels,
+        lru::DEFAULT_STATE_MAP_MAX,
+        monitor::DEFAULT_DEADTIME_SECS,
+        mqtt::{DeviceContext, Processor},
+        sink::Sink,
+        smoothing::MovingAverage,
+        stats::RunStats,
+    };
+
+    /// HTTP server answering the requests it gets with `statuses` in turn, the last one from
+    /// then on, and recording their bodies. Answers are empty unless started with a body.
+    pub struct MockServer {
+        pub url: String,
+        bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl MockServer {
+        pub fn start(statuses: &[u16]) -> Self {
+            Self::start_with_body(statuses, "text/plain", "")
+        }
+
+        /// Answers every request with `body` of `content_type` too
+        pub fn start_with_body(statuses: &[u16], content_type: &str, body: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let bodies = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&bodies);
+            let statuses = statuses.to_vec();
+            let answer = format!(
+                "content-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+            std::thread::spawn(move || {
+                for (index, stream) in listener.incoming().enumerate() {
+                    let Ok(mut stream) = stream else { return };
+                    let mut reader = BufReader::new(&mut stream);
+                    let mut length = 0;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap_or(0);
+                            }
+                        }
+                        line.clear();
+                    }
+                    let mut body = vec![0; length];
+                    if reader.read_exact(&mut body).is_err() {
+                        continue;
+                    }
+                    recorded.lock().unwrap().push(body);
+                    let status = statuses[index.min(statuses.len() - 1)];
+                    let _ = write!(stream, "HTTP/1.1 {} Mock\r\n{}", status, answer);
+                }
+            });
+            Self { url, bodies }
+        }
+
+        /// Bodies of the requests answered so far, in order
+        pub fn bodies(&self) -> Vec<Vec<u8>> {
+            self.bodies.lock().unwrap().clone()
+        }
+    }
+
+    /// hemrs backend listing the entries posted to it, and counting the requests it gets
+    #[derive(Default)]
+    pub struct FakeBackend {
+        entries: Mutex<Vec<serde_json::Value>>,
+        /// Whether a POST is answered with the created entry, as newer hemrs versions do
+        pub answers_posts: bool,
+        pub gets: AtomicUsize,
+        pub posts: AtomicUsize,
+    }
+
+    impl FakeBackend {
+        pub fn new(entries: Vec<serde_json::Value>, answers_posts: bool) -> Self {
+            Self {
+                entries: Mutex::new(entries),
+                answers_posts,
+                ..Self::default()
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HttpBackend for FakeBackend {
+        async fn get_json<T: DeserializeOwned + Send>(
+            &self,
+            _url: &str,
+            _kind: &'static str,
+        ) -> Result<T> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            let entries = self.entries.lock().unwrap().clone();
+            Ok(serde_json::from_value(serde_json::Value::Array(entries))?)
+        }
+
+        async fn post_json<B: Serialize + Sync, T: DeserializeOwned + Send>(
+            &self,
+            _url: &str,
+            body: &B,
+        ) -> Result<Option<T>> {
+            self.posts.fetch_add(1, Ordering::SeqCst);
+            // Lets a second message of the same topic arrive while the entry is created
+            tokio::task::yield_now().await;
+            let mut entry = serde_json::to_value(body)?;
+            let mut entries = self.entries.lock().unwrap();
+            entry["id"] = (entries.len() + 1).into();
+            entries.push(entry.clone());
+            match self.answers_posts {
+                true => Ok(Some(serde_json::from_value(entry)?)),
+                false => Ok(None),
+            }
+        }
+    }
+
+    /// MQTT broker accepting every client and publishing `messages` to it at QoS 0 once it
+    /// subscribed, recording what the clients publish
+    pub struct MockBroker {
+        pub port: u16,
+        published: Arc<Mutex<Vec<Published>>>,
+    }
+
+    /// A message a client published to the `MockBroker`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Published {
+        pub topic: String,
+        pub payload: String,
+        pub retain: bool,
+    }
+
+    impl MockBroker {
+        pub fn start(messages: &[(&str, &str)]) -> Self {
+            Self::serve(messages, false)
+        }
+
+        /// Drops the connection of a client as soon as it publishes anything
+        pub fn start_closing(messages: &[(&str, &str)]) -> Self {
+            Self::serve(messages, true)
+        }
+
+        fn serve(messages: &[(&str, &str)], close: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let messages: Vec<(String, String)> = messages
+                .iter()
+                .map(|(topic, payload)| (topic.to_string(), payload.to_string()))
+                .collect();
+            let published = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&published);
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { return };
+                    let messages = messages.clone();
+                    let recorded = Arc::clone(&recorded);
+                    std::thread::spawn(move || serve_client(stream, &messages, close, &recorded));
+                }
+            });
+            Self { port, published }
+        }
+
+        /// Messages the clients published so far, in order
+        pub fn published(&self) -> Vec<Published> {
+            self.published.lock().unwrap().clone()
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).ok()?;
+        let mut packet = vec![byte[0]];
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            length |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).ok()?;
+        packet.extend(body);
+        Some(packet)
+    }
+
+    fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![header];
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            if length == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn serve_client(
+        mut stream: TcpStream,
+        messages: &[(String, String)],
+        close: bool,
+        recorded: &Mutex<Vec<Published>>,
+    ) {
+        let mut published = false;
+        while let Some(packet) = read_packet(&mut stream) {
+            let (header, body) = (packet[0], &packet[1..]);
+            if header >> 4 == 3 {
+                let topic_end = 2 + usize::from(u16::from_be_bytes([body[0], body[1]]));
+                // PUBLISH at QoS 1 or 2 has its packet id between the topic and the payload
+                let payload_at = match (header >> 1) & 3 {
+                    0 => topic_end,
+                    _ => topic_end + 2,
+                };
+                recorded.lock().unwrap().push(Published {
+                    topic: String::from_utf8_lossy(&body[2..topic_end]).into_owned(),
+                    payload: String::from_utf8_lossy(&body[payload_at..]).into_owned(),
+                    retain: header & 1 == 1,
+                });
+            }
+            let reply = match header >> 4 {
+                // CONNECT
+                1 => encode_packet(0x20, &[0, 0]),
+                3 if close => return,
+                // PUBLISH at QoS 1 or 2 is acknowledged with its packet id
+                3 if (header >> 1) & 3 > 0 => {
+                    let id_at = 2 + usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    encode_packet(0x40, &body[id_at..id_at + 2])
+                }
+                // SUBSCRIBE grants the QoS of every filter
+                8 => {
+                    let mut suback = body[..2].to_vec();
+                    let mut at = 2;
+                    while at < body.len() {
+                        at += 2 + usize::from(u16::from_be_bytes([body[at], body[at + 1]]));
+                        suback.push(body[at]);
+                        at += 1;
+                    }
+                    encode_packet(0x90, &suback)
+                }
+                // UNSUBSCRIBE
+                10 => encode_packet(0xb0, &body[..2]),
+                // PINGREQ
+                12 => encode_packet(0xd0, &[]),
+                // DISCONNECT
+                14 => return,
+                _ => continue,
+            };
+            if stream.write_all(&reply).is_err() {
+                return;
+            }
+            if header >> 4 == 8 && !published {
+                published = true;
+                for (topic, payload) in messages {
+                    let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+                    body.extend_from_slice(topic.as_bytes());
+                    body.extend_from_slice(payload.as_bytes());
+                    if stream.write_all(&encode_packet(0x30, &body)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Everything a `Processor` borrows, set up from the config the way the monitor does
+    pub struct ProcessorState {
+        pub config: Config,
+        filters: Filters,
+        smoothing: MovingAverage,
+        monotonic_time: MonotonicTime,
+        deadtime: Deadtime,
+        rate_limit: RateLimit,
+        last_payloads: LastPayloads,
+        paused: AtomicBool,
+        labels: Labels,
+        device_infos: DeviceInfos,
+        health: Health,
+        pub stats: RunStats,
+    }
+
+    impl ProcessorState {
+        pub fn new(config: Config) -> Self {
+            let max = DEFAULT_STATE_MAP_MAX;
+            Self {
+                filters: Filters::new(
+                    config.sentinels.clone(),
+                    config.unit_bounds.clone(),
+                    config.sensor_bounds.clone(),
+                    config.validate_ranges,
+                ),
+                smoothing: MovingAverage::new(config.smoothing.clone(), max),
+                monotonic_time: MonotonicTime::new(config.enforce_monotonic_time, max),
+                deadtime: Deadtime::new(
+                    config.deadtime_after,
+                    Duration::from_secs(DEFAULT_DEADTIME_SECS),
+                    max,
+                ),
+                rate_limit: RateLimit::new(config.min_interval_secs.map(Duration::from_secs), max),
+                last_payloads: LastPayloads::new(false, max),
+                paused: AtomicBool::new(false),
+                labels: Labels::new(false),
+                device_infos: DeviceInfos::new(max),
+                health: Health::new(),
+                stats: RunStats::new(),
+                config,
+            }
+        }
+
+        /// Processor storing to `sink`, with every sensor unregistered as in a dry run
+        pub fn processor<'a>(&'a self, sink: &'a dyn Sink) -> Processor<'a> {
+            Processor {
+                config: &self.config,
+                sink,
+                filters: &self.filters,
+                smoothing: &self.smoothing,
+                monotonic_time: &self.monotonic_time,
+                deadtime: &self.deadtime,
+                rate_limit: &self.rate_limit,
+                last_payloads: &self.last_payloads,
+                paused: &self.paused,
+                labels: &self.labels,
+                device_infos: &self.device_infos,
+                device_metadata: None,
+                health: &self.health,
+                stats: &self.stats,
+                auto_register: None,
+                republisher: None,
+                #[cfg(feature = "schema")]
+                payload_schema: None,
+                sensor_ids: SensorIds::unregistered(&self.config.sensor_configs()),
+                instance_sensor_ids: HashMap::new(),
+                instance_id: None,
+            }
+        }
+    }
+
+    /// Devices by topic, holding only the device `name` on `topic`
+    pub fn topic_to_device(
+        topic: &str,
+        id: DeviceId,
+        name: &str,
+    ) -> HashMap<String, DeviceContext> {
+        let device = DeviceContext {
+            id,
+            name: name.to_string(),
+            location: "Stue".to_string(),
+            expected_sensors: Vec::new(),
+            info_topics: Vec::new(),
+            offsets: HashMap::new(),
+            payload_root: String::new(),
+            hemrs: None,
+            payload_encoding: PayloadEncoding::None,
+        };
+        HashMap::from([(topic.to_string(), device)])
+    }
+
+    /// Writes `content` to a config file of its own named `name`
+    pub fn config_file(name: &str, content: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sensor_monitor_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Runs `future` on a current thread runtime, returning its output and the lines logged at
+    /// `level` or above while it ran
+    pub fn logged<F: Future>(level: Level, future: F) -> (F::Output, Vec<String>) {
+        #[derive(Clone)]
+        struct Lines(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Lines(Arc::new(Mutex::new(Vec::new())));
+        let writer = lines.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let output = tracing::subscriber::with_default(subscriber, || runtime.block_on(future));
+        let logged = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        (output, logged.lines().map(str::to_string).collect())
+    }
+
+    /// A configured topic with QoS 0 and defaults for everything but its device name
+    pub fn topic_config(topic: &str, device_name: &str) -> TopicConfig {
+        toml::from_str(&format!(
+            "topic = \"{topic}\"\ndevice_name = \"{device_name}\""
+        ))
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fixtures::{topic_to_device, ProcessorState},
+        *,
+    };
+    use crate::{config::Config, mqtt::handle_payload};
+
+    #[tokio::test]
+    async fn records_the_measurements_of_a_payload() {
+        let state = ProcessorState::new(Config::default());
+        let devices = topic_to_device("tele/stue/SENSOR", 1, "stue");
+        let sink = InMemorySink::new();
+
+        let payload = r#"{"Time":"2026-10-15T08:00:00","DHT11":{"Temperature":21.5,"Humidity":40.0,"DewPoint":7.5},"TempUnit":"C"}"#;
+        handle_payload(
+            "tele/stue/SENSOR",
+            payload,
+            &state.processor(&sink),
+            &devices,
+        )
+        .await
+        .unwrap();
+
+        let stored: Vec<(i32, String, f64)> = sink
+            .measurements()
+            .into_iter()
+            .map(|m| (m.device, m.sensor_name, m.measurement))
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                (1, "DHT11 Temperature".to_string(), 21.5),
+                (1, "DHT11 Humidity".to_string(), 40.0),
+                (1, "DHT11 Dew Point".to_string(), 7.5),
+            ]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashSet, fmt::Display};
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{Config, TopicConfig};
+
+/// What a valid config would set up, printed by the validate subcommand
+#[derive(Debug, PartialEq)]
+pub struct ConfigSummary {
+    pub brokers: usize,
+    pub serial_ports: usize,
+    pub topics: usize,
+    /// Distinct device name and location pairs, each set up in hemrs once
+    pub devices: usize,
+    pub sensors: usize,
+}
+
+impl Display for ConfigSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} brokers, {} serial ports, {} topics",
+            self.brokers, self.serial_ports, self.topics
+        )?;
+        write!(
+            f,
+            "{} devices and {} sensors would be set up in hemrs",
+            self.devices, self.sensors
+        )
+    }
+}
+
+fn topic_problems(source: &str, topic: &TopicConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if topic.topic.trim().is_empty() {
+        problems.push(format!("{} has a topic without a name", source));
+    }
+    if topic.device_name.trim().is_empty() {
+        problems.push(format!(
+            "{} topic {:?} has no device_name",
+            source, topic.topic
+        ));
+    }
+    if topic.device_location.trim().is_empty() {
+        problems.push(format!(
+            "{} topic {:?} has no device_location",
+            source, topic.topic
+        ));
+    }
+    problems
+}
+
+/// Checks a parsed config for problems parsing does not catch, reporting all of them at once
+pub fn check_config(config: &Config) -> Result<ConfigSummary> {
+    let mut problems = Vec::new();
+    if config.broker.is_empty() && config.serial.is_empty() {
+        problems.push("no brokers or serial ports are configured".to_string());
+    }
+    for broker in &config.broker {
+        let source = format!("broker {}:{}", broker.host, broker.port);
+        if broker.topics.is_empty() {
+            problems.push(format!("{} has no topics", source));
+        }
+        for topic in &broker.topics {
+            problems.extend(topic_problems(&source, topic));
+        }
+    }
+    for serial in &config.serial {
+        problems.extend(topic_problems(
+            &format!("serial port {}", serial.port),
+            &serial.device,
+        ));
+    }
+    let sensors = config.sensor_configs();
+    let mut keys = HashSet::new();
+    for sensor in &sensors {
+        if !keys.insert(&sensor.key) {
+            problems.push(format!(
+                "sensor {} is configured more than once",
+                sensor.key
+            ));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("invalid config:\n  {}", problems.join("\n  ")));
+    }
+
+    let topics: Vec<&TopicConfig> = config
+        .broker
+        .iter()
+        .flat_map(|broker| &broker.topics)
+        .chain(config.serial.iter().map(|serial| &serial.device))
+        .collect();
+    let devices: HashSet<(&str, &str)> = topics
+        .iter()
+        .map(|topic| (topic.device_name.as_str(), topic.device_location.as_str()))
+        .collect();
+    Ok(ConfigSummary {
+        brokers: config.broker.len(),
+        serial_ports: config.serial.len(),
+        topics: topics.len(),
+        devices: devices.len(),
+        sensors: sensors.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::parse_config, testing::fixtures::config_file};
+
+    #[test]
+    fn summarizes_a_valid_config() {
+        const VALID: &str = r#"
+[[broker]]
+host = "mqtt.local"
+
+[[broker.topics]]
+topic = "tele/stue/SENSOR"
+device_name = "esp32_stue"
+device_location = "Stue"
+
+[[broker.topics]]
+topic = "tele/stue2/SENSOR"
+device_name = "esp32_stue"
+device_location = "Stue"
+
+[[broker.topics]]
+topic = "tele/kjeller/SENSOR"
+device_name = "esp32_kjeller"
+device_location = "Kjeller"
+"#;
+        let config = parse_config(&config_file("valid.toml", VALID), None).unwrap();
+        let summary = check_config(&config).unwrap();
+        assert_eq!(
+            summary,
+            ConfigSummary {
+                brokers: 1,
+                serial_ports: 0,
+                topics: 3,
+                devices: 2,
+                sensors: config.sensor_configs().len(),
+            }
+        );
+        assert!(summary
+            .to_string()
+            .starts_with("1 brokers, 0 serial ports, 3 topics\n"));
+    }
+
+    #[test]
+    fn reports_every_problem_of_an_invalid_config() {
+        const INVALID: &str = r#"
+[[broker]]
+host = "mqtt.local"
+topics = []
+
+[[broker]]
+host = "mqtt2.local"
+
+[[broker.topics]]
+topic = " "
+device_name = ""
+device_location = "Stue"
+"#;
+        let config = parse_config(&config_file("invalid.toml", INVALID), None).unwrap();
+        let error = check_config(&config).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "invalid config:\n  \
+             broker mqtt.local:1883 has no topics\n  \
+             broker mqtt2.local:1883 has a topic without a name\n  \
+             broker mqtt2.local:1883 topic \" \" has no device_name"
+        );
+
+        let config = parse_config(&config_file("empty.toml", ""), None).unwrap();
+        let error = check_config(&config).unwrap_err().to_string();
+        assert!(error.contains("no brokers or serial ports"), "{error}");
+    }
+}